@@ -0,0 +1,204 @@
+use color_eyre::eyre::{self, WrapErr};
+use std::collections::BTreeSet;
+use std::hash::Hasher;
+use std::io;
+use std::path::Path;
+
+/// 64-bit FNV-1a hasher.
+///
+/// Unlike the hasher of the standard library, its output is stable across
+/// builds, which is required for keys persisted in the cache.
+#[derive(Debug, Clone, Copy)]
+pub struct Fnv(u64);
+
+impl Default for Fnv {
+    fn default() -> Self {
+        Self(0xcbf2_9ce4_8422_2325)
+    }
+}
+
+impl Hasher for Fnv {
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 ^= u64::from(*byte);
+            self.0 = self.0.wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+
+    fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+/// Digest of a sequence of values with the stable [`Fnv`] hasher.
+///
+/// Unlike deriving [`std::hash::Hash`], whose output may change across Rust
+/// versions, every value is written as its length followed by its bytes, so
+/// that digests persisted by earlier builds stay valid.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Digest(Fnv);
+
+impl Digest {
+    pub fn write(&mut self, value: impl AsRef<[u8]>) -> &mut Self {
+        let value = value.as_ref();
+        self.0.write(&(value.len() as u64).to_le_bytes());
+        self.0.write(value);
+        self
+    }
+
+    /// Writes the number of values followed by the values.
+    pub fn write_all<T: AsRef<[u8]>>(&mut self, values: &[T]) -> &mut Self {
+        self.write((values.len() as u64).to_le_bytes());
+        for value in values {
+            self.write(value);
+        }
+        self
+    }
+
+    #[must_use]
+    pub fn finish(&self) -> u64 {
+        self.0.finish()
+    }
+
+    /// The digest as 16 hexadecimal digits.
+    #[must_use]
+    pub fn hex(&self) -> String {
+        format!("{:016x}", self.finish())
+    }
+}
+
+/// Digest of the paths, sizes and modification times of all files in `paths`.
+///
+/// Directories are visited recursively, skipping build output in `target`
+/// directories and hidden files and directories (such as `.git`).
+pub fn source_digest<'a>(paths: impl IntoIterator<Item = &'a Path>) -> io::Result<u64> {
+    fn visit(path: &Path, files: &mut Vec<(String, u64, u128)>) -> io::Result<()> {
+        let metadata = std::fs::metadata(path)?;
+        if !metadata.is_dir() {
+            let modified = metadata
+                .modified()?
+                .duration_since(std::time::UNIX_EPOCH)
+                .map(|since| since.as_nanos())
+                .unwrap_or_default();
+            files.push((
+                path.to_string_lossy().into_owned(),
+                metadata.len(),
+                modified,
+            ));
+            return Ok(());
+        }
+        for entry in std::fs::read_dir(path)? {
+            let entry = entry?;
+            let name = entry.file_name();
+            let name = name.to_string_lossy();
+            if name.starts_with('.') || (name == "target" && entry.file_type()?.is_dir()) {
+                continue;
+            }
+            visit(&entry.path(), files)?;
+        }
+        Ok(())
+    }
+
+    let mut files = Vec::new();
+    for path in paths {
+        visit(path, &mut files)?;
+    }
+    // directory listings are not ordered
+    files.sort();
+    files.dedup();
+    let mut digest = Digest::default();
+    for (path, len, modified) in files {
+        digest
+            .write(path)
+            .write(len.to_le_bytes())
+            .write(modified.to_le_bytes());
+    }
+    Ok(digest.finish())
+}
+
+/// Identifies a cargo invocation for a feature combination.
+///
+/// Any change to the inputs results in a different key, invalidating the
+/// cached result.
+#[derive(Debug)]
+pub struct Key<'a> {
+    pub package_id: &'a str,
+    pub features: &'a [&'a String],
+    /// The full cargo arguments, including the subcommand.
+    pub args: &'a [String],
+    /// Options and environment changing the outcome, e.g. `RUSTFLAGS`.
+    pub env: &'a [String],
+    pub rustc_version: &'a str,
+    pub source_digest: u64,
+}
+
+impl Key<'_> {
+    #[must_use]
+    pub fn digest(&self) -> String {
+        Digest::default()
+            .write(self.package_id)
+            .write_all(self.features)
+            .write_all(self.args)
+            .write_all(self.env)
+            .write(self.rustc_version)
+            .write(self.source_digest.to_le_bytes())
+            .hex()
+    }
+}
+
+/// Keys of the cargo invocations that passed, persisted for `--cache`.
+#[derive(Debug, Default, serde::Serialize, serde::Deserialize)]
+pub struct Cache {
+    passed: BTreeSet<String>,
+}
+
+impl Cache {
+    /// Reads the cache from `path`, which is empty if it does not exist yet.
+    pub fn read(path: &Path) -> eyre::Result<Self> {
+        crate::read_state(path, "cache")
+    }
+
+    pub fn write(&self, path: &Path) -> eyre::Result<()> {
+        crate::write_state(self, path, "cache")
+    }
+
+    #[must_use]
+    pub fn passed(&self, key: &str) -> bool {
+        self.passed.contains(key)
+    }
+
+    /// Records whether the invocation identified by `key` passed.
+    pub fn record(&mut self, key: String, passed: bool) {
+        if passed {
+            self.passed.insert(key);
+        } else {
+            self.passed.remove(&key);
+        }
+    }
+}
+
+/// The verbose version of rustc, optionally of a rustup `toolchain`.
+pub fn rustc_version(toolchain: Option<&String>) -> eyre::Result<String> {
+    let mut cmd = match toolchain {
+        // only the rustup proxy of rustc understands the +toolchain argument
+        Some(toolchain) => {
+            let mut cmd = std::process::Command::new("rustc");
+            cmd.arg(format!("+{toolchain}"));
+            cmd
+        }
+        None => {
+            std::process::Command::new(std::env::var_os("RUSTC").unwrap_or_else(|| "rustc".into()))
+        }
+    };
+    let output = cmd
+        .arg("-vV")
+        .output()
+        .wrap_err("failed to get the version of rustc")?;
+    if !output.status.success() {
+        eyre::bail!(
+            "failed to get the version of rustc: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
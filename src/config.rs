@@ -1,14 +1,273 @@
+use color_eyre::eyre::{self, WrapErr};
 use serde::{Deserialize, Serialize};
 use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
 
 #[derive(Serialize, Deserialize, Default, Debug)]
 pub struct Config {
     #[serde(default)]
     pub skip_feature_sets: Vec<HashSet<String>>,
+    /// Feature sets added to the combinations as they are, regardless of the other rules.
     #[serde(default)]
-    pub denylist: HashSet<String>,
+    pub include_feature_sets: Vec<HashSet<String>>,
+    /// If not empty, exactly these feature sets are combined instead of all
+    /// combinations, leaving out features the package does not have.
+    #[serde(default)]
+    pub allow_feature_sets: Vec<HashSet<String>>,
+    /// Whether to drop `exclude_features` from the `include_feature_sets`.
+    #[serde(default)]
+    pub include_sets_respect_excludes: bool,
+    /// Whether to keep features the package does not have in the `include_feature_sets`,
+    /// e.g. features that only exist for some targets, for cargo to resolve.
+    #[serde(default)]
+    pub include_sets_allow_unknown: bool,
+    #[serde(default, alias = "denylist")]
+    pub exclude_features: HashSet<String>,
+    #[serde(default)]
+    pub include_features: HashSet<String>,
     #[serde(default)]
     pub exclude_packages: Vec<String>,
     #[serde(default)]
+    pub max_combination_size: Option<usize>,
+    /// Drop the empty feature combination, e.g. if a package needs some feature to build.
+    #[serde(default)]
+    pub no_empty_feature_set: bool,
+    /// Defaults to [`crate::DEFAULT_MAX_CONFIGURATIONS`].
+    #[serde(default)]
+    pub max_configurations: Option<usize>,
+    #[serde(default)]
+    pub mutually_exclusive: Vec<HashSet<String>>,
+    #[serde(default)]
+    pub pairwise: bool,
+    /// Drop combinations that enable the same features as a smaller combination,
+    /// e.g. `[a, b]` if `b` enables `a`.
+    #[serde(default)]
+    pub dedupe_implied: bool,
+    #[serde(default)]
+    pub include_default_features: bool,
+    /// Defaults to the workspace config if unset.
+    #[serde(default)]
+    pub skip_optional_dependencies: Option<bool>,
+    #[serde(default)]
+    pub matrix: HashMap<String, serde_json::Value>,
+    /// Arguments passed to clippy after `--`, e.g. `["-D", "warnings"]`.
+    #[serde(default)]
+    pub clippy_args: Vec<String>,
+    /// Paths of the files gated by a feature relative to the package, by feature,
+    /// for `--changed-features`.
+    #[serde(default)]
+    pub feature_paths: HashMap<String, Vec<PathBuf>>,
+    /// Environment variables to set for the combinations including a feature, by feature.
+    #[serde(default)]
+    pub feature_env: HashMap<String, HashMap<String, String>>,
+    #[serde(default)]
+    pub order: Order,
+    /// Template of the `display_name` of matrix rows, see [`crate::render_name_template`].
+    #[serde(default)]
+    pub name_template: Option<String>,
+    /// Name of empty feature combinations in the matrix, the list and the
+    /// `display_name` of matrix rows, e.g. for consumers that do not accept an
+    /// empty string. If unset, their features are empty and their `display_name`
+    /// is `none`.
+    #[serde(default)]
+    pub empty_features_name: Option<String>,
+    /// Workspace member whose features are combined instead of the features of this package.
+    #[serde(default)]
+    pub mirror_features_from: Option<String>,
+    /// Run a single feature combination instead of the combinations,
+    /// set by `--all-features` or `--no-features`.
+    #[serde(skip)]
+    pub quick_mode: Option<QuickMode>,
+    /// Separator of the features in the matrix, set by `--features-separator`.
+    #[serde(skip)]
+    pub features_separator: Option<String>,
+    /// Only keep combinations including one of these features, set by `--changed-features`.
+    #[serde(skip)]
+    pub changed_features: Option<HashSet<String>>,
+    /// Only combine features matching this regex, set by `--feature-filter`.
+    #[serde(skip)]
+    pub feature_filter: Option<regex::Regex>,
+    /// Never combine features matching this regex, set by `--feature-reject`.
+    #[serde(skip)]
+    pub feature_reject: Option<regex::Regex>,
+}
+
+impl Config {
+    /// The separator of the features in the matrix, `,` by default.
+    #[must_use]
+    pub fn features_separator(&self) -> &str {
+        self.features_separator.as_deref().unwrap_or(",")
+    }
+}
+
+/// Order of the feature combinations of a package in the matrix and list.
+///
+/// Runs do not follow it: they start with the smallest combinations, so that
+/// failing subsets are known before their supersets, and are summarized in
+/// lexicographic order.
+#[derive(Serialize, Deserialize, Default, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Order {
+    /// Lexicographic order of the sorted feature lists, e.g. `a`, `a,b`, `b`.
+    #[default]
+    Lexicographic,
+    /// Smaller combinations first, and lexicographic order within a size,
+    /// e.g. `a`, `b`, `a,b`, so that simple breakage surfaces first.
+    SizeThenLex,
+}
+
+impl Order {
+    /// Compares two sorted feature lists in this order.
+    #[must_use]
+    pub fn cmp<T: Ord>(self, a: &[T], b: &[T]) -> std::cmp::Ordering {
+        match self {
+            Self::Lexicographic => a.cmp(b),
+            Self::SizeThenLex => (a.len(), a).cmp(&(b.len(), b)),
+        }
+    }
+}
+
+impl std::str::FromStr for Order {
+    type Err = eyre::Report;
+
+    fn from_str(order: &str) -> Result<Self, Self::Err> {
+        match order.to_lowercase().as_str() {
+            "lexicographic" => Ok(Self::Lexicographic),
+            "size-then-lex" => Ok(Self::SizeThenLex),
+            other => Err(eyre::eyre!(
+                "unknown order {other:?} (expected one of: lexicographic, size-then-lex)"
+            )),
+        }
+    }
+}
+
+/// A single feature combination to run instead of the combinations of a package.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QuickMode {
+    /// All features that are not excluded, given with `--all-features`.
+    AllFeatures,
+    /// Only the included features, given with `--no-features`.
+    NoFeatures,
+}
+
+/// Config in `[workspace.metadata.cargo-feature-combinations]`,
+/// providing defaults for all workspace members.
+#[derive(Serialize, Deserialize, Default, Debug)]
+pub struct WorkspaceConfig {
+    #[serde(default)]
+    pub skip_optional_dependencies: bool,
+    /// Added to the `skip_feature_sets` of each package,
+    /// unless the package includes the set.
+    #[serde(default)]
+    pub skip_feature_sets: Vec<HashSet<String>>,
+    /// Added to the `include_feature_sets` of each package.
+    #[serde(default)]
+    pub include_feature_sets: Vec<HashSet<String>>,
+    /// Merged under the matrix of each package.
+    #[serde(default)]
     pub matrix: HashMap<String, serde_json::Value>,
+    /// Cargo subcommands to run besides the known ones, e.g. `udeps`.
+    #[serde(default)]
+    pub allowed_subcommands: Vec<String>,
+    /// Package config of a `--fc-config` file, overlaid on the config of every package.
+    #[serde(skip)]
+    pub package_overlay: Option<serde_json::Value>,
+    /// Fail on unknown keys in package configs, set by `--strict-config`.
+    #[serde(skip)]
+    pub strict: bool,
+}
+
+impl WorkspaceConfig {
+    /// Parses the workspace config from the workspace metadata, if present.
+    ///
+    /// # Errors
+    ///
+    /// If the configuration in the manifest or config file can not be parsed,
+    /// an Error is returned.
+    pub fn from_metadata(
+        metadata: &cargo_metadata::Metadata,
+        config_file: Option<&Path>,
+    ) -> eyre::Result<Self> {
+        Self::from_value(
+            metadata
+                .workspace_metadata
+                .get("cargo-feature-combinations"),
+            config_file,
+        )
+    }
+
+    /// Parses the workspace config, overlaid with the `workspace` section of `config_file`.
+    ///
+    /// The remaining sections of `config_file` are overlaid on the config of
+    /// every package (see [`overlay`]).
+    ///
+    /// # Errors
+    ///
+    /// If the configuration or config file can not be parsed,
+    /// an Error is returned.
+    pub fn from_value(
+        config: Option<&serde_json::Value>,
+        config_file: Option<&Path>,
+    ) -> eyre::Result<Self> {
+        let mut config = config.cloned().unwrap_or_else(|| serde_json::json!({}));
+        let mut package_overlay = None;
+        if let Some(path) = config_file {
+            let mut file_config = read_config_file(path)?;
+            if let Some(workspace) = file_config
+                .as_object_mut()
+                .and_then(|file_config| file_config.remove("workspace"))
+            {
+                overlay(&mut config, &workspace);
+            }
+            package_overlay = Some(file_config);
+        }
+        let mut config: Self = serde_json::from_value(config)?;
+        config.package_overlay = package_overlay;
+        Ok(config)
+    }
+}
+
+/// Keys of a package config that are not fields of [`Config`].
+///
+/// Unknown fields are ignored when deserializing, so this catches typos.
+#[must_use]
+pub fn unknown_keys(config: &serde_json::Value) -> Vec<&str> {
+    // every field is serialized, even if unset
+    let known = serde_json::to_value(Config::default()).unwrap_or_default();
+    let deprecated = ["denylist"];
+    config
+        .as_object()
+        .into_iter()
+        .flat_map(serde_json::Map::keys)
+        .filter(|key| known.get(key.as_str()).is_none() && !deprecated.contains(&key.as_str()))
+        .map(String::as_str)
+        .collect()
+}
+
+/// Reads a config file given by `--fc-config`, which is parsed as TOML if it
+/// has a `.toml` extension and as JSON otherwise.
+fn read_config_file(path: &Path) -> eyre::Result<serde_json::Value> {
+    let contents = std::fs::read_to_string(path)
+        .wrap_err_with(|| format!("failed to read config file {}", path.display()))?;
+    let config: serde_json::Value = if path.extension().is_some_and(|ext| ext == "toml") {
+        toml::from_str(&contents)
+            .wrap_err_with(|| format!("failed to parse config file {}", path.display()))?
+    } else {
+        serde_json::from_str(&contents)
+            .wrap_err_with(|| format!("failed to parse config file {}", path.display()))?
+    };
+    if !config.is_object() {
+        eyre::bail!("config file {} must contain an object", path.display());
+    }
+    Ok(config)
+}
+
+/// Overlays `overlay` on top of `config`.
+///
+/// Lists are appended to, objects are merged recursively,
+/// and all other values are replaced, except by `null`.
+pub fn overlay(config: &mut serde_json::Value, overlay: &serde_json::Value) {
+    use serde_json_merge::{iter::dfs::Dfs, merge::Merge};
+
+    config.merge_recursive::<Dfs>(overlay);
 }
@@ -3,5 +3,10 @@ use color_eyre::eyre;
 fn main() -> eyre::Result<()> {
     let bin_name = env!("CARGO_BIN_NAME");
     let bin_name = bin_name.strip_prefix("cargo-").unwrap_or(bin_name);
-    cargo_feature_combinations::run(bin_name)
+    cargo_feature_combinations::handle_interrupts()?;
+    let report = cargo_feature_combinations::run(bin_name)?;
+    if let Some(exit_code) = report.first_bad_exit_code {
+        std::process::exit(exit_code);
+    }
+    Ok(())
 }
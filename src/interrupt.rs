@@ -0,0 +1,31 @@
+use color_eyre::eyre::{self, WrapErr};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+/// Records Ctrl-C instead of terminating the process, so that the feature
+/// combinations completed so far can still be summarized.
+///
+/// A second Ctrl-C exits right away. Cargo processes in the same process
+/// group receive the signal as well.
+///
+/// The library does not install this handler itself, so that embedding
+/// applications keep control of signal handling; the binaries call it.
+///
+/// # Errors
+///
+/// If a Ctrl-C handler was already set.
+pub fn handle_interrupts() -> eyre::Result<()> {
+    ctrlc::set_handler(|| {
+        if INTERRUPTED.swap(true, Ordering::SeqCst) {
+            std::process::exit(crate::INTERRUPTED_EXIT_CODE);
+        }
+    })
+    .wrap_err("failed to handle Ctrl-C")
+}
+
+/// Whether Ctrl-C was received since [`handle_interrupts`].
+#[must_use]
+pub fn interrupted() -> bool {
+    INTERRUPTED.load(Ordering::SeqCst)
+}
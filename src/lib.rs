@@ -1,19 +1,28 @@
 #![allow(clippy::missing_errors_doc)]
 
+mod cache;
 mod config;
+mod interrupt;
+mod sarif;
+mod teamcity;
 mod tee;
+mod timeout;
 
-use crate::config::Config;
+pub use interrupt::handle_interrupts;
+
+use crate::config::{Config, Order, QuickMode, WorkspaceConfig};
 // use clap::{ArgMatches, FromArgMatches, Parser, Subcommand};
 use color_eyre::eyre::{self, WrapErr};
 use itertools::Itertools;
 use regex::Regex;
-use std::collections::HashSet;
-use std::io::{self, Write};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::io;
 use std::path::PathBuf;
 use std::process;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Mutex;
 use std::time::{Duration, Instant};
-use termcolor::{Color, ColorChoice, ColorSpec, StandardStream, WriteColor};
+use termcolor::{BufferWriter, Color, ColorChoice, ColorSpec, StandardStream, WriteColor};
 
 lazy_static::lazy_static! {
     static ref CYAN: ColorSpec = color_spec(Color::Cyan, true);
@@ -22,7 +31,7 @@ lazy_static::lazy_static! {
     static ref GREEN: ColorSpec = color_spec(Color::Green, true);
 }
 
-#[derive(Debug)]
+#[derive(Debug, serde::Serialize)]
 pub struct Summary {
     package_name: String,
     features: Vec<String>,
@@ -30,6 +39,262 @@ pub struct Summary {
     pedantic_success: bool,
     num_warnings: usize,
     num_errors: usize,
+    timed_out: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    toolchain: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    target: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    signal: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    failing_subset: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    cached: bool,
+    /// Whether the feature combination was cross-compiled with `--use-cross`.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    cross: bool,
+    /// Whether the package passed before and did not change since, with `--only-changed`.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    unchanged: bool,
+    /// Size in bytes of the built artifact, only recorded with `--report-sizes`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    artifact_size: Option<u64>,
+    #[serde(rename = "duration_secs", serialize_with = "serialize_secs")]
+    duration: Duration,
+    /// Compiler diagnostics, only collected with `--message-format json`.
+    #[serde(skip)]
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl Summary {
+    /// Summary of a job that cargo was not run for, e.g. because it was skipped.
+    fn not_run(job: &Job<'_>, cross: bool) -> Self {
+        Self {
+            package_name: job.package.name.clone(),
+            features: job.features.iter().map(ToString::to_string).collect(),
+            exit_code: None,
+            pedantic_success: false,
+            num_warnings: 0,
+            num_errors: 0,
+            timed_out: false,
+            toolchain: job.toolchain.cloned(),
+            target: job.target.cloned(),
+            signal: None,
+            failing_subset: None,
+            cached: false,
+            cross,
+            unchanged: false,
+            artifact_size: None,
+            duration: Duration::ZERO,
+            diagnostics: vec![],
+        }
+    }
+
+    #[must_use]
+    pub fn package_name(&self) -> &str {
+        &self.package_name
+    }
+
+    #[must_use]
+    pub fn features(&self) -> &[String] {
+        &self.features
+    }
+
+    #[must_use]
+    pub fn exit_code(&self) -> Option<i32> {
+        self.exit_code
+    }
+
+    #[must_use]
+    pub fn warnings(&self) -> usize {
+        self.num_warnings
+    }
+
+    #[must_use]
+    pub fn errors(&self) -> usize {
+        self.num_errors
+    }
+
+    /// The toolchain cargo was run with, as given with `--toolchain`.
+    #[must_use]
+    pub fn toolchain(&self) -> Option<&str> {
+        self.toolchain.as_deref()
+    }
+
+    /// The target triple passed to cargo with `--target`, if any.
+    #[must_use]
+    pub fn target(&self) -> Option<&str> {
+        self.target.as_deref()
+    }
+
+    /// Whether cargo was killed after exceeding `--timeout`.
+    #[must_use]
+    pub fn timed_out(&self) -> bool {
+        self.timed_out
+    }
+
+    /// The signal that terminated cargo, e.g. when it was killed for running out of memory.
+    #[must_use]
+    pub fn signal(&self) -> Option<i32> {
+        self.signal
+    }
+
+    /// The failing feature set this combination is a superset of, if it was
+    /// skipped due to `--skip-failing-supersets`.
+    #[must_use]
+    pub fn failing_subset(&self) -> Option<&[String]> {
+        self.failing_subset.as_deref()
+    }
+
+    /// Whether cargo was not run because the feature combination passed
+    /// before and nothing changed since, with `--cache`.
+    #[must_use]
+    pub fn cached(&self) -> bool {
+        self.cached
+    }
+
+    #[must_use]
+    pub fn unchanged(&self) -> bool {
+        self.unchanged
+    }
+
+    /// Whether the feature combination succeeded, taking `--pedantic` into account.
+    #[must_use]
+    pub fn succeeded(&self) -> bool {
+        self.pedantic_success
+    }
+
+    #[must_use]
+    pub fn duration(&self) -> Duration {
+        self.duration
+    }
+
+    /// Size in bytes of the primary artifact built by cargo, with `--report-sizes`.
+    #[must_use]
+    pub fn artifact_size(&self) -> Option<u64> {
+        self.artifact_size
+    }
+
+    #[must_use]
+    pub fn diagnostics(&self) -> &[Diagnostic] {
+        &self.diagnostics
+    }
+}
+
+fn serialize_secs<S: serde::Serializer>(
+    duration: &Duration,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.serialize_f64(duration.as_secs_f64())
+}
+
+/// Writes the summary of a run as a JSON array to `path`.
+pub fn write_summary_json(summary: &[Summary], path: &std::path::Path) -> eyre::Result<()> {
+    let json = serde_json::to_string_pretty(summary)?;
+    std::fs::write(path, json)
+        .wrap_err_with(|| format!("failed to write summary to {}", path.display()))
+}
+
+/// Renders the summary of a run as a JUnit XML report.
+///
+/// Each package becomes a `<testsuite>` and each feature combination a `<testcase>`.
+#[must_use]
+pub fn junit_xml(summary: &[Summary]) -> String {
+    use std::fmt::Write;
+
+    let mut xml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    let is_failure = |s: &Summary| !s.pedantic_success && s.failing_subset.is_none();
+    let total_failures = summary.iter().filter(|s| is_failure(s)).count();
+    let total_skipped = summary
+        .iter()
+        .filter(|s| s.failing_subset.is_some())
+        .count();
+    let total_time: Duration = summary.iter().map(|s| s.duration).sum();
+    let _ = writeln!(
+        xml,
+        r#"<testsuites name="cargo-fc" tests="{}" failures="{}" skipped="{}" time="{:.3}">"#,
+        summary.len(),
+        total_failures,
+        total_skipped,
+        total_time.as_secs_f64(),
+    );
+    for package_name in summary.iter().map(|s| &s.package_name).unique() {
+        let cases: Vec<_> = summary
+            .iter()
+            .filter(|s| &s.package_name == package_name)
+            .collect();
+        let failures = cases.iter().filter(|s| is_failure(s)).count();
+        let skipped = cases.iter().filter(|s| s.failing_subset.is_some()).count();
+        let time: Duration = cases.iter().map(|s| s.duration).sum();
+        let _ = writeln!(
+            xml,
+            r#"  <testsuite name="{}" tests="{}" failures="{}" skipped="{}" time="{:.3}">"#,
+            xml_escape(package_name),
+            cases.len(),
+            failures,
+            skipped,
+            time.as_secs_f64(),
+        );
+        for s in cases {
+            let variant = [&s.toolchain, &s.target].into_iter().flatten().join(", ");
+            let variant = if variant.is_empty() {
+                variant
+            } else {
+                format!(" ({variant})")
+            };
+            let _ = write!(
+                xml,
+                r#"    <testcase name="{} [{}]{}" classname="{}" time="{:.3}""#,
+                xml_escape(&s.package_name),
+                xml_escape(&s.features.join(", ")),
+                xml_escape(&variant),
+                xml_escape(&s.package_name),
+                s.duration.as_secs_f64(),
+            );
+            if s.pedantic_success {
+                xml.push_str(" />\n");
+                continue;
+            }
+            if let Some(ref failing_subset) = s.failing_subset {
+                let _ = writeln!(
+                    xml,
+                    ">\n      <skipped message=\"superset of failing features [{}]\" />\n    </testcase>",
+                    xml_escape(&failing_subset.join(", ")),
+                );
+                continue;
+            }
+            let message = if s.timed_out {
+                "timed out".to_string()
+            } else if let Some(signal) = s.signal {
+                format!("killed by signal {signal}")
+            } else {
+                format!("{} errors, {} warnings", s.num_errors, s.num_warnings)
+            };
+            let _ = writeln!(
+                xml,
+                ">\n      <failure message=\"{}\" />\n    </testcase>",
+                xml_escape(&message),
+            );
+        }
+        xml.push_str("  </testsuite>\n");
+    }
+    xml.push_str("</testsuites>\n");
+    xml
+}
+
+fn xml_escape(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+/// Writes the summary of a run as a JUnit XML report to `path`.
+pub fn write_junit_xml(summary: &[Summary], path: &std::path::Path) -> eyre::Result<()> {
+    std::fs::write(path, junit_xml(summary))
+        .wrap_err_with(|| format!("failed to write JUnit report to {}", path.display()))
 }
 
 // #[derive(Debug, Subcommand)]
@@ -38,10 +303,128 @@ pub enum Command {
     FeatureMatrix {
         // #[clap(long = "pretty", default_value = "true")]
         pretty: bool,
+        format: MatrixFormat,
+        with_command: bool,
+    },
+    List,
+    PrintConfig,
+    Count {
+        per_package: bool,
     },
     Help,
 }
 
+/// Output format of the feature matrix.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum MatrixFormat {
+    /// A plain JSON array of matrix entries.
+    #[default]
+    Json,
+    /// A GitHub Actions job matrix of the form `{"include": [...]}`.
+    ///
+    /// If `$GITHUB_OUTPUT` is set, the matrix is written to it as the `matrix` output.
+    Github,
+    /// Newline-delimited JSON with one matrix entry per line.
+    Ndjson,
+}
+
+impl std::str::FromStr for MatrixFormat {
+    type Err = eyre::Report;
+
+    fn from_str(format: &str) -> Result<Self, Self::Err> {
+        match format.to_lowercase().as_str() {
+            "json" => Ok(Self::Json),
+            "github" => Ok(Self::Github),
+            "ndjson" => Ok(Self::Ndjson),
+            other => Err(eyre::eyre!(
+                "unknown matrix output format {other:?} (expected one of: json, github, ndjson)"
+            )),
+        }
+    }
+}
+
+/// When to use colors, selected by `--color` or `CARGO_TERM_COLOR`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum ColorMode {
+    /// Use colors if stdout is a terminal.
+    #[default]
+    Auto,
+    Always,
+    Never,
+}
+
+impl ColorMode {
+    fn use_color(self) -> bool {
+        use std::io::IsTerminal;
+        match self {
+            Self::Auto => std::io::stdout().is_terminal(),
+            Self::Always => true,
+            Self::Never => false,
+        }
+    }
+
+    /// The color choice for output of `cargo fc` itself.
+    #[must_use]
+    pub fn color_choice(self) -> ColorChoice {
+        match self {
+            // let termcolor respect NO_COLOR and TERM=dumb
+            Self::Auto if self.use_color() => ColorChoice::Auto,
+            Self::Always => ColorChoice::Always,
+            _ => ColorChoice::Never,
+        }
+    }
+
+    /// The value of the `--color` argument passed to cargo.
+    ///
+    /// The output of cargo is captured, so it must be told whether to use colors.
+    #[must_use]
+    pub fn cargo_color(self) -> &'static str {
+        if self.use_color() {
+            "always"
+        } else {
+            "never"
+        }
+    }
+}
+
+impl std::str::FromStr for ColorMode {
+    type Err = eyre::Report;
+
+    fn from_str(color: &str) -> Result<Self, Self::Err> {
+        match color.to_lowercase().as_str() {
+            "auto" => Ok(Self::Auto),
+            "always" => Ok(Self::Always),
+            "never" => Ok(Self::Never),
+            other => Err(eyre::eyre!(
+                "unknown color mode {other:?} (expected one of: auto, always, never)"
+            )),
+        }
+    }
+}
+
+/// A slice of the feature combinations to run, selected by `--shard INDEX/TOTAL`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Shard {
+    pub index: usize,
+    pub total: usize,
+}
+
+impl std::str::FromStr for Shard {
+    type Err = eyre::Report;
+
+    fn from_str(shard: &str) -> Result<Self, Self::Err> {
+        let parse = || {
+            let (index, total) = shard.split_once('/')?;
+            let index = index.trim().parse::<usize>().ok()?;
+            let total = total.trim().parse::<usize>().ok()?;
+            (index < total).then_some(Self { index, total })
+        };
+        parse().ok_or_else(|| {
+            eyre::eyre!("invalid shard {shard:?} (expected INDEX/TOTAL with INDEX < TOTAL)")
+        })
+    }
+}
+
 // #[derive(Parser, Debug, Default)]
 // #[allow(clippy::struct_excessive_bools)]
 // #[clap(disable_help_flag = true)]
@@ -72,14 +455,127 @@ pub enum Command {
 #[allow(clippy::struct_excessive_bools)]
 pub struct Options {
     pub manifest_path: Option<PathBuf>,
+    /// Cargo program to run, given with `--cargo-bin`, see [`cargo_program`].
+    pub cargo_bin: Option<PathBuf>,
+    /// Cross-compile with `cross` for the targets, given with `--use-cross`.
+    pub use_cross: bool,
     pub packages: HashSet<String>,
     pub command: Option<Command>,
     pub silent: bool,
-    pub verbose: bool,
+    /// Level of `--quiet`, where level 1 is equivalent to `--silent`
+    /// and level 2 also hides the feature combination headers.
+    pub quiet: u8,
+    /// Level of `--verbose`, where level 1 prints the cargo command of each
+    /// feature combination and level 2 also the feature graph of each package.
+    pub verbose: u8,
     pub pedantic: bool,
+    /// Number of warnings above which a feature combination fails, given with
+    /// `--max-warnings`. `--pedantic` sets it to zero.
+    pub max_warnings: Option<usize>,
     pub errors_only: bool,
     pub packages_only: bool,
+    /// Only select packages with a library target.
+    pub only_packages_with_lib_target: bool,
+    /// Only select packages with a binary target.
+    pub only_packages_with_bin_target: bool,
     pub fail_fast: bool,
+    /// Run all feature combinations despite failures, which is the default.
+    pub keep_going: bool,
+    pub jobs: Option<usize>,
+    pub dry_run: bool,
+    pub skip_failing_supersets: bool,
+    pub sort_by_time: bool,
+    pub group_by_package: bool,
+    pub no_progress: bool,
+    /// Ask for confirmation of the number of cargo invocations before running,
+    /// given with `--confirm`.
+    pub confirm: bool,
+    pub exit_with_count: bool,
+    /// Skip feature combinations that passed before if nothing changed since.
+    pub cache: bool,
+    /// Skip packages that passed before if their manifest and sources did not
+    /// change since, given with `--only-changed`.
+    pub only_changed: bool,
+    /// Explain why feature combinations are kept or dropped.
+    pub explain: bool,
+    /// Order of the feature combinations, overriding the `order` config.
+    pub order: Option<Order>,
+    /// Template of the matrix `display_name`, overriding the `name_template` config.
+    pub name_template: Option<String>,
+    /// Number of feature combinations per row of the matrix, given with `--batch`.
+    pub batch: Option<usize>,
+    /// Only run these feature combinations, e.g. of a batched matrix row.
+    pub feature_sets: Option<Vec<Vec<String>>>,
+    /// Merge the matrix rows of packages with identical rows.
+    pub collapse_identical: bool,
+    /// Matrix to print the changes of the matrix against, given with `--diff-against`.
+    pub diff_against: Option<PathBuf>,
+    /// Directory to write a file with the features of each feature combination to,
+    /// given with `--emit-feature-files`.
+    pub emit_feature_files: Option<PathBuf>,
+    /// Separator of the features in the matrix and `--features`, `,` if unset.
+    pub features_separator: Option<String>,
+    /// File of features to include (`+feature`) and exclude (`-feature`).
+    pub features_file: Option<PathBuf>,
+    /// Features to include in the combinations of a single package, by package name.
+    pub package_features: HashMap<String, Vec<String>>,
+    /// Do not add any feature arguments to the cargo command.
+    pub no_feature_args: bool,
+    /// Arguments passed to clippy after `--`, given with `--clippy-args`.
+    pub clippy_args: Vec<String>,
+    /// Fail on unknown keys in the config instead of warning.
+    pub strict_config: bool,
+    pub exclude_packages: Vec<String>,
+    /// Only select packages with files changed since this git ref.
+    pub since: Option<String>,
+    /// Also select packages depending on packages changed since `--since`.
+    pub since_include_dependents: bool,
+    /// Only select feature combinations including a feature with files
+    /// changed since `--since`.
+    pub changed_features: bool,
+    /// Targets to run every feature combination for, given with `--target`.
+    pub targets: Vec<String>,
+    /// Toolchains to run every feature combination with, given with `--toolchain`.
+    pub toolchains: Vec<String>,
+    /// Cargo subcommands to run besides the known ones, given with `--allow-subcommand`
+    /// and in the `allowed_subcommands` of the workspace config.
+    pub allowed_subcommands: Vec<String>,
+    pub rerun_failed: bool,
+    /// Matrix printed by the matrix subcommand whose feature combinations
+    /// are run instead, given with `--features-json`.
+    pub features_json: Option<PathBuf>,
+    pub config_file: Option<PathBuf>,
+    pub feature_filter: Option<Regex>,
+    pub feature_reject: Option<Regex>,
+    pub color: ColorMode,
+    pub summary_json: Option<PathBuf>,
+    pub junit: Option<PathBuf>,
+    /// Path to write the compiler diagnostics to as SARIF.
+    pub sarif: Option<PathBuf>,
+    /// Wrap each feature combination in TeamCity service messages, given with `--teamcity`.
+    pub teamcity: bool,
+    /// Record the size of the artifact built for each feature combination.
+    pub report_sizes: bool,
+    pub shard: Option<Shard>,
+    /// Balance the shards of a run by the recorded durations of the feature
+    /// combinations, given with `--balance-shards`.
+    pub balance_shards: bool,
+    pub message_format_json: bool,
+    pub pairwise: bool,
+    /// Drop combinations enabling the same features as a smaller combination.
+    pub dedupe_implied: bool,
+    /// Run a single feature combination per package, given with `--all-features`
+    /// or `--no-features`.
+    pub quick_mode: Option<QuickMode>,
+    pub default_features: bool,
+    pub timeout: Option<Duration>,
+    pub exclude_features: HashSet<String>,
+    pub include_features: HashSet<String>,
+    /// Features passed to cargo with `--features`, which are added to the
+    /// features of every combination, e.g. `serde/std`.
+    pub cargo_features: Vec<String>,
+    /// Feature sets to skip, given with `--exclude-feature-set`.
+    pub exclude_feature_sets: Vec<HashSet<String>>,
 }
 
 #[derive(Debug)]
@@ -106,20 +602,41 @@ pub struct ArgOptions {
 }
 
 impl Args {
+    /// Whether `arg` is given before a bare `--`.
     #[must_use]
     pub fn contains(&self, arg: &str) -> bool {
         self.0
             .iter()
+            .take_while(|a| *a != "--")
             .any(|a| a == arg || a.starts_with(&format!("{arg}=")))
     }
 
+    /// Position of the cargo subcommand, the first argument before a bare `--`
+    /// that is neither an option nor the value of one of cargo's global options.
+    #[must_use]
+    pub fn subcommand_position(&self) -> Option<usize> {
+        let mut args = self.0.iter().enumerate().take_while(|(_, a)| *a != "--");
+        while let Some((idx, arg)) = args.next() {
+            if ["--color", "--config", "--explain", "-C", "-Z"].contains(&arg.as_str()) {
+                args.next();
+            } else if !arg.starts_with(['-', '+']) {
+                return Some(idx);
+            }
+        }
+        None
+    }
+
+    /// Finds all occurrences of `arg` and their values, last to first.
+    ///
+    /// Arguments after a bare `--` are passed through to the invoked binary
+    /// (e.g. the test harness) and never match.
     pub fn get_all(
         &mut self,
         arg: &str,
         has_value: bool,
     ) -> impl Iterator<Item = (std::ops::RangeInclusive<usize>, String)> {
         let mut matched = Vec::new();
-        for (idx, a) in self.0.iter().enumerate() {
+        for (idx, a) in self.0.iter().enumerate().take_while(|(_, a)| *a != "--") {
             match (a, self.0.get(idx + 1)) {
                 (key, Some(value)) if key == arg && has_value => {
                     matched.push((idx..=idx + 1, value.clone()));
@@ -145,573 +662,8688 @@ pub trait Package {
     /// If the Cargo.toml manifest contains a configuration section,
     /// the latter is parsed.
     /// Otherwise, a default configuration is used.
+    /// Options not set by the package fall back to the workspace config.
     ///
     /// # Errors
     ///
     /// If the configuration in the manifest can not be parsed,
     /// an Error is returned.
     ///
-    fn config(&self) -> eyre::Result<Config>;
-    fn feature_combinations(&self, config: &Config) -> Vec<Vec<&String>>;
+    fn config(&self, workspace: &WorkspaceConfig) -> eyre::Result<Config>;
+    /// Lazily yields the feature combinations in order of increasing size,
+    /// without materializing the powerset of all features.
+    fn feature_combinations_iter<'a>(
+        &'a self,
+        config: &'a Config,
+    ) -> impl Iterator<Item = Vec<&'a String>>;
+    /// The feature combinations in the configured [`Order`].
+    fn feature_combinations<'a>(&'a self, config: &'a Config) -> Vec<Vec<&'a String>>;
     fn feature_matrix(&self, config: &Config) -> Vec<String>;
 }
 
 impl Package for cargo_metadata::Package {
-    fn config(&self) -> eyre::Result<Config> {
-        match self.metadata.get("cargo-feature-combinations") {
-            Some(config) => {
-                let config: Config = serde_json::from_value(config.clone())?;
-                Ok(config)
+    fn config(&self, workspace: &WorkspaceConfig) -> eyre::Result<Config> {
+        let package_config = self.metadata.get("cargo-feature-combinations");
+        // only the features of the package's own config must exist, while
+        // those of a --fc-config file may only exist in some packages
+        let warn_unknown_features = |package_config: &Config| {
+            for message in unknown_feature_warnings(self, package_config) {
+                warn_once(message);
+            }
+        };
+        let mut config = match (package_config, &workspace.package_overlay) {
+            (Some(config), None) => {
+                check_config_keys(&self.name, config, workspace.strict)?;
+                let config = serde_json::from_value(config.clone())?;
+                warn_unknown_features(&config);
+                config
+            }
+            (config, Some(package_overlay)) => {
+                if let Some(config) = config {
+                    warn_unknown_features(&serde_json::from_value(config.clone())?);
+                }
+                let mut config = config.cloned().unwrap_or_else(|| serde_json::json!({}));
+                crate::config::overlay(&mut config, package_overlay);
+                check_config_keys(&self.name, &config, workspace.strict)?;
+                serde_json::from_value(config)?
+            }
+            (None, None) => Config::default(),
+        };
+        config
+            .skip_optional_dependencies
+            .get_or_insert(workspace.skip_optional_dependencies);
+        // sets included by the package take precedence over sets skipped by the workspace
+        for set in &workspace.skip_feature_sets {
+            if !config.include_feature_sets.contains(set) && !config.skip_feature_sets.contains(set)
+            {
+                config.skip_feature_sets.push(set.clone());
+            }
+        }
+        for set in &workspace.include_feature_sets {
+            if !config.include_feature_sets.contains(set) {
+                config.include_feature_sets.push(set.clone());
+            }
+        }
+        for (key, value) in &workspace.matrix {
+            match config.matrix.get_mut(key) {
+                Some(package_value) => merge_under(package_value, value),
+                None => {
+                    config.matrix.insert(key.clone(), value.clone());
+                }
             }
-            None => Ok(Config::default()),
         }
+        Ok(config)
     }
 
-    fn feature_combinations(&self, config: &Config) -> Vec<Vec<&String>> {
-        self.features
-            .keys()
-            .collect::<HashSet<_>>()
-            .into_iter()
-            .filter(|ft| !config.denylist.contains(*ft))
-            .powerset()
-            .filter_map(|mut set: Vec<&String>| {
-                set.sort();
-                let hset: HashSet<_> = set.iter().copied().cloned().collect();
-                let skip = config
-                    .skip_feature_sets
-                    .iter()
-                    .any(|skip_set| skip_set.is_subset(&hset));
-                if skip {
-                    None
-                } else {
-                    Some(set)
-                }
-            })
-            .sorted_by(Ord::cmp)
-            // .sorted_by(|a, b| match Ord::cmp(&a.len(), &b.len()) {
-            //     Ordering::Equal => Ord::cmp(a, b),
-            //     ordering => ordering,
-            // })
-            .collect()
+    fn feature_combinations_iter<'a>(
+        &'a self,
+        config: &'a Config,
+    ) -> impl Iterator<Item = Vec<&'a String>> {
+        combinations_iter(&self.features, config)
+    }
+
+    fn feature_combinations<'a>(&'a self, config: &'a Config) -> Vec<Vec<&'a String>> {
+        sorted_combinations(&self.features, config)
     }
 
     fn feature_matrix(&self, config: &Config) -> Vec<String> {
         self.feature_combinations(config)
             .into_iter()
-            .map(|features| features.iter().join(","))
+            .map(|features| features.iter().join(config.features_separator()))
             .collect()
     }
 }
 
-pub fn print_feature_matrix(
-    packages: &[&cargo_metadata::Package],
-    pretty: bool,
-    packages_only: bool,
-) -> eyre::Result<()> {
-    let per_package_features = packages
-        .iter()
-        .map(|pkg| {
-            let config = pkg.config()?;
-            let features = if packages_only {
-                vec!["default".to_string()]
-            } else {
-                pkg.feature_matrix(&config)
-            };
-            Ok::<_, eyre::Report>((pkg.name.clone(), config, features))
-        })
-        .collect::<Result<Vec<_>, _>>()?;
+/// Lazily yields the combinations of `features`, a map of the features of a
+/// package to the features they enable, in order of increasing size.
+///
+/// See [`Package::feature_combinations_iter`].
+pub fn combinations_iter<'a>(
+    features: &'a BTreeMap<String, Vec<String>>,
+    config: &'a Config,
+) -> impl Iterator<Item = Vec<&'a String>> + use<'a> {
+    let (include_features, base_features) = combined_features(features, config);
+    let no_empty_feature_set = config.no_empty_feature_set;
+    let changed_features = config.changed_features.as_ref();
+    let keep = move |set: &Vec<&String>| {
+        !(no_empty_feature_set && set.is_empty())
+            && changed_features.is_none_or(|changed| set.iter().any(|ft| changed.contains(*ft)))
+    };
 
-    let matrix: Vec<serde_json::Value> = per_package_features
-        .into_iter()
-        .flat_map(|(name, config, features)| {
-            features.into_iter().map(move |ft| {
-                use serde_json_merge::{iter::dfs::Dfs, merge::Merge};
+    if let Some(mode) = config.quick_mode {
+        let mut set = include_features;
+        if mode == QuickMode::AllFeatures {
+            set.extend(base_features);
+        }
+        set.sort();
+        return itertools::Either::Left(itertools::Either::Left(std::iter::once(set).filter(keep)));
+    }
 
-                let mut out = serde_json::json!(config.matrix);
-                out.merge::<Dfs>(&serde_json::json!({
-                    "name": name,
-                    "features": ft,
-                }));
-                out
+    if !config.allow_feature_sets.is_empty() {
+        let allowed_sets = config
+            .allow_feature_sets
+            .iter()
+            .map(|set| {
+                features
+                    .keys()
+                    .filter(|ft| set.contains(*ft))
+                    .collect::<Vec<_>>()
             })
-        })
-        .collect();
+            .unique()
+            .filter(keep)
+            .collect::<Vec<_>>();
+        return itertools::Either::Left(itertools::Either::Right(allowed_sets.into_iter()));
+    }
 
-    let matrix = if pretty {
-        serde_json::to_string_pretty(&matrix)
+    let base_sets = if config.pairwise {
+        let base_sets = pairwise_combinations(&base_features, |set| {
+            let within_cap = config
+                .max_combination_size
+                .is_none_or(|max_size| set.len() <= max_size);
+            let mut set = set.to_vec();
+            set.extend(include_features.iter().copied());
+            within_cap && !is_skipped(config, &set)
+        });
+        itertools::Either::Left(base_sets.into_iter())
     } else {
-        serde_json::to_string(&matrix)
-    }?;
-    println!("{matrix}");
-    Ok(())
+        let base_sets = base_features
+            .into_iter()
+            .powerset()
+            // the powerset is yielded in order of increasing size
+            .take_while(|set| {
+                config
+                    .max_combination_size
+                    .is_none_or(|max_size| set.len() <= max_size)
+            });
+        itertools::Either::Right(base_sets)
+    };
+
+    let include_sets = included_feature_sets(features, config);
+    let generated_sets = {
+        let include_sets = include_sets.clone();
+        base_sets.filter_map(move |mut set: Vec<&String>| {
+            set.extend(include_features.iter().copied());
+            set.sort();
+            // included sets are yielded last
+            if is_skipped(config, &set) || include_sets.contains(&set) {
+                None
+            } else {
+                Some(set)
+            }
+        })
+    };
+    // sets are yielded in order of increasing size, so the smallest set enabling
+    // the same features is kept
+    let mut enabled_sets = HashSet::new();
+    let dedupe_implied = config.dedupe_implied;
+    let distinct = move |set: &Vec<&'a String>| {
+        if !dedupe_implied {
+            return true;
+        }
+        let enabled: BTreeSet<&str> = enabled_features(features, set.iter().map(|ft| ft.as_str()))
+            .into_iter()
+            .collect();
+        enabled_sets.insert(enabled)
+    };
+    // included sets are added regardless of dedupe_implied
+    itertools::Either::Right(
+        generated_sets
+            .filter(keep)
+            .filter(distinct)
+            .chain(include_sets.into_iter().filter(keep)),
+    )
 }
 
+/// The combinations of `features`, a map of the features of a package to the
+/// features they enable, in the configured [`Order`].
+///
+/// This is what [`Package::feature_combinations`] computes for the features
+/// of a package, e.g. for library users without a `cargo_metadata::Package`.
 #[must_use]
-pub fn color_spec(color: Color, bold: bool) -> ColorSpec {
-    let mut spec = ColorSpec::new();
-    spec.set_fg(Some(color));
-    spec.set_bold(bold);
-    spec
+pub fn combinations(features: &BTreeMap<String, Vec<String>>, config: &Config) -> Vec<Vec<String>> {
+    sorted_combinations(features, config)
+        .into_iter()
+        .map(|set| set.into_iter().cloned().collect())
+        .collect()
 }
 
-pub fn warning_counts(output: &str) -> impl Iterator<Item = usize> + '_ {
-    lazy_static::lazy_static! {
-        static ref WARNING_REGEX: Regex =
-            Regex::new(r"warning: .* generated (\d+) warnings?").unwrap();
-    }
-    WARNING_REGEX
-        .captures_iter(output)
-        .filter_map(|cap| cap.get(1))
-        .map(|m| m.as_str().parse::<usize>().unwrap_or(0))
+fn sorted_combinations<'a>(
+    features: &'a BTreeMap<String, Vec<String>>,
+    config: &'a Config,
+) -> Vec<Vec<&'a String>> {
+    combinations_iter(features, config)
+        .sorted_by(|a, b| config.order.cmp(a, b))
+        .collect()
 }
 
-pub fn error_counts(output: &str) -> impl Iterator<Item = usize> + '_ {
-    lazy_static::lazy_static! {
-        static ref ERROR_REGEX: Regex =
-            Regex::new(r"error: could not compile `.*` due to\s*(\d*)\s*previous errors?").unwrap();
+/// Warns about unknown keys in the config of a package, which are
+/// usually typos such as `exclude_feature`, or fails if `strict` is set.
+fn check_config_keys(package: &str, config: &serde_json::Value, strict: bool) -> eyre::Result<()> {
+    let unknown = crate::config::unknown_keys(config);
+    if unknown.is_empty() {
+        return Ok(());
     }
-    ERROR_REGEX
-        .captures_iter(output)
-        .filter_map(|cap| cap.get(1))
-        .map(|m| m.as_str().parse::<usize>().unwrap_or(1))
-}
-
-pub fn print_summary(
-    summary: Vec<Summary>,
-    mut stdout: termcolor::StandardStream,
-    elapsed: Duration,
-) {
-    let num_packages = summary
-        .iter()
-        .map(|s| &s.package_name)
-        .collect::<HashSet<_>>()
-        .len();
-    let num_feature_sets = summary
-        .iter()
-        .map(|s| (&s.package_name, s.features.iter().collect::<Vec<_>>()))
-        .collect::<HashSet<_>>()
-        .len();
-
-    println!();
-    stdout.set_color(&CYAN).ok();
-    print!("    Finished ");
-    stdout.reset().ok();
-    println!(
-        "{num_feature_sets} total feature combination{} for {num_packages} package{} in {elapsed:?}",
-        if num_feature_sets > 1 { "s" } else { "" },
-        if num_packages > 1 { "s" } else { "" },
-
+    let message = format!(
+        "unknown key{} {} in the config of package {package}",
+        if unknown.len() > 1 { "s" } else { "" },
+        unknown.iter().map(|key| format!("`{key}`")).join(", "),
     );
-    println!();
-
-    let mut first_bad_exit_code: Option<i32> = None;
-    let most_errors = summary.iter().map(|s| s.num_errors).max().unwrap_or(0);
-    let most_warnings = summary.iter().map(|s| s.num_warnings).max().unwrap_or(0);
-    let errors_width = most_errors.to_string().len();
-    let warnings_width = most_warnings.to_string().len();
-
-    for s in summary {
-        if !s.pedantic_success {
-            stdout.set_color(&RED).ok();
-            print!("        FAIL ");
-            if first_bad_exit_code.is_none() {
-                first_bad_exit_code = s.exit_code;
-            }
-        } else if s.num_warnings > 0 {
-            stdout.set_color(&YELLOW).ok();
-            print!("        WARN ");
-        } else {
-            stdout.set_color(&GREEN).ok();
-            print!("        PASS ");
-        }
-        stdout.reset().ok();
-        println!(
-            "{} ( {:ew$} errors, {:ww$} warnings, features = [{}] )",
-            s.package_name,
-            s.num_errors.to_string(),
-            s.num_warnings.to_string(),
-            s.features.iter().join(", "),
-            ew = errors_width,
-            ww = warnings_width,
-        );
+    if strict {
+        eyre::bail!(message);
     }
-    println!();
+    warn_once(message);
+    Ok(())
+}
 
-    if let Some(exit_code) = first_bad_exit_code {
-        std::process::exit(exit_code);
+/// Prints a warning, unless the same warning was printed before.
+fn warn_once(message: String) {
+    lazy_static::lazy_static! {
+        // the config of a package may be parsed more than once
+        static ref WARNED: Mutex<HashSet<String>> = Mutex::new(HashSet::new());
+    }
+    if WARNED
+        .lock()
+        .map_or(true, |mut warned| warned.insert(message.clone()))
+    {
+        eprintln!("warning: {message}");
     }
 }
 
-fn print_package_cmd(
-    package: &cargo_metadata::Package,
-    features: &[&String],
-    cargo_args: &Args,
-    all_args: &[String],
-    options: &Options,
-    stdout: &mut StandardStream,
-) {
-    if !options.silent {
-        println!();
-    }
-    stdout.set_color(&CYAN).ok();
-    if cargo_args.contains("build") {
-        print!("    Building ");
-    } else if cargo_args.contains("check") || cargo_args.contains("clippy") {
-        print!("    Checking ");
-    } else if cargo_args.contains("test") {
-        print!("     Testing ");
+/// Warnings about features in the config of a package that the package does not have,
+/// which are otherwise silently ignored, suggesting the closest existing feature.
+///
+/// Unknown features of the `include_feature_sets` are kept with
+/// `include_sets_allow_unknown`, so they are not reported then.
+fn unknown_feature_warnings(package: &cargo_metadata::Package, config: &Config) -> Vec<String> {
+    let include_feature_sets: &[HashSet<String>] = if config.include_sets_allow_unknown {
+        &[]
     } else {
-        print!("     Running ");
-    }
-    stdout.reset().ok();
-    print!(
-        "{} ( features = [{}] )",
-        package.name,
-        features.as_ref().iter().join(", ")
-    );
-    if options.verbose {
-        print!(" [cargo {}]", all_args.join(" "));
+        &config.include_feature_sets
+    };
+    let sets = [
+        ("skip_feature_sets", config.skip_feature_sets.as_slice()),
+        ("include_feature_sets", include_feature_sets),
+        ("allow_feature_sets", &config.allow_feature_sets),
+        ("mutually_exclusive", &config.mutually_exclusive),
+    ];
+    let features: [(&str, Vec<&String>); 4] = [
+        ("exclude_features", config.exclude_features.iter().collect()),
+        ("include_features", config.include_features.iter().collect()),
+        ("feature_paths", config.feature_paths.keys().collect()),
+        ("feature_env", config.feature_env.keys().collect()),
+    ];
+    let mut configured: Vec<(&str, &String)> = Vec::new();
+    for (key, sets) in sets {
+        for set in sets {
+            configured.extend(set.iter().sorted().map(|feature| (key, feature)));
+        }
     }
-    println!();
-    if !options.silent {
-        println!();
+    for (key, features) in features {
+        configured.extend(features.into_iter().sorted().map(|feature| (key, feature)));
     }
+    configured
+        .into_iter()
+        .filter(|(_, feature)| !package.features.contains_key(*feature))
+        .unique()
+        .map(|(key, feature)| {
+            let closest = package
+                .features
+                .keys()
+                .map(|existing| (edit_distance(feature, existing), existing))
+                .min()
+                .filter(|(distance, _)| *distance <= feature.chars().count() / 3 + 1);
+            let suggestion = closest
+                .map(|(_, existing)| format!(", did you mean `{existing}`?"))
+                .unwrap_or_default();
+            format!(
+                "feature `{feature}` in `{key}` of package {} does not exist{suggestion}",
+                package.name
+            )
+        })
+        .collect()
 }
 
-pub fn run_cargo_command(
+/// Warnings about features of the `skip_feature_sets` and `include_feature_sets`
+/// of the `workspace` config that exist in none of the `packages`.
+fn unknown_workspace_feature_warnings(
     packages: &[&cargo_metadata::Package],
-    mut cargo_args: Args,
-    options: &Options,
-) -> eyre::Result<()> {
-    let start = Instant::now();
-    // let packages = md.workspace_packages();
+    workspace: &WorkspaceConfig,
+) -> Vec<String> {
+    let sets = [
+        ("skip_feature_sets", &workspace.skip_feature_sets),
+        ("include_feature_sets", &workspace.include_feature_sets),
+    ];
+    sets.into_iter()
+        .flat_map(|(key, sets)| {
+            sets.iter()
+                .flat_map(|set| set.iter().sorted())
+                .map(move |feature| (key, feature))
+        })
+        .filter(|(_, feature)| {
+            !packages
+                .iter()
+                .any(|package| package.features.contains_key(*feature))
+        })
+        .unique()
+        .map(|(key, feature)| {
+            format!("feature `{feature}` in `{key}` of the workspace does not exist in any package")
+        })
+        .collect()
+}
 
-    // split into cargo and extra arguments after --
-    let extra_args_idx = cargo_args
+/// Levenshtein distance of two strings, in characters.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut distances: Vec<usize> = (0..=b.len()).collect();
+    for (i, a) in a.chars().enumerate() {
+        let mut diagonal = distances[0];
+        distances[0] = i + 1;
+        for (j, b) in b.iter().enumerate() {
+            let substitution = diagonal + usize::from(a != *b);
+            diagonal = distances[j + 1];
+            distances[j + 1] = substitution.min(diagonal + 1).min(distances[j] + 1);
+        }
+    }
+    distances[b.len()]
+}
+
+/// The `include_feature_sets` of the config, as sorted sets of existing features.
+///
+/// Unless `include_sets_respect_excludes` is set, included sets may contain
+/// features of `exclude_features`. With `include_sets_allow_unknown`, they
+/// also keep features the package does not have.
+fn included_feature_sets<'a>(
+    features: &'a BTreeMap<String, Vec<String>>,
+    config: &'a Config,
+) -> Vec<Vec<&'a String>> {
+    config
+        .include_feature_sets
         .iter()
-        .position(|arg| arg.as_str() == "--")
-        .unwrap_or(cargo_args.len());
-    let extra_args = cargo_args.split_off(extra_args_idx);
+        .map(|set| {
+            let unknown = set
+                .iter()
+                .filter(|ft| config.include_sets_allow_unknown && !features.contains_key(*ft));
+            features
+                .keys()
+                .filter(|ft| set.contains(*ft))
+                .chain(unknown)
+                .filter(|ft| {
+                    !(config.include_sets_respect_excludes && config.exclude_features.contains(*ft))
+                })
+                .sorted()
+                .collect::<Vec<_>>()
+        })
+        .unique()
+        .collect()
+}
+
+/// The features included in every combination and the features to combine.
+fn combined_features<'a>(
+    features: &'a BTreeMap<String, Vec<String>>,
+    config: &Config,
+) -> (Vec<&'a String>, Vec<&'a String>) {
+    // features that are always enabled on top of the default features
+    // need not be combined, and neither do skipped optional dependencies
+    let mut default_features = if config.include_default_features {
+        default_features(features)
+    } else {
+        HashSet::new()
+    };
+    if config.skip_optional_dependencies == Some(true) {
+        default_features.extend(optional_dependency_features(features));
+    }
+
+    let include_features: Vec<&String> = features
+        .keys()
+        .filter(|ft| config.include_features.contains(*ft))
+        .filter(|ft| !config.exclude_features.contains(*ft))
+        .filter(|ft| !default_features.contains(ft.as_str()))
+        .collect();
+
+    let base_features: Vec<&String> = features
+        .keys()
+        .filter(|ft| is_feature_allowed(config, ft))
+        .filter(|ft| !config.exclude_features.contains(*ft))
+        .filter(|ft| !config.include_features.contains(*ft))
+        .filter(|ft| !default_features.contains(ft.as_str()))
+        .collect();
+
+    (include_features, base_features)
+}
+
+/// Default limit of feature combinations per package, see `max_configurations`.
+pub const DEFAULT_MAX_CONFIGURATIONS: usize = 100_000;
+
+/// The number of combinations of `num_features` features with at most `max_size` features.
+///
+/// Saturates instead of overflowing.
+fn num_combinations(num_features: usize, max_size: Option<usize>) -> u128 {
+    let max_size = max_size.unwrap_or(num_features).min(num_features);
+    let mut binomial: u128 = 1;
+    let mut total: u128 = 1;
+    for k in 0..max_size {
+        binomial = binomial
+            .checked_mul((num_features - k) as u128)
+            .map_or(u128::MAX, |product| product / (k as u128 + 1));
+        total = total.saturating_add(binomial);
+    }
+    total
+}
+
+/// Checks that the feature combinations of a package stay within `max_configurations`.
+///
+/// The number of combinations is computed upfront, before any combination is generated.
+///
+/// # Errors
+///
+/// If the package would have too many feature combinations,
+/// an error suggesting how to reduce them is returned.
+pub fn check_num_configurations(
+    package: &cargo_metadata::Package,
+    config: &Config,
+) -> eyre::Result<()> {
+    if config.pairwise || config.quick_mode.is_some() || !config.allow_feature_sets.is_empty() {
+        // pairwise combinations grow only logarithmically with the number of features,
+        // quick mode runs a single feature set, and allowed feature sets are listed explicitly
+        return Ok(());
+    }
+    let (_, base_features) = combined_features(&package.features, config);
+    let max_configurations = config
+        .max_configurations
+        .unwrap_or(DEFAULT_MAX_CONFIGURATIONS);
+    let num_combinations = num_combinations(base_features.len(), config.max_combination_size);
+    if num_combinations > max_configurations as u128 {
+        eyre::bail!(
+            "package {} has too many feature combinations: {} features result in {} combinations, \
+            which exceeds max_configurations = {max_configurations}. \
+            Consider excluding features with `exclude_features`, limiting the combination size \
+            with `max_combination_size`, using `pairwise = true`, \
+            or raising `max_configurations`",
+            package.name,
+            base_features.len(),
+            if num_combinations == u128::MAX {
+                "more than u128::MAX".to_string()
+            } else {
+                num_combinations.to_string()
+            },
+        );
+    }
+    Ok(())
+}
+
+/// Deep merges `defaults` under `value`, keeping the values of `value` on conflicts.
+///
+/// This overlays `value` on `defaults` like [`crate::config::overlay`], except that
+/// arrays are replaced instead of appended to, so that e.g. a package can override
+/// the operating systems of a workspace.
+fn merge_under(value: &mut serde_json::Value, defaults: &serde_json::Value) {
+    use serde_json::Value;
+    use serde_json_merge::{iter::dfs::Dfs, merge::Merge};
+
+    let mut merged = defaults.clone();
+    merged.merge_by_recursive::<Dfs, _>(value, &mut |_, merged, value| match (merged, value) {
+        (Value::Object(merged), Some(Value::Object(value))) => {
+            for key in value.keys() {
+                merged.entry(key.clone()).or_insert(Value::Null);
+            }
+            true
+        }
+        (merged, Some(value)) => {
+            *merged = value.clone();
+            false
+        }
+        (_, None) => false,
+    });
+    *value = merged;
+}
+
+/// The `default` feature and all features it transitively enables.
+fn default_features(features: &BTreeMap<String, Vec<String>>) -> HashSet<&str> {
+    enabled_features(features, ["default"])
+}
+
+/// The given features and all features they transitively enable.
+fn enabled_features<'a>(
+    features: &'a BTreeMap<String, Vec<String>>,
+    enabled_features: impl IntoIterator<Item = &'a str>,
+) -> HashSet<&'a str> {
+    let mut enabled = HashSet::new();
+    let mut queue: Vec<&str> = enabled_features.into_iter().collect();
+    while let Some(feature) = queue.pop() {
+        let Some(implied) = features.get(feature) else {
+            // dependency features such as `dep:foo` or `foo/bar`
+            continue;
+        };
+        if enabled.insert(feature) {
+            queue.extend(implied.iter().map(String::as_str));
+        }
+    }
+    enabled
+}
+
+/// The implicit features of optional dependencies.
+///
+/// Optional dependencies that are only enabled via `dep:` have no implicit feature.
+/// Only optional dependencies can be enabled via `dep:`, so the implicit
+/// features are the features enabling just the dependency of the same name.
+fn optional_dependency_features(features: &BTreeMap<String, Vec<String>>) -> HashSet<&str> {
+    features
+        .iter()
+        .filter(|(name, implied)| **implied == [format!("dep:{name}")])
+        .map(|(name, _)| name.as_str())
+        .collect()
+}
+
+/// Whether a feature passes `--feature-filter` and `--feature-reject`.
+fn is_feature_allowed(config: &Config, feature: &str) -> bool {
+    let filtered = config
+        .feature_filter
+        .as_ref()
+        .is_none_or(|filter| filter.is_match(feature));
+    let rejected = config
+        .feature_reject
+        .as_ref()
+        .is_some_and(|reject| reject.is_match(feature));
+    filtered && !rejected
+}
+
+/// Whether a feature set is ruled out by `skip_feature_sets`, `mutually_exclusive`,
+/// or the feature filters.
+fn is_skipped(config: &Config, set: &[&String]) -> bool {
+    skip_reason(config, set).is_some()
+}
+
+/// The rule ruling out a feature set, see [`is_skipped`].
+fn skip_reason(config: &Config, set: &[&String]) -> Option<Decision> {
+    if let Some(ft) = set.iter().find(|ft| !is_feature_allowed(config, ft)) {
+        return Some(Decision::FilteredFeature((*ft).clone()));
+    }
+    let hset: HashSet<_> = set.iter().copied().cloned().collect();
+    let sorted = |set: &HashSet<String>| set.iter().cloned().sorted().collect();
+    if let Some(skip_set) = config
+        .skip_feature_sets
+        .iter()
+        .find(|skip_set| skip_set.is_subset(&hset))
+    {
+        return Some(Decision::SkipFeatureSet(sorted(skip_set)));
+    }
+    config
+        .mutually_exclusive
+        .iter()
+        .find(|group| group.intersection(&hset).count() > 1)
+        .map(|group| Decision::MutuallyExclusive(sorted(group)))
+}
+
+/// Why a candidate feature combination was kept or dropped, see `--explain`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Decision {
+    Kept,
+    /// Added by `include_feature_sets`, even if other rules drop it.
+    Included,
+    /// Contains a feature of `exclude_features`.
+    ExcludedFeature(String),
+    /// Contains a feature ruled out by `--feature-filter` or `--feature-reject`.
+    FilteredFeature(String),
+    /// Combines more features than `max_combination_size`.
+    MaxCombinationSize(usize),
+    /// Is a superset of a set of `skip_feature_sets`.
+    SkipFeatureSet(Vec<String>),
+    /// Contains more than one feature of a `mutually_exclusive` group.
+    MutuallyExclusive(Vec<String>),
+    /// Not needed to cover every pair of features with `pairwise`.
+    Pairwise,
+    /// The empty feature combination, with `no_empty_feature_set`.
+    EmptyFeatureSet,
+    /// Enables the same features as a smaller combination, with `dedupe_implied`.
+    ImpliedDuplicate,
+    /// Includes none of the features with changed files, with `--changed-features`.
+    Unchanged,
+    /// Not one of the `allow_feature_sets`.
+    NotAllowed,
+}
+
+impl std::fmt::Display for Decision {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Kept => write!(f, "kept"),
+            Self::Included => write!(f, "kept, added by include_feature_sets"),
+            Self::ExcludedFeature(ft) => write!(f, "dropped by exclude_features ({ft})"),
+            Self::FilteredFeature(ft) => {
+                write!(f, "dropped by --feature-filter or --feature-reject ({ft})")
+            }
+            Self::MaxCombinationSize(max_size) => {
+                write!(f, "dropped by max_combination_size = {max_size}")
+            }
+            Self::SkipFeatureSet(set) => {
+                write!(f, "dropped by skip_feature_sets [{}]", set.join(", "))
+            }
+            Self::MutuallyExclusive(group) => {
+                write!(f, "dropped by mutually_exclusive [{}]", group.join(", "))
+            }
+            Self::Pairwise => write!(f, "dropped by pairwise"),
+            Self::EmptyFeatureSet => write!(f, "dropped by no_empty_feature_set"),
+            Self::ImpliedDuplicate => write!(f, "dropped by dedupe_implied"),
+            Self::Unchanged => write!(f, "dropped by --changed-features"),
+            Self::NotAllowed => write!(f, "dropped by allow_feature_sets"),
+        }
+    }
+}
+
+/// A candidate feature combination and the decision on it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Explanation<'a> {
+    pub features: Vec<&'a String>,
+    pub decision: Decision,
+}
+
+/// Explains for every candidate feature combination of a package whether it
+/// is kept or dropped, and by which rule.
+///
+/// Candidates are all combinations of the features that are not always
+/// included, including the excluded ones.
+///
+/// # Errors
+///
+/// If there are more candidates than `max_configurations`, an error is returned.
+pub fn explain_feature_combinations<'a>(
+    package: &'a cargo_metadata::Package,
+    config: &'a Config,
+) -> eyre::Result<Vec<Explanation<'a>>> {
+    let (include_features, base_features) = combined_features(&package.features, config);
+    let candidate_features: Vec<&String> = package
+        .features
+        .keys()
+        .filter(|ft| {
+            base_features.contains(ft)
+                || (!include_features.contains(ft)
+                    && (config.exclude_features.contains(*ft) || !is_feature_allowed(config, ft)))
+        })
+        .collect();
+    let max_configurations = config
+        .max_configurations
+        .unwrap_or(DEFAULT_MAX_CONFIGURATIONS);
+    if num_combinations(candidate_features.len(), None) > max_configurations as u128 {
+        eyre::bail!(
+            "package {} has too many candidate feature combinations to explain",
+            package.name
+        );
+    }
+
+    let kept: HashSet<Vec<&String>> = package.feature_combinations_iter(config).collect();
+    let allowlist = !config.allow_feature_sets.is_empty();
+    let included = if allowlist {
+        // only the allowed sets are kept, regardless of the other rules
+        kept.iter().cloned().sorted().collect()
+    } else {
+        included_feature_sets(&package.features, config)
+    };
+    let mut explanations: Vec<Explanation<'a>> = candidate_features
+        .into_iter()
+        .powerset()
+        .map(|combined| {
+            let mut features = combined.clone();
+            features.extend(include_features.iter().copied());
+            features.sort();
+            let dropped_by = combined
+                .iter()
+                .find(|ft| config.exclude_features.contains(**ft))
+                .map(|ft| Decision::ExcludedFeature((*ft).clone()))
+                .or_else(|| {
+                    config
+                        .max_combination_size
+                        .filter(|max_size| combined.len() > *max_size)
+                        .map(Decision::MaxCombinationSize)
+                })
+                .or_else(|| skip_reason(config, &features))
+                .or_else(|| {
+                    config
+                        .changed_features
+                        .as_ref()
+                        .filter(|changed| !features.iter().any(|ft| changed.contains(*ft)))
+                        .map(|_| Decision::Unchanged)
+                })
+                .or_else(|| {
+                    (!kept.contains(&features)).then_some(if config.pairwise {
+                        Decision::Pairwise
+                    } else {
+                        Decision::ImpliedDuplicate
+                    })
+                });
+            let decision = match dropped_by {
+                _ if config.no_empty_feature_set && features.is_empty() => {
+                    Decision::EmptyFeatureSet
+                }
+                _ if allowlist && kept.contains(&features) => Decision::Kept,
+                _ if allowlist => Decision::NotAllowed,
+                Some(_) if included.contains(&features) => Decision::Included,
+                Some(decision) => decision,
+                None => Decision::Kept,
+            };
+            Explanation { features, decision }
+        })
+        .collect();
+    // included sets need not be candidates, e.g. if they lack an include feature
+    for features in included {
+        if !explanations.iter().any(|e| e.features == features) {
+            explanations.push(Explanation {
+                features,
+                decision: if allowlist {
+                    Decision::Kept
+                } else {
+                    Decision::Included
+                },
+            });
+        }
+    }
+    Ok(explanations)
+}
+
+/// Prints the explanation of the feature combinations of each package to stderr.
+pub fn print_explanation(
+    packages: &[(&cargo_metadata::Package, Config)],
+    color: ColorMode,
+) -> eyre::Result<()> {
+    use std::io::Write;
+
+    let mut stderr = StandardStream::stderr(color.color_choice());
+    for (package, config) in packages {
+        stderr.set_color(&CYAN).ok();
+        write!(stderr, "{}", package.name)?;
+        stderr.reset().ok();
+        writeln!(stderr)?;
+        for Explanation { features, decision } in explain_feature_combinations(package, config)? {
+            let color: &ColorSpec = match decision {
+                Decision::Kept | Decision::Included => &GREEN,
+                _ => &YELLOW,
+            };
+            write!(stderr, "  [{}] ", features.iter().join(", "))?;
+            stderr.set_color(color).ok();
+            write!(stderr, "{decision}")?;
+            stderr.reset().ok();
+            writeln!(stderr)?;
+        }
+    }
+    Ok(())
+}
+
+/// Prints the feature graph of each package, i.e. the features enabled by
+/// each feature, and its number of feature combinations to stderr.
+pub fn print_feature_graph(
+    packages: &[(&cargo_metadata::Package, Config)],
+    color: ColorMode,
+) -> eyre::Result<()> {
+    use std::io::Write;
+
+    let mut stderr = StandardStream::stderr(color.color_choice());
+    for (package, config) in packages {
+        stderr.set_color(&CYAN).ok();
+        write!(stderr, "{}", package.name)?;
+        stderr.reset().ok();
+        writeln!(stderr)?;
+        for (feature, enabled) in &package.features {
+            writeln!(stderr, "  {feature} -> [{}]", enabled.join(", "))?;
+        }
+        let num_combinations = package.feature_combinations(config).len();
+        writeln!(
+            stderr,
+            "  {num_combinations} feature combination{}",
+            if num_combinations == 1 { "" } else { "s" }
+        )?;
+    }
+    Ok(())
+}
+
+/// Computes a small set of combinations covering every pair of `features`.
+///
+/// Each pair of features is covered in all four on/off assignments by at least
+/// one combination, unless no valid combination can cover the assignment.
+/// Combinations are built greedily, enabling a feature only if it covers more
+/// uncovered pairs than leaving it disabled and `is_valid` holds.
+///
+/// Since all exclusion rules only ever rule out supersets of some set,
+/// disabling a feature never invalidates a combination.
+pub fn pairwise_combinations<'a>(
+    features: &[&'a String],
+    is_valid: impl Fn(&[&'a String]) -> bool,
+) -> Vec<Vec<&'a String>> {
+    if features.len() < 2 {
+        return features
+            .iter()
+            .copied()
+            .powerset()
+            .filter(|set| is_valid(set))
+            .collect();
+    }
+
+    let enabled = |row: &[Option<bool>]| -> Vec<&'a String> {
+        features
+            .iter()
+            .zip(row)
+            .filter(|(_, on)| **on == Some(true))
+            .map(|(feature, _)| *feature)
+            .collect()
+    };
+
+    let mut uncovered: std::collections::BTreeSet<(usize, bool, usize, bool)> = (0..features.len())
+        .tuple_combinations()
+        .flat_map(|(i, j)| {
+            [(false, false), (false, true), (true, false), (true, true)]
+                .map(|(vi, vj)| (i, vi, j, vj))
+        })
+        .collect();
+
+    let mut combinations = Vec::new();
+    while let Some(&(i, vi, j, vj)) = uncovered.first() {
+        let mut row = vec![None; features.len()];
+        row[i] = Some(vi);
+        row[j] = Some(vj);
+        if !is_valid(&enabled(&row)) {
+            // no valid combination can cover this assignment
+            uncovered.remove(&(i, vi, j, vj));
+            continue;
+        }
+
+        for k in 0..features.len() {
+            if row[k].is_some() {
+                continue;
+            }
+            let gain = |v: bool| {
+                row.iter()
+                    .enumerate()
+                    .filter_map(|(m, vm)| vm.map(|vm| (m, vm)))
+                    .filter(|&(m, vm)| {
+                        let pair = if m < k { (m, vm, k, v) } else { (k, v, m, vm) };
+                        uncovered.contains(&pair)
+                    })
+                    .count()
+            };
+            let enable = gain(true) > gain(false);
+            row[k] = Some(enable);
+            if enable && !is_valid(&enabled(&row)) {
+                row[k] = Some(false);
+            }
+        }
+
+        uncovered.retain(|&(a, va, b, vb)| !(row[a] == Some(va) && row[b] == Some(vb)));
+        combinations.push(enabled(&row));
+    }
+    combinations
+}
+
+/// Renders the `display_name` of a matrix row from a `name_template`.
+///
+/// The placeholders `{package}`, `{features}`, `{feature_count}` and `{index}`
+/// are substituted, where an empty feature combination renders as `empty`.
+#[must_use]
+pub fn render_name_template(
+    template: &str,
+    package: &str,
+    features: &str,
+    index: usize,
+    empty: &str,
+) -> String {
+    let feature_count = features.split(',').filter(|ft| !ft.is_empty()).count();
+    template
+        .replace("{package}", package)
+        .replace(
+            "{features}",
+            if features.is_empty() { empty } else { features },
+        )
+        .replace("{feature_count}", &feature_count.to_string())
+        .replace("{index}", &index.to_string())
+}
+
+/// Splits `items` into batches of at most `size` consecutive items,
+/// where all items of a batch are in the `same_batch` as its first item.
+pub fn batches<T>(
+    items: impl IntoIterator<Item = T>,
+    size: usize,
+    same_batch: impl Fn(&T, &T) -> bool,
+) -> Vec<Vec<T>> {
+    let mut batches: Vec<Vec<T>> = Vec::new();
+    for item in items {
+        match batches.last_mut() {
+            Some(batch) if batch.len() < size && same_batch(&batch[0], &item) => batch.push(item),
+            _ => batches.push(vec![item]),
+        }
+    }
+    batches
+}
+
+/// Merges the matrix rows of each package and target into batches of `size`.
+///
+/// The `features` and `command` of a batch are arrays with an entry per
+/// feature combination, and the display names are joined.
+fn batch_rows(
+    rows: impl Iterator<Item = serde_json::Value>,
+    size: usize,
+) -> Vec<serde_json::Value> {
+    let same_batch = |a: &serde_json::Value, b: &serde_json::Value| {
+        a["name"] == b["name"] && a["target"] == b["target"]
+    };
+    batches(rows, size, same_batch)
+        .into_iter()
+        .map(|batch| {
+            let mut out = batch[0].clone();
+            for key in ["features", "command"] {
+                if out.get(key).is_some() {
+                    out[key] = batch.iter().map(|row| row[key].clone()).collect();
+                }
+            }
+            if out.get("display_name").is_some() {
+                out["display_name"] = batch
+                    .iter()
+                    .filter_map(|row| row["display_name"].as_str())
+                    .join(", ")
+                    .into();
+            }
+            out
+        })
+        .collect()
+}
+
+/// A row of the feature matrix, fully resolved but not yet serialized.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MatrixEntry {
+    pub package: String,
+    pub features: Vec<String>,
+    /// The features of the row if there are none, see [`Config::empty_features_name`].
+    pub empty_features_name: String,
+    /// The matrix config of the package, along with the `target`, `command`
+    /// and `display_name` of the row, if any.
+    pub metadata: serde_json::Value,
+}
+
+impl MatrixEntry {
+    /// The row as printed by the matrix subcommand, with the features
+    /// joined by `separator`.
+    #[must_use]
+    pub fn to_json(&self, separator: &str) -> serde_json::Value {
+        use serde_json_merge::{iter::dfs::Dfs, merge::Merge};
+
+        // keep the name and features after the matrix config and before the fields of the row
+        let row_keys = ["target", "command", "display_name"];
+        let (row, matrix): (serde_json::Map<_, _>, serde_json::Map<_, _>) = self
+            .metadata
+            .as_object()
+            .into_iter()
+            .flatten()
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .partition(|(key, _)| row_keys.contains(&key.as_str()));
+        let mut out = serde_json::Value::Object(matrix);
+        out.merge::<Dfs>(&serde_json::json!({
+            "name": self.package,
+            "features": if self.features.is_empty() {
+                self.empty_features_name.clone()
+            } else {
+                self.features.join(separator)
+            },
+        }));
+        out.merge::<Dfs>(&serde_json::Value::Object(row));
+        out
+    }
+}
+
+/// The rows of the feature matrix of `packages`, as printed by the matrix subcommand
+/// before batching.
+///
+/// With `--balance-shards`, the rows are sharded by the `durations` of earlier runs.
+#[must_use]
+pub fn feature_matrix_entries(
+    packages: &[(&cargo_metadata::Package, Config)],
+    with_command: bool,
+    durations: &BTreeMap<String, f64>,
+    options: &Options,
+) -> Vec<MatrixEntry> {
+    let per_package_features = packages
+        .iter()
+        .filter(|(pkg, _)| has_selected_targets(pkg, options))
+        .flat_map(|(pkg, config)| {
+            let features: Vec<Vec<String>> = if options.packages_only {
+                vec![vec!["default".to_string()]]
+            } else {
+                pkg.feature_combinations(config)
+                    .into_iter()
+                    .map(|features| features.into_iter().cloned().collect())
+                    .collect()
+            };
+            features
+                .into_iter()
+                .map(move |features| (pkg.name.clone(), config, features))
+        });
+    let command = |config: &Config, features: &[String], target: Option<&String>| -> String {
+        let mut args = if options.packages_only || options.no_feature_args {
+            // packages are built with their default features
+            vec![]
+        } else {
+            feature_args(features, config.include_default_features)
+        };
+        if let Some(target) = target {
+            args.extend(["--target".to_string(), target.clone()]);
+        }
+        args.join(" ")
+    };
+
+    // the feature combinations of each package are already in the configured order
+    let rows: Vec<_> = cross_product(
+        cross_product(
+            per_package_features.sorted_by(|(a_name, _, _), (b_name, _, _)| a_name.cmp(b_name)),
+            &options.targets,
+        ),
+        &options.toolchains,
+    )
+    .collect();
+    // shards of the matrix select the same feature combinations as sharded runs
+    let shards = assign_shards(
+        options.shard,
+        durations,
+        rows.iter()
+            .map(|(((name, _, features), target), toolchain)| {
+                let features = features.iter().map(String::as_str).collect();
+                (name.as_str(), features, *target, *toolchain)
+            }),
+    );
+    rows.into_iter()
+        .enumerate()
+        .filter(|(idx, _)| in_shard(*idx, options.shard, shards.as_deref()))
+        .map(|(idx, (((name, config, features), target), toolchain))| {
+            use serde_json_merge::{iter::dfs::Dfs, merge::Merge};
+
+            let mut metadata = serde_json::json!(config.matrix);
+            if let Some(target) = target {
+                metadata.merge::<Dfs>(&serde_json::json!({ "target": target }));
+            }
+            if let Some(toolchain) = toolchain {
+                metadata.merge::<Dfs>(&serde_json::json!({ "toolchain": toolchain }));
+            }
+            if with_command {
+                metadata.merge::<Dfs>(
+                    &serde_json::json!({ "command": command(config, &features, target) }),
+                );
+            }
+            if let Some(ref template) = config.name_template {
+                let display_name = render_name_template(
+                    template,
+                    &name,
+                    &features.join(","),
+                    idx,
+                    config.empty_features_name.as_deref().unwrap_or("none"),
+                );
+                metadata.merge::<Dfs>(&serde_json::json!({ "display_name": display_name }));
+            }
+            MatrixEntry {
+                package: name,
+                features,
+                empty_features_name: config.empty_features_name.clone().unwrap_or_default(),
+                metadata,
+            }
+        })
+        .collect()
+}
+
+/// Merges the matrix rows of packages whose rows are identical apart from
+/// their name, e.g. leaf crates with the same features.
+///
+/// The `name` of merged rows is an array of the package names, and their
+/// display names are joined.
+fn collapse_identical_rows(
+    rows: impl Iterator<Item = serde_json::Value>,
+) -> Vec<serde_json::Value> {
+    let without_name = |row: &serde_json::Value| {
+        let mut row = row.clone();
+        if let Some(row) = row.as_object_mut() {
+            row.remove("name");
+            row.remove("display_name");
+        }
+        row
+    };
+    // the rows of a package are consecutive
+    let mut packages: Vec<(Vec<serde_json::Value>, Vec<serde_json::Value>)> = Vec::new();
+    for (_, package_rows) in &rows.chunk_by(|row| row["name"].clone()) {
+        let package_rows: Vec<_> = package_rows.collect();
+        let key: Vec<_> = package_rows.iter().map(without_name).collect();
+        let Some((_, rows)) = packages.iter_mut().find(|(other, _)| *other == key) else {
+            packages.push((key, package_rows));
+            continue;
+        };
+        for (row, package_row) in rows.iter_mut().zip(package_rows) {
+            let mut names = match row["name"].take() {
+                serde_json::Value::Array(names) => names,
+                name => vec![name],
+            };
+            names.push(package_row["name"].clone());
+            row["name"] = names.into();
+            if let (Some(a), Some(b)) = (
+                row["display_name"].as_str(),
+                package_row["display_name"].as_str(),
+            ) {
+                row["display_name"] = format!("{a}, {b}").into();
+            }
+        }
+    }
+    packages.into_iter().flat_map(|(_, rows)| rows).collect()
+}
+
+/// Writes the features of each feature combination of the packages to
+/// `dir/<package>/<index>.features`, e.g. to `xargs` over, returning the number of files.
+///
+/// Feature files of an earlier, larger matrix are removed.
+///
+/// # Errors
+///
+/// If a directory or file can not be written, an error is returned.
+pub fn write_feature_files(
+    packages: &[(&cargo_metadata::Package, &Config)],
+    dir: &std::path::Path,
+) -> eyre::Result<usize> {
+    let mut num_files = 0;
+    for (package, config) in packages {
+        let package_dir = dir.join(package.name.as_str());
+        std::fs::create_dir_all(&package_dir)
+            .wrap_err_with(|| format!("failed to create {}", package_dir.display()))?;
+        for entry in std::fs::read_dir(&package_dir)? {
+            let path = entry?.path();
+            if path.extension().is_some_and(|ext| ext == "features") {
+                std::fs::remove_file(&path)
+                    .wrap_err_with(|| format!("failed to remove {}", path.display()))?;
+            }
+        }
+        for (idx, features) in package.feature_matrix(config).into_iter().enumerate() {
+            let path = package_dir.join(format!("{idx}.features"));
+            std::fs::write(&path, format!("{features}\n"))
+                .wrap_err_with(|| format!("failed to write {}", path.display()))?;
+            num_files += 1;
+        }
+    }
+    Ok(num_files)
+}
+
+pub fn print_feature_matrix(
+    packages: &[(&cargo_metadata::Package, Config)],
+    pretty: bool,
+    format: MatrixFormat,
+    with_command: bool,
+    durations: &BTreeMap<String, f64>,
+    options: &Options,
+) -> eyre::Result<()> {
+    if let Some(ref dir) = options.emit_feature_files {
+        let packages: Vec<_> = packages
+            .iter()
+            .filter(|(package, _)| has_selected_targets(package, options))
+            .map(|(package, config)| (*package, config))
+            .collect();
+        let num_files = write_feature_files(&packages, dir)?;
+        println!("wrote {num_files} feature files to {}", dir.display());
+        return Ok(());
+    }
+
+    let rows = feature_matrix_entries(packages, with_command, durations, options)
+        .into_iter()
+        .map(|entry| entry.to_json(options.features_separator.as_deref().unwrap_or(",")));
+    let rows: Box<dyn Iterator<Item = serde_json::Value>> = if options.collapse_identical {
+        Box::new(collapse_identical_rows(rows).into_iter())
+    } else {
+        Box::new(rows)
+    };
+    let rows: Box<dyn Iterator<Item = serde_json::Value>> = match options.batch {
+        Some(size) => Box::new(batch_rows(rows, size).into_iter()),
+        None => Box::new(rows),
+    };
+
+    if let Some(ref path) = options.diff_against {
+        let old = read_matrix(path)?;
+        let changes = diff_matrices(&old, &serde_json::Value::Array(rows.collect()))
+            .wrap_err_with(|| format!("invalid matrix in {}", path.display()))?;
+        let mut stdout = StandardStream::stdout(options.color.color_choice());
+        print_matrix_diff(&changes, &mut stdout)?;
+        return Ok(());
+    }
+
+    if format == MatrixFormat::Ndjson {
+        use std::io::Write;
+        if pretty {
+            eprintln!("warning: --pretty is ignored for the ndjson output format");
+        }
+        let mut stdout = io::stdout().lock();
+        for row in rows {
+            serde_json::to_writer(&mut stdout, &row)?;
+            writeln!(stdout)?;
+            stdout.flush()?;
+        }
+        return Ok(());
+    }
+
+    let matrix: Vec<serde_json::Value> = rows.collect();
+    let matrix = match format {
+        MatrixFormat::Json | MatrixFormat::Ndjson => serde_json::json!(matrix),
+        MatrixFormat::Github => serde_json::json!({ "include": matrix }),
+    };
+    let matrix = if pretty {
+        serde_json::to_string_pretty(&matrix)
+    } else {
+        serde_json::to_string(&matrix)
+    }?;
+
+    match std::env::var_os("GITHUB_OUTPUT") {
+        Some(github_output) if format == MatrixFormat::Github => {
+            use std::io::Write;
+            let mut file = std::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&github_output)
+                .wrap_err_with(|| {
+                    format!("failed to open {}", PathBuf::from(&github_output).display())
+                })?;
+            if pretty {
+                // multiline values must use the heredoc-style delimiter syntax
+                writeln!(file, "matrix<<EOF\n{matrix}\nEOF")?;
+            } else {
+                writeln!(file, "matrix={matrix}")?;
+            }
+        }
+        _ => println!("{matrix}"),
+    }
+    Ok(())
+}
+
+pub fn print_feature_list(
+    packages: &[(&cargo_metadata::Package, Config)],
+    silent: bool,
+    color: ColorMode,
+) -> eyre::Result<()> {
+    let mut stdout = StandardStream::stdout(color.color_choice());
+    let mut num_feature_sets = 0;
+
+    for (package, config) in packages {
+        let matrix = package.feature_matrix(config);
+        num_feature_sets += matrix.len();
+
+        if !silent {
+            stdout.set_color(&CYAN).ok();
+            print!("{}", package.name);
+            stdout.reset().ok();
+            println!();
+        }
+        for features in matrix {
+            match config.empty_features_name {
+                Some(ref name) if features.is_empty() => println!("{name}"),
+                _ => println!("{features}"),
+            }
+        }
+    }
+
+    // the total goes to stderr, so that the list can be piped into shell loops
+    let num_packages = packages.len();
+    eprintln!(
+        "{num_feature_sets} total feature combination{} for {num_packages} package{}",
+        if num_feature_sets == 1 { "" } else { "s" },
+        if num_packages == 1 { "" } else { "s" },
+    );
+    Ok(())
+}
+
+/// The workspace config and the fully resolved config of each package,
+/// as printed by `--print-config`.
+pub fn resolved_config_json(
+    packages: &[(&cargo_metadata::Package, Config)],
+    workspace: &WorkspaceConfig,
+) -> eyre::Result<serde_json::Value> {
+    let packages = packages
+        .iter()
+        .map(|(package, config)| Ok((package.name.clone(), serde_json::to_value(config)?)))
+        .collect::<eyre::Result<serde_json::Map<_, _>>>()?;
+    Ok(serde_json::json!({
+        "workspace": workspace,
+        "packages": packages,
+    }))
+}
+
+pub fn print_feature_count(packages: &[(&cargo_metadata::Package, Config)], per_package: bool) {
+    let counts = packages
+        .iter()
+        .map(|(package, config)| (&package.name, package.feature_matrix(config).len()));
+    if per_package {
+        for (name, count) in counts {
+            println!("{name}: {count}");
+        }
+    } else {
+        println!("{}", counts.map(|(_, count)| count).sum::<usize>());
+    }
+}
+
+#[must_use]
+pub fn color_spec(color: Color, bold: bool) -> ColorSpec {
+    let mut spec = ColorSpec::new();
+    spec.set_fg(Some(color));
+    spec.set_bold(bold);
+    spec
+}
+
+pub fn warning_counts(output: &str) -> impl Iterator<Item = usize> + '_ {
+    lazy_static::lazy_static! {
+        static ref WARNING_REGEX: Regex =
+            Regex::new(r"warning: .* generated (\d+) warnings?").unwrap();
+    }
+    WARNING_REGEX
+        .captures_iter(output)
+        .filter_map(|cap| cap.get(1))
+        .map(|m| m.as_str().parse::<usize>().unwrap_or(0))
+}
+
+pub fn error_counts(output: &str) -> impl Iterator<Item = usize> + '_ {
+    lazy_static::lazy_static! {
+        static ref ERROR_REGEX: Regex =
+            Regex::new(r"error: could not compile `[^`]*`(?: \([^)]*\))? due to\s*(\d*)\s*previous errors?").unwrap();
+    }
+    ERROR_REGEX
+        .captures_iter(output)
+        .filter_map(|cap| cap.get(1))
+        .map(|m| m.as_str().parse::<usize>().unwrap_or(1))
+}
+
+/// Number of warning and error diagnostics emitted by the compiler.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct DiagnosticCounts {
+    pub warnings: usize,
+    pub errors: usize,
+}
+
+/// A warning or error emitted by the compiler.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub error: bool,
+    /// Lint name or error code, e.g. `unused_imports` or `E0425`.
+    pub code: Option<String>,
+    pub message: String,
+    /// File, line and column of the primary span.
+    pub location: Option<(String, usize, usize)>,
+}
+
+impl From<&[Diagnostic]> for DiagnosticCounts {
+    fn from(diagnostics: &[Diagnostic]) -> Self {
+        let errors = diagnostics.iter().filter(|d| d.error).count();
+        Self {
+            warnings: diagnostics.len() - errors,
+            errors,
+        }
+    }
+}
+
+/// Renders the `--message-format=json` output of cargo in human-readable form.
+///
+/// The rendered compiler diagnostics and any non-JSON lines (e.g. test output)
+/// are written to `out`, while warnings and errors are counted by their level.
+pub fn render_json_messages(
+    reader: impl io::BufRead,
+    out: &mut impl io::Write,
+) -> io::Result<DiagnosticCounts> {
+    let diagnostics = render_json_diagnostics(reader, out)?;
+    Ok(DiagnosticCounts::from(diagnostics.as_slice()))
+}
+
+/// Renders the `--message-format=json` output of cargo like
+/// [`render_json_messages`], returning the warnings and errors.
+pub fn render_json_diagnostics(
+    reader: impl io::BufRead,
+    out: &mut impl io::Write,
+) -> io::Result<Vec<Diagnostic>> {
+    Ok(render_json_output(reader, out)?.diagnostics)
+}
+
+/// The warnings, errors and artifacts in the `--message-format=json` output of cargo.
+#[derive(Debug, Default)]
+pub struct JsonOutput {
+    pub diagnostics: Vec<Diagnostic>,
+    pub artifacts: Vec<cargo_metadata::Artifact>,
+}
+
+/// Renders the `--message-format=json` output of cargo like
+/// [`render_json_messages`], returning the warnings, errors and artifacts.
+pub fn render_json_output(
+    reader: impl io::BufRead,
+    out: &mut impl io::Write,
+) -> io::Result<JsonOutput> {
+    use cargo_metadata::{diagnostic::DiagnosticLevel, Message};
+
+    let mut diagnostics = Vec::new();
+    let mut artifacts = Vec::new();
+    for message in Message::parse_stream(reader) {
+        match message? {
+            Message::CompilerMessage(msg) => {
+                let error = match msg.message.level {
+                    DiagnosticLevel::Warning => Some(false),
+                    DiagnosticLevel::Error | DiagnosticLevel::Ice => Some(true),
+                    _ => None,
+                };
+                if let Some(error) = error {
+                    let location = msg
+                        .message
+                        .spans
+                        .iter()
+                        .find(|span| span.is_primary)
+                        .map(|span| (span.file_name.clone(), span.line_start, span.column_start));
+                    diagnostics.push(Diagnostic {
+                        error,
+                        code: msg.message.code.as_ref().map(|code| code.code.clone()),
+                        message: msg.message.message.clone(),
+                        location,
+                    });
+                }
+                if let Some(rendered) = msg.message.rendered {
+                    out.write_all(rendered.as_bytes())?;
+                    out.flush()?;
+                }
+            }
+            Message::CompilerArtifact(artifact) => artifacts.push(artifact),
+            Message::TextLine(line) => {
+                writeln!(out, "{line}")?;
+                out.flush()?;
+            }
+            _ => {}
+        }
+    }
+    Ok(JsonOutput {
+        diagnostics,
+        artifacts,
+    })
+}
+
+/// Size in bytes of the primary artifact of `package`, i.e. its first binary
+/// or else its library, ignoring build scripts and test harnesses.
+#[must_use]
+pub fn primary_artifact_size(
+    artifacts: &[cargo_metadata::Artifact],
+    package: &cargo_metadata::Package,
+) -> Option<u64> {
+    use cargo_metadata::TargetKind;
+
+    let artifact = artifacts
+        .iter()
+        .filter(|artifact| artifact.package_id == package.id && !artifact.profile.test)
+        .filter(|artifact| !artifact.target.is_kind(TargetKind::CustomBuild))
+        .min_by_key(|artifact| !artifact.target.is_kind(TargetKind::Bin))?;
+    let path = artifact
+        .executable
+        .as_ref()
+        .or_else(|| artifact.filenames.first())?;
+    std::fs::metadata(path).ok().map(|metadata| metadata.len())
+}
+
+/// Formats a size in bytes with binary units, e.g. `1.5 MiB`.
+#[must_use]
+pub fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+    if bytes < 1024 {
+        return format!("{bytes} B");
+    }
+    #[allow(clippy::cast_precision_loss)]
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{size:.1} {}", UNITS[unit])
+}
+
+/// The size column of a feature combination in the summary, if its size was recorded.
+fn size_column(summary: &Summary) -> Option<String> {
+    summary
+        .artifact_size
+        .map(|size| format!("size = {},", format_size(size)))
+}
+
+pub fn print_summary(
+    summary: &[Summary],
+    out: &mut impl WriteColor,
+    elapsed: Duration,
+    group_by_package: bool,
+) -> io::Result<()> {
+    let num_packages = summary
+        .iter()
+        .map(|s| &s.package_name)
+        .collect::<HashSet<_>>()
+        .len();
+    // each toolchain and target of a feature combination is a row of its own,
+    // counted like in `num_failures`
+    let num_feature_sets = summary
+        .iter()
+        .map(|s| (&s.package_name, &s.features, &s.toolchain, &s.target))
+        .collect::<HashSet<_>>()
+        .len();
+
+    writeln!(out)?;
+    out.set_color(&CYAN).ok();
+    write!(out, "    Finished ")?;
+    out.reset().ok();
+    writeln!(
+        out,
+        "{num_feature_sets} total feature combination{} for {num_packages} package{} in {elapsed:?}",
+        if num_feature_sets > 1 { "s" } else { "" },
+        if num_packages > 1 { "s" } else { "" },
+
+    )?;
+    writeln!(out)?;
+
+    let most_errors = summary.iter().map(|s| s.num_errors).max().unwrap_or(0);
+    let most_warnings = summary.iter().map(|s| s.num_warnings).max().unwrap_or(0);
+    let errors_width = most_errors.to_string().len();
+    let warnings_width = most_warnings.to_string().len();
+    let durations: Vec<String> = summary
+        .iter()
+        .map(|s| format!("{:.2}s", s.duration.as_secs_f64()))
+        .collect();
+    let duration_width = durations.iter().map(String::len).max().unwrap_or(0);
+    // toolchains and targets are shown as aligned columns, if any
+    let column = |name: &str, value: Option<&String>| value.map(|v| format!("{name} = {v},"));
+    let column_width = |name: &str, value: fn(&Summary) -> Option<&String>| {
+        summary
+            .iter()
+            .filter_map(|s| column(name, value(s)).map(|c| c.len()))
+            .max()
+            .unwrap_or(0)
+    };
+    let toolchain_width = column_width("toolchain", |s| s.toolchain.as_ref());
+    let target_column = |s: &Summary| {
+        let name = if s.cross { "cross target" } else { "target" };
+        column(name, s.target.as_ref())
+    };
+    let target_width = summary
+        .iter()
+        .filter_map(|s| target_column(s).map(|c| c.len()))
+        .max()
+        .unwrap_or(0);
+    let size_width = summary
+        .iter()
+        .filter_map(|s| size_column(s).map(|c| c.len()))
+        .max()
+        .unwrap_or(0);
+
+    let print_row = |out: &mut dyn WriteColor, s: &Summary, duration: &str| -> io::Result<()> {
+        let status = summary_status(s);
+        out.set_color(status.color()).ok();
+        write!(out, "{:>12} ", status.label())?;
+        out.reset().ok();
+        let columns = [
+            (column("toolchain", s.toolchain.as_ref()), toolchain_width),
+            (target_column(s), target_width),
+            (size_column(s), size_width),
+        ]
+        .into_iter()
+        .filter_map(|(column, width)| Some(format!("{:width$} ", column?)))
+        .join("");
+        if let Some(ref failing_subset) = s.failing_subset {
+            return writeln!(
+                out,
+                "{} ( {columns}features = [{}], superset of failing features = [{}] )",
+                s.package_name,
+                s.features.iter().join(", "),
+                failing_subset.iter().join(", "),
+            );
+        }
+        writeln!(
+            out,
+            "{} ( {:ew$} errors, {:ww$} warnings, {:>dw$}, {columns}features = [{}] )",
+            s.package_name,
+            s.num_errors.to_string(),
+            s.num_warnings.to_string(),
+            duration,
+            s.features.iter().join(", "),
+            ew = errors_width,
+            ww = warnings_width,
+            dw = duration_width,
+        )
+    };
+
+    if !group_by_package {
+        for (s, duration) in summary.iter().zip(&durations) {
+            print_row(out, s, duration)?;
+        }
+        writeln!(out)?;
+        return Ok(());
+    }
+
+    for package_name in summary.iter().map(|s| &s.package_name).unique() {
+        let rows: Vec<_> = summary
+            .iter()
+            .zip(&durations)
+            .filter(|(s, _)| &s.package_name == package_name)
+            .collect();
+        out.set_color(&CYAN).ok();
+        write!(out, "{:>12} ", "Package")?;
+        out.reset().ok();
+        writeln!(out, "{package_name}")?;
+        for (s, duration) in &rows {
+            print_row(out, s, duration)?;
+        }
+        let counts = rows.iter().counts_by(|(s, _)| summary_status(s));
+        writeln!(
+            out,
+            "{:>12} {package_name} ( {} )",
+            "",
+            SummaryStatus::ALL
+                .iter()
+                .filter_map(|status| Some(format!("{} {}", counts.get(status)?, status.label())))
+                .join(", "),
+        )?;
+        writeln!(out)?;
+    }
+
+    let num_failures = num_failures(summary);
+    out.set_color(&CYAN).ok();
+    write!(out, "{:>12} ", "Total")?;
+    out.reset().ok();
+    writeln!(
+        out,
+        "{num_packages} package{}, {num_feature_sets} feature combination{}, {num_failures} failed",
+        if num_packages > 1 { "s" } else { "" },
+        if num_feature_sets > 1 { "s" } else { "" },
+    )?;
+    writeln!(out)?;
+    Ok(())
+}
+
+/// Whether a feature combination with `num_errors` and `num_warnings` has no
+/// errors and at most `max_warnings` warnings, if there is a threshold.
+fn within_warning_threshold(
+    num_errors: usize,
+    num_warnings: usize,
+    max_warnings: Option<usize>,
+) -> bool {
+    max_warnings.is_none_or(|max_warnings| num_errors == 0 && num_warnings <= max_warnings)
+}
+
+/// The status of a feature combination in the summary.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum SummaryStatus {
+    Pass,
+    Cached,
+    Unchanged,
+    Warn,
+    Fail,
+    Timeout,
+    Killed,
+    Skipped,
+}
+
+impl SummaryStatus {
+    /// All statuses, in the order of the subtotals of packages.
+    const ALL: [Self; 8] = [
+        Self::Pass,
+        Self::Cached,
+        Self::Unchanged,
+        Self::Warn,
+        Self::Fail,
+        Self::Timeout,
+        Self::Killed,
+        Self::Skipped,
+    ];
+
+    fn label(self) -> &'static str {
+        match self {
+            Self::Pass => "PASS",
+            Self::Cached => "CACHED",
+            Self::Unchanged => "UNCHANGED",
+            Self::Warn => "WARN",
+            Self::Fail => "FAIL",
+            Self::Timeout => "TIMEOUT",
+            Self::Killed => "KILLED",
+            Self::Skipped => "SKIPPED",
+        }
+    }
+
+    fn color(self) -> &'static ColorSpec {
+        match self {
+            Self::Pass | Self::Cached | Self::Unchanged => &GREEN,
+            Self::Warn | Self::Skipped => &YELLOW,
+            Self::Fail | Self::Timeout | Self::Killed => &RED,
+        }
+    }
+}
+
+/// The status of a feature combination in the summary.
+fn summary_status(summary: &Summary) -> SummaryStatus {
+    if summary.cached {
+        SummaryStatus::Cached
+    } else if summary.unchanged {
+        SummaryStatus::Unchanged
+    } else if summary.failing_subset.is_some() {
+        SummaryStatus::Skipped
+    } else if summary.timed_out {
+        SummaryStatus::Timeout
+    } else if summary.signal.is_some() {
+        SummaryStatus::Killed
+    } else if !summary.pedantic_success {
+        SummaryStatus::Fail
+    } else if summary.num_warnings > 0 {
+        SummaryStatus::Warn
+    } else {
+        SummaryStatus::Pass
+    }
+}
+
+/// The cargo subcommand run for each feature combination.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CargoSubcommand {
+    Build,
+    Check,
+    Clippy,
+    Test,
+    Bench,
+    /// `cargo nextest run` or `cargo nextest list`.
+    Nextest,
+    Doc,
+    Run,
+    /// `cargo fix`, which modifies the sources.
+    Fix,
+    /// `cargo fmt`, which modifies the sources.
+    Fmt,
+    /// Any other subcommand, such as `udeps`.
+    Other,
+}
+
+impl CargoSubcommand {
+    /// The label shown in front of each feature combination.
+    #[must_use]
+    pub fn label(self) -> &'static str {
+        match self {
+            Self::Build => "Building",
+            Self::Check | Self::Clippy => "Checking",
+            Self::Test | Self::Nextest => "Testing",
+            Self::Bench => "Benchmarking",
+            Self::Doc => "Documenting",
+            Self::Fix => "Fixing",
+            Self::Fmt => "Formatting",
+            Self::Run | Self::Other => "Running",
+        }
+    }
+
+    /// Whether the subcommand modifies the sources, so that the feature
+    /// combinations must not run concurrently.
+    #[must_use]
+    pub fn modifies_sources(self) -> bool {
+        matches!(self, Self::Fix | Self::Fmt)
+    }
+
+    /// Whether the subcommand accepts the cargo arguments selecting features,
+    /// targets and colors, which `cargo fmt` rejects.
+    #[must_use]
+    pub fn accepts_build_args(self) -> bool {
+        !matches!(self, Self::Fmt)
+    }
+}
+
+/// Detects the cargo subcommand in the cargo arguments.
+///
+/// Subcommands not known to `cargo fc` are run as [`CargoSubcommand::Other`].
+#[must_use]
+pub fn cargo_subcommand(cargo_args: &Args) -> CargoSubcommand {
+    let Some(idx) = cargo_args.subcommand_position() else {
+        return CargoSubcommand::Other;
+    };
+    let subcommand = cargo_args[idx].as_str();
+    if subcommand == "nextest" {
+        // the nextest subcommand is its first argument that is not an option
+        let nextest_subcommand = cargo_args[idx + 1..]
+            .iter()
+            .take_while(|arg| *arg != "--")
+            .find(|arg| !arg.starts_with('-'));
+        return match nextest_subcommand.map(String::as_str) {
+            Some("run" | "list") => CargoSubcommand::Nextest,
+            _ => CargoSubcommand::Other,
+        };
+    }
+    [
+        ("build", CargoSubcommand::Build),
+        ("check", CargoSubcommand::Check),
+        ("clippy", CargoSubcommand::Clippy),
+        ("test", CargoSubcommand::Test),
+        ("bench", CargoSubcommand::Bench),
+        ("doc", CargoSubcommand::Doc),
+        ("run", CargoSubcommand::Run),
+        ("fix", CargoSubcommand::Fix),
+        ("fmt", CargoSubcommand::Fmt),
+    ]
+    .into_iter()
+    .find_map(|(name, cargo_subcommand)| (name == subcommand).then_some(cargo_subcommand))
+    .unwrap_or(CargoSubcommand::Other)
+}
+
+/// Checks that the cargo subcommand of `cargo_args` is known or allowed with
+/// `--allow-subcommand` or the `allowed_subcommands` of the workspace config.
+///
+/// # Errors
+///
+/// If the subcommand is neither known nor allowed, e.g. a typo.
+pub fn check_subcommand(cargo_args: &Args, options: &Options) -> eyre::Result<()> {
+    if cargo_subcommand(cargo_args) != CargoSubcommand::Other {
+        return Ok(());
+    }
+    let Some(subcommand) = cargo_args
+        .subcommand_position()
+        .and_then(|idx| cargo_args.get(idx))
+    else {
+        return Ok(());
+    };
+    if options.allowed_subcommands.contains(subcommand) {
+        return Ok(());
+    }
+    eyre::bail!(
+        "unknown cargo subcommand {subcommand:?}, allow it with --allow-subcommand {subcommand} \
+        or allowed_subcommands in the workspace config"
+    )
+}
+
+/// Position of a feature combination among all feature combinations of a run.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Progress {
+    pub current: usize,
+    pub total: usize,
+}
+
+impl std::fmt::Display for Progress {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let width = self.total.to_string().len();
+        write!(f, "[ {:>width$}/{} ]", self.current, self.total)
+    }
+}
+
+fn print_package_cmd(
+    job: &Job<'_>,
+    cargo_args: &Args,
+    all_args: &[String],
+    options: &Options,
+    out: &mut impl WriteColor,
+) -> io::Result<()> {
+    if options.quiet >= 2 && !options.dry_run {
+        return Ok(());
+    }
+    // there is no cargo output to separate from when doing a dry run
+    let separate = !options.silent && !options.dry_run;
+    if separate {
+        writeln!(out)?;
+    }
+    if let Some(progress) = job.progress {
+        write!(out, "{progress}")?;
+    }
+    out.set_color(&CYAN).ok();
+    write!(out, "{:>12} ", cargo_subcommand(cargo_args).label())?;
+    out.reset().ok();
+    write!(out, "{} ( ", job.package.name)?;
+    if let Some(toolchain) = job.toolchain {
+        write!(out, "toolchain = {toolchain}, ")?;
+    }
+    if let Some(target) = job.target {
+        let name = if options.use_cross {
+            "cross target"
+        } else {
+            "target"
+        };
+        write!(out, "{name} = {target}, ")?;
+    }
+    write!(out, "features = [{}] )", job.features.iter().join(", "))?;
+    if options.verbose > 0 || options.dry_run {
+        let cargo = options
+            .cargo_bin
+            .as_deref()
+            .map_or("cargo".into(), std::path::Path::to_string_lossy);
+        write!(out, " [{cargo} {}]", all_args.join(" "))?;
+    }
+    writeln!(out)?;
+    if separate {
+        writeln!(out)?;
+    }
+    Ok(())
+}
+
+/// A feature combination of a package to run cargo for.
+struct Job<'a> {
+    /// Index of the job among all jobs of the run, used for sharding.
+    idx: usize,
+    package: &'a cargo_metadata::Package,
+    config: &'a Config,
+    features: Vec<&'a String>,
+    toolchain: Option<&'a String>,
+    target: Option<&'a String>,
+    progress: Option<Progress>,
+}
+
+/// Pairs each item with each of the `values`, or with `None` if there are no values.
+///
+/// This expands the feature combinations into one job per `--target` and `--toolchain`.
+pub fn cross_product<'a, T: Clone + 'a>(
+    items: impl Iterator<Item = T> + 'a,
+    values: &'a [String],
+) -> impl Iterator<Item = (T, Option<&'a String>)> + 'a {
+    items.flat_map(move |item| {
+        let values: Vec<Option<&String>> = if values.is_empty() {
+            vec![None]
+        } else {
+            values.iter().map(Some).collect()
+        };
+        values.into_iter().map(move |value| (item.clone(), value))
+    })
+}
+
+/// Inputs of the cache keys that are the same for many feature combinations.
+///
+/// These are computed once up front, as they are expensive to compute.
+struct CacheInputs<'a> {
+    /// Verbose rustc version of each toolchain.
+    rustc_versions: HashMap<Option<&'a String>, String>,
+    /// Digest of the sources of each package.
+    source_digests: HashMap<&'a cargo_metadata::PackageId, u64>,
+    /// Options and environment changing the outcome of cargo.
+    env: Vec<String>,
+}
+
+impl<'a> CacheInputs<'a> {
+    fn new(
+        packages: &'a [(&'a cargo_metadata::Package, Config)],
+        all_packages: &[cargo_metadata::Package],
+        options: &'a Options,
+    ) -> eyre::Result<Self> {
+        let toolchains: Vec<Option<&String>> = if options.toolchains.is_empty() {
+            vec![None]
+        } else {
+            options.toolchains.iter().map(Some).collect()
+        };
+        let rustc_versions = toolchains
+            .into_iter()
+            .map(|toolchain| Ok((toolchain, crate::cache::rustc_version(toolchain)?)))
+            .collect::<eyre::Result<_>>()?;
+        let source_digests = packages
+            .iter()
+            .map(|(package, _)| {
+                let paths = source_paths(package, all_packages);
+                let digest = crate::cache::source_digest(paths.iter().map(PathBuf::as_path))
+                    .wrap_err_with(|| format!("failed to hash sources of {}", package.name))?;
+                Ok((&package.id, digest))
+            })
+            .collect::<eyre::Result<_>>()?;
+        let env = ["RUSTFLAGS", "CARGO_ENCODED_RUSTFLAGS", "FC_CARGO"]
+            .into_iter()
+            .map(|key| format!("{key}={:?}", std::env::var_os(key)))
+            .chain([
+                format!("config_file={}", config_file_digest(options)?),
+                format!("cargo_bin={:?}", options.cargo_bin),
+                format!("errors_only={}", options.errors_only),
+                format!("max_warnings={:?}", options.max_warnings),
+            ])
+            .collect();
+        Ok(Self {
+            rustc_versions,
+            source_digests,
+            env,
+        })
+    }
+
+    fn key(&self, job: &Job<'_>, args: &[String]) -> String {
+        // the feature environment may come from a --fc-config file, so it is resolved here
+        let feature_env = feature_env(job.config, &job.features).unwrap_or_default();
+        let env: Vec<String> = self
+            .env
+            .iter()
+            .cloned()
+            .chain(
+                feature_env
+                    .iter()
+                    .map(|(key, value)| format!("{key}={value}")),
+            )
+            .collect();
+        crate::cache::Key {
+            package_id: &job.package.id.repr,
+            features: &job.features,
+            args,
+            env: &env,
+            rustc_version: self
+                .rustc_versions
+                .get(&job.toolchain)
+                .map_or("", String::as_str),
+            source_digest: self
+                .source_digests
+                .get(&job.package.id)
+                .copied()
+                .unwrap_or_default(),
+        }
+        .digest()
+    }
+
+    /// Key of all feature combinations of a package for `--only-changed`.
+    ///
+    /// Besides the inputs of the key of each feature combination, this covers the
+    /// feature combinations, the environment of the config and the `cargo_args`
+    /// before adding the color, which does not change whether a package passes.
+    fn package_key(
+        &self,
+        package: &cargo_metadata::Package,
+        config: &Config,
+        cargo_args: &[String],
+        options: &Options,
+    ) -> String {
+        let mut digest = crate::cache::Digest::default();
+        digest
+            .write(&package.id.repr)
+            .write_all(cargo_args)
+            .write_all(&self.env)
+            .write_all(&options.targets)
+            .write_all(&options.toolchains)
+            .write_all(&self.rustc_versions.values().sorted().collect::<Vec<_>>())
+            .write(
+                self.source_digests
+                    .get(&package.id)
+                    .copied()
+                    .unwrap_or_default()
+                    .to_le_bytes(),
+            )
+            .write_all(&package.feature_matrix(config))
+            .write_all(&config.clippy_args);
+        for (feature, env) in config
+            .feature_env
+            .iter()
+            .sorted_by_key(|(feature, _)| *feature)
+        {
+            digest.write(feature);
+            for (key, value) in env.iter().sorted() {
+                digest.write(key).write(value);
+            }
+        }
+        digest.hex()
+    }
+}
+
+/// Files and directories whose changes invalidate the cached results of a package.
+///
+/// These are the package directory, including its manifest, the directories
+/// of its path dependencies and theirs, looked up in `all_packages`, and the
+/// lock file of the workspace.
+fn source_paths(
+    package: &cargo_metadata::Package,
+    all_packages: &[cargo_metadata::Package],
+) -> Vec<PathBuf> {
+    let Some(package_dir) = package.manifest_path.parent() else {
+        return vec![];
+    };
+    let lock_file = package_dir
+        .ancestors()
+        .map(|dir| dir.join("Cargo.lock"))
+        .find(|lock_file| lock_file.is_file());
+    let mut dirs = vec![package_dir.to_owned()];
+    let mut queue = vec![package];
+    while let Some(package) = queue.pop() {
+        for dir in package
+            .dependencies
+            .iter()
+            .filter_map(|dependency| dependency.path.as_ref())
+        {
+            if dirs.contains(dir) {
+                continue;
+            }
+            dirs.push(dir.clone());
+            queue.extend(
+                all_packages
+                    .iter()
+                    .filter(|other| other.manifest_path.parent() == Some(dir.as_path())),
+            );
+        }
+    }
+    dirs.into_iter()
+        .chain(lock_file)
+        .map(cargo_metadata::camino::Utf8PathBuf::into_std_path_buf)
+        .collect()
+}
+
+/// Digest of the contents of the `--fc-config` file, if any.
+fn config_file_digest(options: &Options) -> eyre::Result<String> {
+    let Some(ref path) = options.config_file else {
+        return Ok(String::new());
+    };
+    let config = std::fs::read(path)
+        .wrap_err_with(|| format!("failed to read config file {}", path.display()))?;
+    Ok(crate::cache::Digest::default().write(config).hex())
+}
+
+/// Running child cargo processes, keyed by the index of their feature combination.
+///
+/// Children are registered here so that outstanding jobs can be killed
+/// when `--fail-fast` observes the first failure.
+#[derive(Debug, Default)]
+struct Children(Mutex<HashMap<usize, process::Child>>);
+
+impl Children {
+    fn insert(&self, idx: usize, child: process::Child) {
+        if let Ok(mut children) = self.0.lock() {
+            children.insert(idx, child);
+        }
+    }
+
+    fn remove(&self, idx: usize) -> Option<process::Child> {
+        self.0.lock().ok()?.remove(&idx)
+    }
+
+    fn kill_all(&self) {
+        if let Ok(mut children) = self.0.lock() {
+            for child in children.values_mut() {
+                child.kill().ok();
+            }
+        }
+    }
+}
+
+/// Shared state of a single `run_cargo_command` invocation.
+struct Invocation<'a> {
+    cargo_args: &'a Args,
+    extra_args: &'a [String],
+    /// Cargo is run from the workspace root, so that all packages share its
+    /// target directory and `.cargo/config.toml`.
+    workspace_root: &'a std::path::Path,
+    missing_arguments: bool,
+    options: &'a Options,
+    children: Children,
+}
+
+/// Cargo arguments selecting the features of a feature combination.
+///
+/// Unless `default_features` is set, the default features are disabled.
+/// The features are always separated by commas, as `--features-separator`
+/// only applies to the matrix and list output.
+fn feature_args(features: &[impl AsRef<str>], default_features: bool) -> Vec<String> {
+    let mut args = Vec::new();
+    if !default_features {
+        args.push("--no-default-features".to_string());
+    }
+    args.push(format!(
+        "--features={}",
+        features.iter().map(AsRef::as_ref).join(",")
+    ));
+    args
+}
+
+/// Checks that the binary to run of `package` with `cargo fc run` is unambiguous.
+///
+/// If the package has several binaries and none was selected with `--bin`
+/// or `--example`, cargo runs its `default-run` binary.
+///
+/// # Errors
+///
+/// If the package has several binaries but no `default-run`, which would be ambiguous.
+pub fn check_run_binary(package: &cargo_metadata::Package, cargo_args: &Args) -> eyre::Result<()> {
+    if cargo_subcommand(cargo_args) != CargoSubcommand::Run
+        || cargo_args.contains("--bin")
+        || cargo_args.contains("--example")
+        || package.default_run.is_some()
+    {
+        return Ok(());
+    }
+    let bins: Vec<_> = package
+        .targets
+        .iter()
+        .filter(|target| target.is_bin())
+        .map(|target| target.name.as_str())
+        .collect();
+    if bins.len() <= 1 {
+        return Ok(());
+    }
+    eyre::bail!(
+        "package {} has several binaries ({}), pass --bin or set default-run in its manifest",
+        package.name,
+        bins.join(", ")
+    )
+}
+
+/// Feature arguments to add to the `cargo_args` given by the user.
+///
+/// Nothing is added with `--no-feature-args`, and `--no-default-features`
+/// is not repeated if the user already passed it.
+///
+/// The features passed to cargo with `--features` are added to `features`,
+/// so that cargo rejects those the package lacks instead of ignoring them.
+fn injected_feature_args(
+    cargo_args: &[String],
+    features: &[impl AsRef<str>],
+    default_features: bool,
+    options: &Options,
+) -> Vec<String> {
+    let has_arg = |arg: &str| cargo_args.iter().any(|a| a == arg);
+    if options.no_feature_args {
+        return vec![];
+    }
+    let features: Vec<&str> = features
+        .iter()
+        .map(AsRef::as_ref)
+        .chain(options.cargo_features.iter().map(String::as_str))
+        .unique()
+        .collect();
+    feature_args(&features, default_features)
+        .into_iter()
+        .filter(|arg| arg != "--no-default-features" || !has_arg(arg))
+        .collect()
+}
+
+/// Splits the cargo arguments given by the user into the arguments for cargo
+/// and the extra arguments from the `--` separator on, e.g. for test binaries.
+///
+/// The arguments shared by all feature combinations are added to the former,
+/// so that nothing is ever injected after the separator.
+fn split_cargo_args(mut cargo_args: Args, options: &Options) -> (Args, Vec<String>) {
+    let extra_args_idx = cargo_args
+        .iter()
+        .position(|arg| arg.as_str() == "--")
+        .unwrap_or(cargo_args.len());
+    let extra_args = cargo_args.split_off(extra_args_idx);
+    if cargo_subcommand(&cargo_args).accepts_build_args() {
+        cargo_args.extend([
+            "--color".to_string(),
+            options.color.cargo_color().to_string(),
+        ]);
+    }
+    (cargo_args, extra_args)
+}
+
+impl Invocation<'_> {
+    /// Assembles the cargo command for a feature combination with its `args`.
+    fn command(&self, job: &Job<'_>, args: &[String]) -> eyre::Result<process::Command> {
+        let cargo = cargo_program(
+            self.options.cargo_bin.as_deref(),
+            |key| std::env::var_os(key),
+            job.toolchain.is_some(),
+        );
+        let mut cmd = process::Command::new(&cargo);
+
+        if self.options.errors_only {
+            let (key, rustflags) = errors_only_rustflags(
+                std::env::var_os("RUSTFLAGS"),
+                std::env::var_os("CARGO_ENCODED_RUSTFLAGS"),
+            );
+            cmd.env(key, rustflags);
+        }
+
+        cmd.envs(feature_env(job.config, &job.features)?);
+        cmd.args(args).current_dir(self.workspace_root);
+        Ok(cmd)
+    }
+
+    /// Assembles the full cargo arguments for a feature combination.
+    ///
+    /// Injected arguments always precede the extra arguments after `--`.
+    fn args(&self, job: &Job<'_>) -> Vec<String> {
+        let mut args: Vec<String> = job
+            .toolchain
+            .map(|toolchain| format!("+{toolchain}"))
+            .into_iter()
+            .collect();
+        args.extend(self.cargo_args.iter().cloned());
+        let build_args = cargo_subcommand(self.cargo_args).accepts_build_args();
+        if !self.missing_arguments {
+            args.extend([
+                "--manifest-path".to_string(),
+                job.package.manifest_path.to_string(),
+            ]);
+        }
+        if !self.missing_arguments && build_args {
+            args.extend(injected_feature_args(
+                self.cargo_args,
+                &job.features,
+                job.config.include_default_features,
+                self.options,
+            ));
+        }
+        if let Some(target) = job.target.filter(|_| build_args) {
+            args.extend(["--target".to_string(), target.clone()]);
+        }
+        if self.options.message_format_json && build_args {
+            args.push("--message-format=json-diagnostic-rendered-ansi".to_string());
+        }
+        args.extend(self.extra_args.iter().cloned());
+        // clippy arguments such as lint levels follow the separator
+        let clippy_args = &job.config.clippy_args;
+        if !clippy_args.is_empty() && cargo_subcommand(self.cargo_args) == CargoSubcommand::Clippy {
+            if self.extra_args.is_empty() {
+                args.push("--".to_string());
+            }
+            args.extend(clippy_args.iter().cloned());
+        }
+        args
+    }
+}
+
+/// The cargo program to run, in order of precedence `--cargo-bin`,
+/// the `FC_CARGO` and `CARGO` environment variables, and `cargo`.
+///
+/// `CARGO` is set by cargo for its own subprocesses, but only the rustup proxy
+/// of cargo understands the +toolchain argument, so it is ignored with a `toolchain`.
+fn cargo_program(
+    cargo_bin: Option<&std::path::Path>,
+    env: impl Fn(&str) -> Option<std::ffi::OsString>,
+    toolchain: bool,
+) -> std::ffi::OsString {
+    cargo_bin
+        .map(|path| path.as_os_str().to_os_string())
+        .or_else(|| env("FC_CARGO"))
+        .or_else(|| if toolchain { None } else { env("CARGO") })
+        .unwrap_or_else(|| "cargo".into())
+}
+
+fn run_feature_set(
+    job: &Job<'_>,
+    invocation: &Invocation<'_>,
+    out: &mut impl WriteColor,
+) -> eyre::Result<Summary> {
+    let options = invocation.options;
+    let Job {
+        idx,
+        package,
+        features,
+        ..
+    } = job;
+    let idx = *idx;
+
+    let label = combination_label(&package.name, features, job.target, job.toolchain);
+    if options.teamcity {
+        writeln!(out, "{}", crate::teamcity::test_started(&label))?;
+    }
+
+    let args = invocation.args(job);
+    print_package_cmd(job, invocation.cargo_args, args.as_slice(), options, out)?;
+
+    let mut cmd = invocation.command(job, &args)?;
+    cmd.stderr(process::Stdio::piped());
+    if options.message_format_json {
+        cmd.stdout(process::Stdio::piped());
+    }
+    let started = Instant::now();
+    let deadline = options
+        .timeout
+        .map(|timeout| {
+            started
+                .checked_add(timeout)
+                .ok_or_else(|| eyre::eyre!("timeout of {timeout:?} is too long"))
+        })
+        .transpose()?;
+    let mut process = cmd.spawn()?;
+    let proc_stdout = process.stdout.take().map(|pipe| read_until(pipe, deadline));
+    let proc_stderr = process.stderr.take().map(|pipe| read_until(pipe, deadline));
+    invocation.children.insert(idx, process);
+
+    // build an output writer buffer
+    let output_buffer = Vec::<u8>::new();
+    let mut colored_output = io::Cursor::new(output_buffer);
+    let mut json_output = None;
+
+    if let Some(mut proc_stdout) = proc_stdout {
+        // cargo only reports its progress on stderr, which is drained concurrently
+        let stderr_reader = proc_stderr.map(|mut proc_stderr| {
+            std::thread::spawn(move || {
+                let mut stderr = Vec::new();
+                io::Read::read_to_end(&mut proc_stderr, &mut stderr)?;
+                proc_stderr.join()?;
+                Ok::<_, io::Error>(stderr)
+            })
+        });
+
+        let proc_reader = io::BufReader::new(&mut proc_stdout);
+        let rendered = if options.silent {
+            render_json_output(proc_reader, &mut colored_output)
+        } else {
+            let mut tee_writer = crate::tee::Writer::new(&mut colored_output, &mut *out);
+            render_json_output(proc_reader, &mut tee_writer)
+        };
+        // the readers are joined even if rendering failed
+        let stderr = stderr_reader.map(|stderr_reader| {
+            stderr_reader
+                .join()
+                .map_err(|_| eyre::eyre!("failed to read stderr of cargo"))
+        });
+        proc_stdout.join()?;
+        json_output = Some(rendered?);
+
+        if let Some(stderr) = stderr {
+            let stderr = stderr??;
+            io::Write::write_all(&mut colored_output, &stderr)?;
+            if !options.silent {
+                out.write_all(&stderr)?;
+                out.flush().ok();
+            }
+        }
+    } else if let Some(mut proc_stderr) = proc_stderr {
+        // tee write to buffer and output
+        let mut proc_reader = io::BufReader::new(&mut proc_stderr);
+        let copied = if options.silent {
+            io::copy(&mut proc_reader, &mut colored_output)
+        } else {
+            let mut tee_reader = crate::tee::Reader::new(proc_reader, &mut *out, true);
+            io::copy(&mut tee_reader, &mut colored_output)
+        };
+        proc_stderr.join()?;
+        copied?;
+    } else {
+        eprintln!("ERROR: failed to redirect stderr");
+    }
+
+    let Some(mut process) = invocation.children.remove(idx) else {
+        eyre::bail!("lost track of cargo process for package {}", package.name);
+    };
+    let timed_out = match deadline {
+        Some(deadline) if Instant::now() >= deadline => process.try_wait()?.is_none(),
+        _ => false,
+    };
+    if timed_out {
+        process.kill().ok();
+    }
+    let exit_status = process.wait()?;
+    let duration = started.elapsed();
+    let output = strip_ansi_escapes::strip(colored_output.get_ref());
+    let output = String::from_utf8_lossy(&output);
+
+    let DiagnosticCounts {
+        warnings: num_warnings,
+        errors: num_errors,
+    } = json_output.as_ref().map_or_else(
+        || DiagnosticCounts {
+            warnings: warning_counts(&output).sum::<usize>(),
+            errors: error_counts(&output).sum::<usize>(),
+        },
+        |output| DiagnosticCounts::from(output.diagnostics.as_slice()),
+    );
+    let fail = timed_out || !exit_status.success();
+    let pedantic_success =
+        !fail && within_warning_threshold(num_errors, num_warnings, options.max_warnings);
+
+    if options.fail_fast && options.silent && !pedantic_success {
+        io::copy(&mut io::Cursor::new(colored_output.into_inner()), out)?;
+        out.flush().ok();
+    }
+
+    // only build commands produce the artifacts that are shipped
+    let artifact_size = json_output
+        .as_ref()
+        .filter(|_| {
+            options.report_sizes
+                && cargo_subcommand(invocation.cargo_args) == CargoSubcommand::Build
+        })
+        .and_then(|output| primary_artifact_size(&output.artifacts, package));
+
+    let summary = Summary {
+        features: features.iter().copied().cloned().collect(),
+        num_errors,
+        num_warnings,
+        package_name: package.name.clone(),
+        toolchain: job.toolchain.cloned(),
+        target: job.target.cloned(),
+        exit_code: if timed_out {
+            Some(TIMEOUT_EXIT_CODE)
+        } else {
+            exit_status.code()
+        },
+        pedantic_success,
+        timed_out,
+        signal: if timed_out {
+            None
+        } else {
+            exit_signal(exit_status)
+        },
+        failing_subset: None,
+        cached: false,
+        cross: options.use_cross,
+        unchanged: false,
+        artifact_size,
+        duration,
+        diagnostics: json_output
+            .map(|output| output.diagnostics)
+            .unwrap_or_default(),
+    };
+    if options.teamcity {
+        for message in crate::teamcity::test_finished(&label, &summary) {
+            writeln!(out, "{message}")?;
+        }
+    }
+    Ok(summary)
+}
+
+/// Reports a feature combination that did not run to TeamCity.
+fn report_ignored(
+    job: &Job<'_>,
+    summary: Summary,
+    options: &Options,
+    out: &mut impl WriteColor,
+) -> eyre::Result<Summary> {
+    if options.teamcity {
+        let label = combination_label(&job.package.name, &job.features, job.target, job.toolchain);
+        writeln!(out, "{}", crate::teamcity::test_ignored(&label, &summary))?;
+    }
+    Ok(summary)
+}
+
+/// Environment variables of the `feature_env` config for the `features`
+/// of a feature combination.
+///
+/// Fails if two features of the combination set a variable to different values.
+pub fn feature_env(
+    config: &Config,
+    features: &[&String],
+) -> eyre::Result<std::collections::BTreeMap<String, String>> {
+    let mut env: std::collections::BTreeMap<String, (&String, String)> = Default::default();
+    for feature in features {
+        let Some(vars) = config.feature_env.get(*feature) else {
+            continue;
+        };
+        for (key, value) in vars {
+            match env.get(key) {
+                Some((other, other_value)) if other_value != value => eyre::bail!(
+                    "features {other} and {feature} set {key} to different values ({other_value:?} and {value:?})"
+                ),
+                Some(_) => {}
+                None => {
+                    env.insert(key.clone(), (feature, value.clone()));
+                }
+            }
+        }
+    }
+    Ok(env
+        .into_iter()
+        .map(|(key, (_, value))| (key, value))
+        .collect())
+}
+
+/// Composes the rustc flags that allow all warnings for `--errors-only`.
+///
+/// Returns the environment variable to set and its value. `-Awarnings` is
+/// appended to the existing flags rather than prepended, as the last lint
+/// level given for a lint takes precedence, so that a `-Dwarnings` in the
+/// existing flags cannot turn warnings back on. Since cargo ignores
+/// `RUSTFLAGS` when `CARGO_ENCODED_RUSTFLAGS` is set, the latter (whose flags
+/// are separated by `0x1f`) is extended instead if present.
+///
+/// Allowed warnings are not emitted by rustc at all, so they are neither
+/// rendered nor counted with `--message-format json` either.
+fn errors_only_rustflags(
+    rustflags: Option<std::ffi::OsString>,
+    encoded_rustflags: Option<std::ffi::OsString>,
+) -> (&'static str, std::ffi::OsString) {
+    const ALLOW_WARNINGS: &str = "-Awarnings";
+    let (key, separator, flags) = match encoded_rustflags {
+        Some(flags) => ("CARGO_ENCODED_RUSTFLAGS", "\x1f", flags),
+        None => ("RUSTFLAGS", " ", rustflags.unwrap_or_default()),
+    };
+    let mut composed = flags;
+    if !composed.is_empty() {
+        composed.push(separator);
+    }
+    composed.push(ALLOW_WARNINGS);
+    (key, composed)
+}
+
+/// Whether `features` contains all of `subset` and at least one more feature.
+#[must_use]
+pub fn is_proper_superset(features: &[&String], subset: &[String]) -> bool {
+    features.len() > subset.len() && subset.iter().all(|feature| features.contains(&feature))
+}
+
+/// The exit code of the first failed feature combination.
+///
+/// Cargo processes terminated by a signal have no exit code, so the
+/// conventional `128 + signal` is used instead. Feature combinations failing
+/// only `--max-warnings` or `--pedantic` exit with 0, so 1 is used instead.
+#[must_use]
+pub fn first_bad_exit_code(summary: &[Summary]) -> Option<i32> {
+    summary
+        .iter()
+        // skipped supersets of failures have no exit code of their own
+        .filter(|s| !s.pedantic_success && s.failing_subset.is_none())
+        .map(|s| match (s.exit_code, s.signal) {
+            (Some(0), _) => 1,
+            (Some(exit_code), _) => exit_code,
+            (None, Some(signal)) => 128 + signal,
+            (None, None) => 1,
+        })
+        .next()
+}
+
+/// The exit code of a run, selected by `--exit-with-count`.
+///
+/// Defaults to the exit code of the first failed feature combination in the
+/// order of the summary, regardless of the order in which they completed.
+#[must_use]
+pub fn exit_code(summary: &[Summary], options: &Options) -> Option<i32> {
+    if options.exit_with_count {
+        failure_count_exit_code(summary)
+    } else {
+        first_bad_exit_code(summary)
+    }
+}
+
+/// The number of failed feature combinations, not counting skipped ones.
+#[must_use]
+pub fn num_failures(summary: &[Summary]) -> usize {
+    summary
+        .iter()
+        .filter(|s| !s.pedantic_success && s.failing_subset.is_none())
+        .count()
+}
+
+/// Exit code for `--exit-with-count`.
+///
+/// This is the number of failed feature combinations, clamped to 255 as
+/// exit codes are truncated to a single byte.
+#[must_use]
+pub fn failure_count_exit_code(summary: &[Summary]) -> Option<i32> {
+    match num_failures(summary) {
+        0 => None,
+        n => Some(i32::try_from(n.min(255)).unwrap_or(255)),
+    }
+}
+
+/// The signal that terminated a process, if any.
+fn exit_signal(exit_status: process::ExitStatus) -> Option<i32> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::ExitStatusExt;
+        exit_status.signal()
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = exit_status;
+        None
+    }
+}
+
+/// Exit code recorded for feature combinations that exceeded `--timeout`.
+///
+/// This matches the exit code of the coreutils `timeout` command.
+pub const TIMEOUT_EXIT_CODE: i32 = 124;
+
+/// Exit code of runs interrupted with Ctrl-C, as for shells.
+pub const INTERRUPTED_EXIT_CODE: i32 = 130;
+
+/// A pipe of a child process, read until the deadline of `--timeout`, if any.
+enum PipeReader {
+    Pipe(Box<dyn io::Read + Send>),
+    Deadline(crate::timeout::Reader),
+}
+
+impl PipeReader {
+    /// Waits for the thread reading the pipe until the deadline, if any.
+    fn join(self) -> io::Result<()> {
+        match self {
+            Self::Pipe(_) => Ok(()),
+            Self::Deadline(reader) => reader.join(),
+        }
+    }
+}
+
+impl io::Read for PipeReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Self::Pipe(pipe) => pipe.read(buf),
+            Self::Deadline(reader) => reader.read(buf),
+        }
+    }
+}
+
+/// Bounds reading from a pipe of a child process by a deadline, if any.
+fn read_until(pipe: impl io::Read + Send + 'static, deadline: Option<Instant>) -> PipeReader {
+    match deadline {
+        Some(deadline) => PipeReader::Deadline(crate::timeout::Reader::new(pipe, deadline)),
+        None => PipeReader::Pipe(Box::new(pipe)),
+    }
+}
+
+/// Resolves the number of concurrent cargo invocations.
+///
+/// When unset or zero, the available parallelism of the host is used.
+fn num_jobs(options: &Options) -> usize {
+    match options.jobs {
+        Some(jobs) if jobs > 0 => jobs,
+        _ => std::thread::available_parallelism().map_or(1, std::num::NonZeroUsize::get),
+    }
+}
+
+/// The result of running cargo for all feature combinations.
+#[derive(Debug, Default)]
+pub struct RunReport {
+    pub summaries: Vec<Summary>,
+    /// Exit code of the first failing feature combination, if any.
+    ///
+    /// With `--exit-with-count`, this is the number of failing feature
+    /// combinations instead.
+    pub first_bad_exit_code: Option<i32>,
+}
+
+/// A failed feature combination, as recorded for `--rerun-failed`,
+/// or a planned one read with `--features-json`.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct FailedFeatureSet {
+    pub package: String,
+    pub features: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub toolchain: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub target: Option<String>,
+}
+
+impl From<&Summary> for FailedFeatureSet {
+    fn from(summary: &Summary) -> Self {
+        Self {
+            package: summary.package_name.clone(),
+            features: summary.features.clone(),
+            toolchain: summary.toolchain.clone(),
+            target: summary.target.clone(),
+        }
+    }
+}
+
+/// Reads the JSON state persisted between runs from `path`, which is the
+/// default if it does not exist yet.
+///
+/// `what` names the state in errors, e.g. `failures`.
+pub(crate) fn read_state<T: serde::de::DeserializeOwned + Default>(
+    path: &std::path::Path,
+    what: &str,
+) -> eyre::Result<T> {
+    match std::fs::read_to_string(path) {
+        Ok(json) => serde_json::from_str(&json)
+            .wrap_err_with(|| format!("failed to parse {what} in {}", path.display())),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(T::default()),
+        Err(err) => Err(err).wrap_err_with(|| format!("failed to read {}", path.display())),
+    }
+}
+
+/// Writes the JSON `state` persisted between runs to `path`, creating its directory.
+pub(crate) fn write_state<T: serde::Serialize + ?Sized>(
+    state: &T,
+    path: &std::path::Path,
+    what: &str,
+) -> eyre::Result<()> {
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)
+            .wrap_err_with(|| format!("failed to create {}", dir.display()))?;
+    }
+    let json = serde_json::to_string_pretty(state)?;
+    std::fs::write(path, json)
+        .wrap_err_with(|| format!("failed to write {what} to {}", path.display()))
+}
+
+/// Writes the failed feature combinations of a run to `path`.
+pub fn write_failures(failures: &[FailedFeatureSet], path: &std::path::Path) -> eyre::Result<()> {
+    write_state(failures, path, "failures")
+}
+
+/// The failed feature combinations of a run with the `summary`, in addition to the
+/// `recorded` failures of feature combinations that did not run, e.g. of other shards.
+#[must_use]
+pub fn merge_failures(
+    recorded: Vec<FailedFeatureSet>,
+    summary: &[Summary],
+) -> Vec<FailedFeatureSet> {
+    let ran: Vec<_> = summary.iter().map(FailedFeatureSet::from).collect();
+    recorded
+        .into_iter()
+        .filter(|failure| !ran.contains(failure))
+        .chain(
+            summary
+                .iter()
+                .filter(|s| !s.pedantic_success)
+                .map(FailedFeatureSet::from),
+        )
+        .collect()
+}
+
+/// Reads the failed feature combinations of the last run from `path`, if any were recorded.
+pub fn read_failures(path: &std::path::Path) -> eyre::Result<Option<Vec<FailedFeatureSet>>> {
+    read_state(path, "failures")
+}
+
+/// Reads a matrix printed by the matrix subcommand from `path`.
+fn read_matrix(path: &std::path::Path) -> eyre::Result<serde_json::Value> {
+    let json = std::fs::read_to_string(path)
+        .wrap_err_with(|| format!("failed to read {}", path.display()))?;
+    serde_json::from_str(&json)
+        .wrap_err_with(|| format!("failed to parse matrix in {}", path.display()))
+}
+
+/// Reads the feature combinations of a matrix printed by the matrix subcommand
+/// from `path`, see [`matrix_feature_sets`].
+pub fn read_feature_matrix(
+    path: &std::path::Path,
+    packages: &[(&cargo_metadata::Package, Config)],
+) -> eyre::Result<Vec<FailedFeatureSet>> {
+    matrix_feature_sets(&read_matrix(path)?, packages)
+        .wrap_err_with(|| format!("invalid matrix in {}", path.display()))
+}
+
+/// Type of a feature combination in a matrix, with its package, features, target and toolchain.
+type MatrixCombination<'a> = (&'a str, &'a str, Option<&'a str>, Option<&'a str>);
+
+/// The package, features, target and toolchain of each feature combination in
+/// the rows of a matrix printed by the matrix subcommand, given as a plain array
+/// or in the `{"include": [...]}` form.
+///
+/// Collapsed and batched rows are expanded.
+fn matrix_combinations(matrix: &serde_json::Value) -> eyre::Result<Vec<MatrixCombination<'_>>> {
+    use serde_json::Value;
+
+    let rows = matrix
+        .get("include")
+        .unwrap_or(matrix)
+        .as_array()
+        .ok_or_else(|| eyre::eyre!("expected an array of rows or an object with include"))?;
+    let mut combinations = Vec::new();
+    for row in rows {
+        let names = match &row["name"] {
+            Value::String(name) => vec![name.as_str()],
+            Value::Array(names) => names.iter().filter_map(Value::as_str).collect(),
+            _ => eyre::bail!("row without a package name: {row}"),
+        };
+        let features = match &row["features"] {
+            Value::String(features) => vec![features.as_str()],
+            Value::Array(features) => features.iter().filter_map(Value::as_str).collect(),
+            _ => eyre::bail!("row without features: {row}"),
+        };
+        let target = row["target"].as_str();
+        let toolchain = row["toolchain"].as_str();
+        for name in names {
+            combinations.extend(
+                features
+                    .iter()
+                    .map(|features| (name, *features, target, toolchain)),
+            );
+        }
+    }
+    Ok(combinations)
+}
+
+/// The feature combinations of the rows of a matrix printed by the matrix
+/// subcommand, see [`matrix_combinations`].
+///
+/// The features of each row are split with the config of its package.
+/// Rows of other packages are left out.
+pub fn matrix_feature_sets(
+    matrix: &serde_json::Value,
+    packages: &[(&cargo_metadata::Package, Config)],
+) -> eyre::Result<Vec<FailedFeatureSet>> {
+    let mut feature_sets = Vec::new();
+    for (name, features, target, toolchain) in matrix_combinations(matrix)? {
+        let Some((_, config)) = packages.iter().find(|(package, _)| package.name == name) else {
+            continue;
+        };
+        let features =
+            if features.is_empty() || config.empty_features_name.as_deref() == Some(features) {
+                vec![]
+            } else {
+                features
+                    .split(config.features_separator())
+                    .map(str::to_string)
+                    .collect()
+            };
+        feature_sets.push(FailedFeatureSet {
+            package: name.to_string(),
+            features,
+            toolchain: toolchain.map(str::to_string),
+            target: target.map(str::to_string),
+        });
+    }
+    Ok(feature_sets)
+}
+
+/// A feature combination added to or removed from a matrix, see [`diff_matrices`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MatrixChange {
+    Added(String),
+    Removed(String),
+}
+
+impl MatrixChange {
+    /// The label of the feature combination, e.g. `package [features]`.
+    #[must_use]
+    pub fn label(&self) -> &str {
+        match self {
+            Self::Added(label) | Self::Removed(label) => label,
+        }
+    }
+}
+
+/// The feature combinations added to and removed from the `old` matrix in
+/// the `new` matrix, labeled like `package [features] (target)` and sorted by label.
+///
+/// # Errors
+///
+/// If either matrix is not a matrix printed by the matrix subcommand.
+pub fn diff_matrices(
+    old: &serde_json::Value,
+    new: &serde_json::Value,
+) -> eyre::Result<Vec<MatrixChange>> {
+    let labels = |matrix| -> eyre::Result<Vec<String>> {
+        Ok(matrix_combinations(matrix)?
+            .into_iter()
+            .map(|(name, features, target, _)| match target {
+                Some(target) => format!("{name} [{features}] ({target})"),
+                None => format!("{name} [{features}]"),
+            })
+            .collect())
+    };
+    let (old, new) = (labels(old)?, labels(new)?);
+    let removed = old
+        .iter()
+        .filter(|label| !new.contains(label))
+        .map(|label| MatrixChange::Removed(label.clone()));
+    let added = new
+        .iter()
+        .filter(|label| !old.contains(label))
+        .map(|label| MatrixChange::Added(label.clone()));
+    Ok(removed
+        .chain(added)
+        .sorted_by(|a, b| a.label().cmp(b.label()))
+        .collect())
+}
+
+/// Prints the changes of a matrix, added feature combinations in green
+/// and removed ones in red.
+pub fn print_matrix_diff(changes: &[MatrixChange], out: &mut impl WriteColor) -> io::Result<()> {
+    for change in changes {
+        let (sign, color): (_, &ColorSpec) = match change {
+            MatrixChange::Added(_) => ("+", &GREEN),
+            MatrixChange::Removed(_) => ("-", &RED),
+        };
+        out.set_color(color).ok();
+        write!(out, "{sign} {}", change.label())?;
+        out.reset().ok();
+        writeln!(out)?;
+    }
+    let num_added = changes
+        .iter()
+        .filter(|change| matches!(change, MatrixChange::Added(_)))
+        .count();
+    writeln!(
+        out,
+        "{num_added} added, {} removed",
+        changes.len() - num_added
+    )
+}
+
+/// Labels a feature combination, e.g. `package [a,b] (target) +toolchain`,
+/// identifying it in the recorded durations and TeamCity service messages.
+fn combination_label(
+    package: &str,
+    features: &[impl AsRef<str>],
+    target: Option<&String>,
+    toolchain: Option<&String>,
+) -> String {
+    let features = features.iter().map(AsRef::as_ref).join(",");
+    let target = target.map_or(String::new(), |target| format!(" ({target})"));
+    let toolchain = toolchain.map_or(String::new(), |toolchain| format!(" +{toolchain}"));
+    format!("{package} [{features}]{target}{toolchain}")
+}
+
+/// Reads the durations in seconds of the feature combinations of earlier runs
+/// from `path`, which are empty if none were recorded.
+pub fn read_durations(path: &std::path::Path) -> eyre::Result<BTreeMap<String, f64>> {
+    read_state(path, "durations")
+}
+
+/// Reads the digests of the packages that passed in earlier runs from `path`,
+/// by package name, which are empty if none were recorded.
+pub fn read_package_digests(path: &std::path::Path) -> eyre::Result<BTreeMap<String, String>> {
+    read_state(path, "state")
+}
+
+/// Writes the digests of the packages that passed to `path`.
+pub fn write_package_digests(
+    digests: &BTreeMap<String, String>,
+    path: &std::path::Path,
+) -> eyre::Result<()> {
+    write_state(digests, path, "state")
+}
+
+/// Records the durations of the feature combinations that ran in `summary`
+/// in `path`, keeping the durations of the other feature combinations.
+pub fn write_durations(summary: &[Summary], path: &std::path::Path) -> eyre::Result<()> {
+    let mut durations = read_durations(path)?;
+    // cached, unchanged and skipped feature combinations did not run
+    for s in summary
+        .iter()
+        .filter(|s| !s.cached && !s.unchanged && s.failing_subset.is_none())
+    {
+        let key = combination_label(
+            &s.package_name,
+            &s.features,
+            s.target.as_ref(),
+            s.toolchain.as_ref(),
+        );
+        durations.insert(key, s.duration.as_secs_f64());
+    }
+    write_state(&durations, path, "durations")
+}
+
+/// Assigns work items with the given `durations` to `total` shards, such that
+/// the shards take about the same time, returning the shard of each item.
+///
+/// The longest items are assigned first, each to the shard with the least
+/// total duration so far, or else the fewest items.
+#[must_use]
+pub fn balance_shards(durations: &[f64], total: usize) -> Vec<usize> {
+    let mut loads: Vec<f64> = vec![0.0; total.max(1)];
+    let mut counts = vec![0; loads.len()];
+    let mut shards = vec![0; durations.len()];
+    let longest_first =
+        (0..durations.len()).sorted_by(|a, b| durations[*b].total_cmp(&durations[*a]));
+    for idx in longest_first {
+        let shard = (0..loads.len())
+            .min_by(|a, b| {
+                loads[*a]
+                    .total_cmp(&loads[*b])
+                    .then(counts[*a].cmp(&counts[*b]))
+            })
+            .unwrap_or_default();
+        loads[shard] += durations[idx];
+        counts[shard] += 1;
+        shards[idx] = shard;
+    }
+    shards
+}
+
+/// A feature combination to run, by package, features, target and toolchain.
+type WorkItem<'a> = (
+    &'a str,
+    Vec<&'a str>,
+    Option<&'a String>,
+    Option<&'a String>,
+);
+
+/// The shard of each of the work `items` with `--shard`, or `None` without it.
+///
+/// Shards agree on the work items regardless of the order they are run or
+/// printed in, by ordering them by package name, then by their sorted features,
+/// target and toolchain. With the `durations` recorded by earlier runs for
+/// `--balance-shards`, the shards take about the same time, and otherwise
+/// every `TOTAL`-th work item belongs to a shard.
+fn assign_shards<'a>(
+    shard: Option<Shard>,
+    durations: &BTreeMap<String, f64>,
+    items: impl Iterator<Item = WorkItem<'a>>,
+) -> Option<Vec<usize>> {
+    let shard = shard?;
+    let items: Vec<_> = items.collect();
+    let order: Vec<usize> = (0..items.len())
+        .sorted_by_cached_key(|idx| {
+            let (package, features, target, toolchain) = &items[*idx];
+            (
+                *package,
+                features.iter().sorted().join(","),
+                *target,
+                *toolchain,
+            )
+        })
+        .collect();
+    let ordered_shards = if durations.is_empty() {
+        (0..order.len()).map(|pos| pos % shard.total).collect()
+    } else {
+        #[allow(clippy::cast_precision_loss)]
+        let mean = durations.values().sum::<f64>() / durations.len() as f64;
+        let durations: Vec<_> = order
+            .iter()
+            .map(|idx| {
+                let (package, features, target, toolchain) = &items[*idx];
+                let label = combination_label(package, features, *target, *toolchain);
+                // feature combinations that did not run yet are assumed to take the mean
+                durations.get(&label).copied().unwrap_or(mean)
+            })
+            .collect();
+        balance_shards(&durations, shard.total)
+    };
+    let mut shards = vec![0; items.len()];
+    for (idx, shard) in order.into_iter().zip(ordered_shards) {
+        shards[idx] = shard;
+    }
+    Some(shards)
+}
+
+/// Whether the work item `idx` belongs to `shard`, see [`assign_shards`].
+fn in_shard(idx: usize, shard: Option<Shard>, shards: Option<&[usize]>) -> bool {
+    match (shard, shards) {
+        (Some(shard), Some(shards)) => shards[idx] == shard.index,
+        _ => true,
+    }
+}
+
+/// Asks whether to run `total` cargo invocations, reading the answer from `input`.
+///
+/// Without an `interactive` terminal, e.g. in CI, the total is only printed.
+fn confirm_run(total: usize, mut input: impl io::BufRead, interactive: bool) -> io::Result<bool> {
+    if !interactive {
+        eprintln!("running {total} cargo invocations");
+        return Ok(true);
+    }
+    eprint!("Run {total} cargo invocations? [y/N] ");
+    let mut answer = String::new();
+    input.read_line(&mut answer)?;
+    Ok(matches!(answer.trim().to_lowercase().as_str(), "y" | "yes"))
+}
+
+/// Checks that the features of the feature combinations to rerun exist in their package.
+///
+/// # Errors
+///
+/// If a feature combination names a feature its package does not have,
+/// e.g. one that was removed since the last run.
+fn check_feature_sets(
+    feature_sets: &[FailedFeatureSet],
+    packages: &[(&cargo_metadata::Package, Config)],
+) -> eyre::Result<()> {
+    for set in feature_sets {
+        let Some((package, _)) = packages.iter().find(|(p, _)| p.name == set.package) else {
+            continue;
+        };
+        if let Some(feature) = set
+            .features
+            .iter()
+            .find(|ft| !package.features.contains_key(*ft))
+        {
+            eyre::bail!(
+                "package {} has no feature {feature:?} to run [{}]",
+                package.name,
+                set.features.join(",")
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Type of the feature combinations to run, with their package, config, toolchain and target.
+type FeatureSetRun<'a> = (
+    &'a cargo_metadata::Package,
+    &'a Config,
+    Vec<&'a String>,
+    Option<&'a String>,
+    Option<&'a String>,
+);
+
+/// The numbered feature combinations of all packages to run, for every
+/// target and toolchain, before sharding.
+///
+/// With `rerun`, only these feature combinations are run.
+fn feature_sets_to_run<'a>(
+    packages: &'a [(&'a cargo_metadata::Package, Config)],
+    rerun: Option<&'a Vec<FailedFeatureSet>>,
+    options: &'a Options,
+) -> impl Iterator<Item = (usize, FeatureSetRun<'a>)> + 'a {
+    packages
+        .iter()
+        .flat_map(move |(package, config)| {
+            let combinations = match rerun {
+                // failures are rerun for the toolchain and target they failed for
+                Some(failures) => itertools::Either::Left(
+                    failures
+                        .iter()
+                        .filter(|failure| failure.package == package.name)
+                        .filter_map(|failure| {
+                            // unknown features were rejected by check_feature_sets
+                            let features = failure
+                                .features
+                                .iter()
+                                .map(|ft| package.features.get_key_value(ft).map(|(ft, _)| ft))
+                                .collect::<Option<Vec<_>>>()?;
+                            Some((
+                                (features, failure.target.as_ref()),
+                                failure.toolchain.as_ref(),
+                            ))
+                        })
+                        .sorted_by_key(|((features, _), _)| features.len()),
+                ),
+                None => itertools::Either::Right(cross_product(
+                    cross_product(package.feature_combinations_iter(config), &options.targets),
+                    &options.toolchains,
+                )),
+            };
+            combinations.map(move |((features, target), toolchain)| {
+                (*package, config, features, toolchain, target)
+            })
+        })
+        .filter(|(_, _, features, _, _)| {
+            options.feature_sets.as_ref().is_none_or(|sets| {
+                sets.iter()
+                    .any(|set| set.iter().eq(features.iter().copied()))
+            })
+        })
+        .enumerate()
+}
+
+/// The shard of each of the `feature_sets` to run with `--shard`, see [`assign_shards`].
+fn feature_set_shards<'a>(
+    feature_sets: impl Iterator<Item = (usize, FeatureSetRun<'a>)>,
+    durations: &BTreeMap<String, f64>,
+    options: &Options,
+) -> Option<Vec<usize>> {
+    assign_shards(
+        options.shard,
+        durations,
+        feature_sets.map(|(_, (package, _, features, toolchain, target))| {
+            let features = features.into_iter().map(String::as_str).collect();
+            (package.name.as_str(), features, target, toolchain)
+        }),
+    )
+}
+
+/// Warns about failing to persist state in the state directory, which only
+/// serves later runs and does not fail the current one.
+fn warn_on_state_error(result: eyre::Result<()>) {
+    if let Err(err) = result {
+        eprintln!("warning: {err:#}");
+    }
+}
+
+/// Runs cargo for all feature combinations of the packages of the workspace
+/// described by `metadata`.
+///
+/// State persisted across runs is kept in `state_dir`. The failed feature
+/// combinations are recorded there, from which they are loaded again when
+/// running with `--rerun-failed`, as are the passed ones for `--cache`,
+/// the durations for `--balance-shards` and the digests of the packages
+/// that passed for `--only-changed`.
+pub fn run_cargo_command(
+    packages: &[(&cargo_metadata::Package, Config)],
+    cargo_args: Args,
+    options: &Options,
+    metadata: &cargo_metadata::Metadata,
+    state_dir: Option<&std::path::Path>,
+) -> eyre::Result<RunReport> {
+    let start = Instant::now();
+    let workspace_root = metadata.workspace_root.as_std_path();
+    let failures_cache = state_dir.map(|dir| dir.join("last-failures.json"));
+    let durations_path = state_dir
+        .filter(|_| !options.dry_run)
+        .map(|dir| dir.join("durations.json"));
+    let cache_path = state_dir
+        .filter(|_| options.cache && !options.dry_run)
+        .map(|dir| dir.join("cache.json"));
+    let state_path = state_dir
+        .filter(|_| options.only_changed && !options.dry_run)
+        .map(|dir| dir.join("state.json"));
+
+    let rerun = if options.rerun_failed {
+        let Some(ref path) = failures_cache else {
+            eyre::bail!("--rerun-failed requires a cache of failed feature combinations");
+        };
+        let Some(failures) = read_failures(path)? else {
+            eprintln!(
+                "no failed feature combinations recorded in {}, run without --rerun-failed first",
+                path.display()
+            );
+            return Ok(RunReport::default());
+        };
+        Some(failures)
+    } else if let Some(ref path) = options.features_json {
+        Some(read_feature_matrix(path, packages)?)
+    } else {
+        None
+    };
+    if let Some(ref feature_sets) = rerun {
+        check_feature_sets(feature_sets, packages)?;
+    }
+    // let packages = md.workspace_packages();
+
+    // packages that passed before are not run again if nothing changed since,
+    // which is digested up front as cargo may change the sources, e.g. with cargo fix
+    let passed_digests = match state_path {
+        Some(ref path) => read_package_digests(path)?,
+        None => BTreeMap::new(),
+    };
+    let digests: HashMap<&str, String> = match state_path {
+        Some(_) => {
+            let inputs = CacheInputs::new(packages, &metadata.packages, options)?;
+            packages
+                .iter()
+                .map(|(package, config)| {
+                    let key = inputs.package_key(package, config, &cargo_args, options);
+                    (package.name.as_str(), key)
+                })
+                .collect()
+        }
+        None => HashMap::new(),
+    };
+
+    let missing_arguments = cargo_args.is_empty();
+    let (cargo_args, extra_args) = split_cargo_args(cargo_args, options);
+    // fail before running anything if the binary to run is ambiguous
+    for (package, _) in packages {
+        check_run_binary(package, &cargo_args)?;
+    }
+
+    let mut stdout = StandardStream::stdout(options.color.color_choice());
+
+    // feature combinations are generated lazily while running, in order of increasing size
+    let rerun = rerun.as_ref();
+    let all_feature_sets = || feature_sets_to_run(packages, rerun, options);
+
+    // without recorded durations, every TOTAL-th feature combination belongs to a shard
+    let durations = match durations_path {
+        Some(ref path) if options.balance_shards => read_durations(path)?,
+        _ => BTreeMap::new(),
+    };
+    let shards = feature_set_shards(all_feature_sets(), &durations, options);
+    let feature_sets =
+        || all_feature_sets().filter(|(idx, _)| in_shard(*idx, options.shard, shards.as_deref()));
+
+    // the total is only known by generating all feature combinations up front
+    let total = (!options.no_progress && !options.dry_run).then(|| feature_sets().count());
+    if options.confirm {
+        let total = total.unwrap_or_else(|| feature_sets().count());
+        use std::io::IsTerminal;
+        let interactive = io::stdin().is_terminal();
+        if !confirm_run(total, io::stdin().lock(), interactive)? {
+            eyre::bail!("aborted, not running {total} cargo invocations");
+        }
+    }
+    // without feature combination headers, the progress is shown on a single line
+    let progress_line = total.is_some() && options.quiet >= 2 && {
+        use std::io::IsTerminal;
+        io::stderr().is_terminal()
+    };
+
+    let invocation = Invocation {
+        cargo_args: &cargo_args,
+        extra_args: &extra_args,
+        workspace_root,
+        missing_arguments,
+        options,
+        children: Children::default(),
+    };
+    // smaller feature sets run first, so that with --skip-failing-supersets
+    // their failures rule out their supersets
+    let queue = Mutex::new(feature_sets());
+    let num_started = AtomicUsize::new(0);
+    let failures = Mutex::new(Vec::<FailedFeatureSet>::new());
+    let results = Mutex::new(Vec::<(usize, Summary)>::new());
+    let errors = Mutex::new(Vec::<eyre::Report>::new());
+    let cancelled = AtomicBool::new(false);
+
+    let next = || {
+        if cancelled.load(Ordering::SeqCst) || crate::interrupt::interrupted() {
+            return None;
+        }
+        let mut queue = queue.lock().ok()?;
+        let (idx, (package, config, features, toolchain, target)) = queue.next()?;
+        let current = num_started.fetch_add(1, Ordering::SeqCst) + 1;
+        let progress = total.map(|total| Progress { current, total });
+        if let (true, Some(progress)) = (progress_line, progress) {
+            eprint!("\r{progress}");
+        }
+        Some(Job {
+            idx,
+            package,
+            config,
+            features,
+            toolchain,
+            target,
+            progress,
+        })
+    };
+
+    // records the result of a job and returns whether its output should be shown
+    let complete = |idx: usize, result: eyre::Result<Summary>| -> bool {
+        if crate::interrupt::interrupted() {
+            // jobs interrupted by Ctrl-C did not complete
+            invocation.children.kill_all();
+            return false;
+        }
+        if cancelled.load(Ordering::SeqCst) {
+            // results of jobs cancelled by an earlier failure are discarded
+            return false;
+        }
+        match result {
+            Ok(summary) => {
+                if options.skip_failing_supersets
+                    && !summary.pedantic_success
+                    && summary.failing_subset.is_none()
+                {
+                    if let Ok(mut failures) = failures.lock() {
+                        failures.push(FailedFeatureSet::from(&summary));
+                    }
+                }
+                if options.fail_fast && !summary.pedantic_success {
+                    cancelled.store(true, Ordering::SeqCst);
+                    invocation.children.kill_all();
+                }
+                if let Ok(mut results) = results.lock() {
+                    results.push((idx, summary));
+                }
+            }
+            Err(err) => {
+                cancelled.store(true, Ordering::SeqCst);
+                invocation.children.kill_all();
+                if let Ok(mut errors) = errors.lock() {
+                    errors.push(err);
+                }
+            }
+        }
+        true
+    };
+
+    if options.dry_run {
+        while let Some(job) = next() {
+            let args = invocation.args(&job);
+            print_package_cmd(&job, &cargo_args, args.as_slice(), options, &mut stdout)?;
+        }
+        return Ok(RunReport::default());
+    }
+
+    // skips the feature set if it is a superset of a known failing feature set
+    let skip = |job: &Job<'_>| -> Option<Summary> {
+        if !options.skip_failing_supersets {
+            return None;
+        }
+        let failures = failures.lock().ok()?;
+        let failing_subset = failures
+            .iter()
+            .filter(|failure| {
+                failure.package == job.package.name
+                    && failure.toolchain.as_ref() == job.toolchain
+                    && failure.target.as_ref() == job.target
+            })
+            .map(|failure| &failure.features)
+            .filter(|failing| is_proper_superset(&job.features, failing))
+            .min_by_key(|failing| failing.len())?;
+        Some(Summary {
+            failing_subset: Some(failing_subset.clone()),
+            ..Summary::not_run(job, options.use_cross)
+        })
+    };
+
+    // feature combinations that passed before are not run again if nothing changed
+    let cache = match cache_path {
+        Some(ref path) => Some(Mutex::new(crate::cache::Cache::read(path)?)),
+        None => None,
+    };
+    let cache_inputs = cache
+        .as_ref()
+        .map(|_| CacheInputs::new(packages, &metadata.packages, options))
+        .transpose()?;
+    let cache_key = |job: &Job<'_>| -> Option<String> {
+        let inputs = cache_inputs.as_ref()?;
+        Some(inputs.key(job, &invocation.args(job)))
+    };
+    let cached = |job: &Job<'_>, key: Option<&String>| -> Option<Summary> {
+        let cache = cache.as_ref()?.lock().ok()?;
+        if !cache.passed(key?) {
+            return None;
+        }
+        Some(Summary {
+            exit_code: Some(0),
+            pedantic_success: true,
+            cached: true,
+            ..Summary::not_run(job, options.use_cross)
+        })
+    };
+    let unchanged = |job: &Job<'_>| -> Option<Summary> {
+        let digest = digests.get(job.package.name.as_str())?;
+        if passed_digests.get(&job.package.name) != Some(digest) {
+            return None;
+        }
+        Some(Summary {
+            exit_code: Some(0),
+            pedantic_success: true,
+            unchanged: true,
+            ..Summary::not_run(job, options.use_cross)
+        })
+    };
+
+    let record = |key: Option<String>, result: &eyre::Result<Summary>| {
+        let (Some(cache), Some(key), Ok(summary)) = (&cache, key, result) else {
+            return;
+        };
+        // skipped and unchanged feature combinations did not run
+        if summary.failing_subset.is_none() && !summary.unchanged {
+            if let Ok(mut cache) = cache.lock() {
+                cache.record(key, summary.pedantic_success);
+            }
+        }
+    };
+
+    let jobs = if cargo_subcommand(&cargo_args).modifies_sources() {
+        // concurrent runs would race on the same files
+        1
+    } else {
+        // no more workers than feature combinations
+        let len = total.unwrap_or_else(|| feature_sets().count());
+        num_jobs(options).min(len).max(1)
+    };
+    if jobs == 1 {
+        // stream cargo output live when running sequentially
+        while let Some(job) = next() {
+            let key = cache_key(&job);
+            let result = match skip(&job)
+                .or_else(|| unchanged(&job))
+                .or_else(|| cached(&job, key.as_ref()))
+            {
+                Some(skipped) => report_ignored(&job, skipped, options, &mut stdout),
+                None => run_feature_set(&job, &invocation, &mut stdout),
+            };
+            record(key, &result);
+            complete(job.idx, result);
+        }
+    } else {
+        // buffer the output of each job and flush it atomically on completion,
+        // so that the output of concurrent cargo processes does not interleave
+        let writer = BufferWriter::stdout(options.color.color_choice());
+        std::thread::scope(|scope| {
+            for _ in 0..jobs {
+                scope.spawn(|| {
+                    while let Some(job) = next() {
+                        let mut buffer = writer.buffer();
+                        let key = cache_key(&job);
+                        let result = match skip(&job)
+                            .or_else(|| unchanged(&job))
+                            .or_else(|| cached(&job, key.as_ref()))
+                        {
+                            Some(skipped) => report_ignored(&job, skipped, options, &mut buffer),
+                            None => run_feature_set(&job, &invocation, &mut buffer),
+                        };
+                        record(key, &result);
+                        if complete(job.idx, result) {
+                            writer.print(&buffer).ok();
+                        }
+                    }
+                });
+            }
+        });
+    }
+
+    if progress_line && num_started.load(Ordering::SeqCst) > 0 {
+        eprintln!();
+    }
+
+    if let Some(err) = errors.into_inner().ok().and_then(|mut errors| errors.pop()) {
+        return Err(err);
+    }
+
+    // the state of an interrupted run is incomplete and not persisted,
+    // the feature combinations completed before Ctrl-C are only summarized
+    let interrupted = crate::interrupt::interrupted();
+    if let (Some(cache), Some(path), false) = (cache, cache_path, interrupted) {
+        if let Ok(cache) = cache.into_inner() {
+            warn_on_state_error(cache.write(&path));
+        }
+    }
+
+    let mut results = results.into_inner().unwrap_or_default();
+    // summarize in lexicographic order, regardless of the configured order
+    results.sort_by_cached_key(|(_, s)| {
+        (
+            s.package_name.clone(),
+            s.features.join(","),
+            s.toolchain.clone(),
+            s.target.clone(),
+        )
+    });
+    let mut summary: Vec<Summary> = results.into_iter().map(|(_, summary)| summary).collect();
+
+    let mut first_bad_exit_code = exit_code(&summary, options);
+    if cancelled.load(Ordering::SeqCst) {
+        // failing fast always results in a bad exit code
+        first_bad_exit_code.get_or_insert(1);
+    }
+    if interrupted {
+        eprintln!(
+            "interrupted, summarizing the {} completed feature combination{}",
+            summary.len(),
+            if summary.len() == 1 { "" } else { "s" }
+        );
+        first_bad_exit_code = Some(INTERRUPTED_EXIT_CODE);
+    }
+
+    if options.sort_by_time {
+        // surface the slowest feature combinations first
+        summary.sort_by_key(|s| std::cmp::Reverse(s.duration));
+    }
+
+    if let Some(ref path) = state_path.filter(|_| !interrupted) {
+        // only a run of all feature combinations of a package shows that it passes
+        let complete = rerun.is_none()
+            && options.shard.is_none()
+            && options.feature_sets.is_none()
+            && !cancelled.load(Ordering::SeqCst);
+        let mut passed_digests = passed_digests;
+        for (package, digest) in digests {
+            let mut summaries = summary
+                .iter()
+                .filter(|s| s.package_name == package)
+                .peekable();
+            if summaries.peek().is_none() {
+                continue;
+            }
+            if complete && summaries.all(|s| s.pedantic_success) {
+                passed_digests.insert(package.to_string(), digest);
+            } else {
+                passed_digests.remove(package);
+            }
+        }
+        warn_on_state_error(write_package_digests(&passed_digests, path));
+    }
+    print_summary(
+        &summary,
+        &mut stdout,
+        start.elapsed(),
+        options.group_by_package,
+    )?;
+
+    // the shards of a run must be balanced by the same durations
+    if let Some(ref path) = durations_path.filter(|_| !options.balance_shards && !interrupted) {
+        warn_on_state_error(write_durations(&summary, path));
+    }
+    if let Some(ref path) = failures_cache.filter(|_| !interrupted) {
+        // the other shards record their failures in the same file, which is
+        // not locked, so shards sharing a target directory must not run concurrently
+        let recorded = match read_failures(path).map(Option::unwrap_or_default) {
+            Ok(recorded) if options.shard.is_some() => recorded,
+            Ok(_) => vec![],
+            Err(err) => {
+                warn_on_state_error(Err(err));
+                vec![]
+            }
+        };
+        warn_on_state_error(write_failures(&merge_failures(recorded, &summary), path));
+    }
+    if let Some(ref path) = options.summary_json {
+        write_summary_json(&summary, path)?;
+    }
+    if let Some(ref path) = options.junit {
+        write_junit_xml(&summary, path)?;
+    }
+    if let Some(ref path) = options.sarif {
+        crate::sarif::write(&summary, path)?;
+    }
+    Ok(RunReport {
+        summaries: summary,
+        first_bad_exit_code,
+    })
+}
+
+fn print_help() {
+    let help = r#"Run cargo commands for all feature combinations
+
+USAGE:
+    cargo [+toolchain] [SUBCOMMAND] [SUBCOMMAND_OPTIONS]
+    cargo [+toolchain] [OPTIONS] [CARGO_OPTIONS] [CARGO_SUBCOMMAND]
+
+SUBCOMMAND:
+    matrix                  Print JSON feature combination matrix to stdout
+        --pretty            Print pretty JSON
+        --output-format <FORMAT>
+                            Output format of the matrix (json, github or
+                            ndjson). The github format wraps the matrix as
+                            {"include": [...]} and writes it to the
+                            matrix output in $GITHUB_OUTPUT if set.
+                            The ndjson format prints one entry per line
+        --with-command      Add the cargo arguments selecting the features
+                            of each combination as "command"
+        --name-template <TEMPLATE>
+                            Add a "display_name" rendered from TEMPLATE, e.g.
+                            "{package} [{features}]", overriding the
+                            name_template config
+        --batch <N>         Batch up to N feature combinations of a package
+                            per entry, whose "features" is then an array.
+                            Check a batch with --feature-sets
+        --collapse-identical
+                            Merge the entries of packages with identical
+                            feature combinations into single entries, whose
+                            "name" is then an array of the package names
+        --diff-against <PATH>
+                            Print the feature combinations added to (green)
+                            and removed from (red) the matrix saved in PATH
+                            instead of the matrix
+        --emit-feature-files <DIR>
+                            Write the features of each feature combination to
+                            DIR/<package>/<index>.features instead of the
+                            matrix, e.g. to xargs over
+    count                   Print the number of feature combinations
+        --per-package       Print the number of feature combinations
+                            of each package
+
+OPTIONS:
+    --help                  Print help information
+    --list                  Print the feature combinations of each package
+                            without running cargo, and their total to stderr.
+                            With --silent, the package names are left out
+    --print-config          Print the workspace config and the resolved config
+                            of each package as JSON, after merging the
+                            workspace config, --fc-config and command line options
+    --order <ORDER>         Order of the feature combinations of each package
+                            in the matrix and list: lexicographic (default)
+                            or size-then-lex, overriding the order config.
+                            Runs always start with the smallest combinations
+                            and are summarized in lexicographic order
+    --explain               Explain on stderr which rule keeps or drops each
+                            candidate feature combination, with --list or
+                            the matrix subcommand
+    -p, --package <SPEC>    Only check the package matching SPEC, given as a
+                            name, name@version, package id or path. Names
+                            may be glob patterns such as api-*
+    --stdin-packages        Read packages from stdin, one per line, and check
+                            them like packages given with --package
+    --silent                Hide cargo output and only show summary
+    -q, --quiet             Hide cargo output like --silent. Pass twice to also
+                            hide the feature combination headers. Passed to
+                            cargo when given after the subcommand
+    -v, --verbose           Print the cargo command of each feature combination.
+                            Pass twice to also print the feature graph and the
+                            number of feature combinations of each package.
+                            Passed to cargo when given after the subcommand
+    --fail-fast             Fail fast on the first bad feature combination
+    --keep-going, --fail-at-end
+                            Check all feature combinations despite failures
+                            and exit with the first bad exit code (default)
+    --exit-with-count       Exit with the number of failed feature combinations
+                            (at most 255) instead of the first bad exit code
+    --cache                 Skip feature combinations that passed before, if
+                            their sources, toolchain and arguments did not
+                            change since, as recorded in target/fc
+    --only-changed          Skip packages that passed before, if their
+                            sources, feature combinations, toolchain and
+                            arguments did not change since, as recorded in
+                            target/fc. Coarser but cheaper than --cache
+    --rerun-failed          Only run the feature combinations that failed in
+                            the last run, as recorded in target/fc. Sharded
+                            runs record the failures of all shards, unless
+                            they share the target directory concurrently
+    --features-json <PATH>  Only run the feature combinations of a matrix
+                            printed by the matrix subcommand before, e.g. to
+                            run exactly the matrix planned for CI
+    --fc-jobs <N>           Number of feature combinations to check in parallel
+                            (defaults to the number of available CPUs).
+                            cargo fix and cargo fmt always run sequentially,
+                            since they modify the sources. -j and --jobs are
+                            passed to cargo
+    --errors-only           Allow all warnings, show errors only (-Awarnings,
+                            appended to RUSTFLAGS so that it takes precedence)
+    --message-format json   Count warnings and errors from the JSON diagnostics
+                            of cargo instead of its human-readable output
+    --cargo-bin <PATH>      Cargo program to run, e.g. a wrapper of cargo.
+                            Defaults to $FC_CARGO, then $CARGO, then cargo
+    --dry-run               Print the cargo command of each feature combination
+                            without running it
+    --timeout <SECONDS>     Kill cargo for a feature combination after the
+                            timeout and record it as failed
+    --sort-by-time          Sort the summary by duration, slowest first
+    --group-by-package      Group the summary by package, with a subtotal per
+                            package and a total for the workspace
+    --no-progress           Do not prefix feature combinations with the number
+                            of feature combinations checked so far
+    --confirm               Ask for confirmation of the number of cargo
+                            invocations before running, or only print it if
+                            stdin is not a terminal
+    --color <WHEN>          Coloring of cargo and cargo fc output: auto,
+                            always or never. Defaults to $CARGO_TERM_COLOR
+    --summary-json <PATH>   Write a JSON summary of all feature combinations
+    --junit <PATH>          Write a JUnit XML report with a test case per
+                            feature combination
+    --sarif <PATH>          Write the warnings and errors collected with
+                            --message-format json as a SARIF report, e.g. for
+                            GitHub code scanning
+    --teamcity              Report each feature combination as a test to
+                            TeamCity with service messages on stdout
+    --report-sizes          Show the size of the binary or library built for
+                            each feature combination in the summary, with
+                            --message-format json and cargo build
+    --skip-failing-supersets
+                            Skip feature combinations that are supersets of an
+                            already failing feature combination
+    --feature-sets <JSON>   Only check the feature combinations in a JSON array
+                            of comma-separated features, e.g. the features of
+                            a batched matrix entry: '["a", "a,b"]'
+    --features-separator <SEPARATOR>
+                            Separator of the features in the matrix, the list
+                            and --feature-sets, e.g. " " (defaults to ","). The
+                            --features passed to cargo are always separated
+                            by ","
+    --shard <INDEX/TOTAL>   Only run every TOTAL-th feature combination starting
+                            at INDEX, ordered by package name and features,
+                            e.g. to split the work across CI nodes. Also
+                            applies to the matrix subcommand
+    --balance-shards        With --shard, split the feature combinations into
+                            shards of about the same duration, as recorded by
+                            earlier runs without --balance-shards in
+                            target/fc, instead of by index. Also applies to
+                            the matrix subcommand
+    --pedantic              Treat warnings like errors in summary and 
+                            when using --fail-fast, like --max-warnings 0
+    --max-warnings <N>      Treat more than N warnings like errors in summary
+                            and when using --fail-fast
+    --pairwise              Only check a small set of feature combinations
+                            that covers every pair of features, instead of
+                            all combinations
+    --dedupe-implied        Drop feature combinations that enable the same
+                            features as a smaller combination, e.g. a,b if b
+                            enables a, in addition to the dedupe_implied config
+    --all-features          Only check a single combination of all features
+                            that are not excluded, instead of all combinations
+    --no-features           Only check a single combination without features,
+                            apart from the included features
+    --target <TRIPLE>       Run every feature combination for TRIPLE. Can be
+                            given multiple times. Also adds a target field
+                            to the matrix subcommand
+    --use-cross             Cross-compile for the --target triples with cross
+                            instead of cargo
+    --toolchain <NAME>      Run every feature combination with cargo +NAME,
+                            or add a row per toolchain to the matrix. Can be
+                            given multiple times
+    --allow-subcommand <NAME>
+                            Run the cargo subcommand NAME, e.g. udeps, which
+                            is rejected unless known or allowed. Can be given
+                            multiple times
+    --exclude-package <PACKAGE>
+                            Exclude packages matching a name or a glob pattern
+                            such as internal-* (in addition to the
+                            exclude_packages config)
+    --since <REF>           Only check packages with files changed since the
+                            git ref REF, e.g. origin/main
+    --since-include-dependents
+                            With --since, also check the packages depending on
+                            the changed packages
+    --changed-features      With --since, only check the feature combinations
+                            including a feature with changed files, such as
+                            src/<feature>.rs or the feature_paths config.
+                            Other changes select all feature combinations
+    --only-packages-with-lib-target
+                            Only check packages with a library target
+    --only-packages-with-bin-target
+                            Only check packages with a binary target
+    --exclude-feature <FEATURE>
+                            Exclude a feature from all combinations
+                            (in addition to the exclude_features config)
+    --include-feature <FEATURE>
+                            Include a feature in all combinations
+                            (in addition to the include_features config)
+    --exclude-feature-set <FEATURES>
+                            Skip combinations including all of the
+                            comma-separated FEATURES, e.g. a,b. Can be given
+                            multiple times (in addition to the
+                            skip_feature_sets config)
+    --default-features      Keep the default features enabled and only combine
+                            the features not already enabled by default
+    --package-features <PACKAGE:FEATURES>
+                            Include features in all combinations of PACKAGE
+                            only, e.g. --package-features api:std,serde
+    --features-from-file <PATH>
+                            Include features listed as +FEATURE and exclude
+                            features listed as -FEATURE in all combinations,
+                            one per line. Blank lines and # comments are
+                            ignored
+    -F, --features <FEATURES>
+                            Include features in all combinations, like
+                            --include-feature. Features of dependencies such
+                            as serde/std and features a package lacks are
+                            passed to cargo as they are
+    --no-feature-args       Do not add --no-default-features and --features to
+                            the cargo command. --no-default-features is not
+                            added twice if it is passed to cargo
+    --clippy-args <ARGS>    Pass whitespace-separated ARGS to clippy after --,
+                            e.g. "-D warnings", in addition to the clippy_args
+                            config. Other subcommands do not get them
+    --fc-config <PATH>      Overlay the config of every package with a JSON
+                            or TOML config file (see below). --config is
+                            left to cargo
+    --strict-config         Fail on unknown keys in the config of a package
+                            instead of warning about them
+    --feature-filter <REGEX>
+                            Only keep combinations of features matching REGEX
+    --feature-reject <REGEX>
+                            Drop combinations with a feature matching REGEX
+
+ENVIRONMENT:
+    FC_EXCLUDE_FEATURES     Comma-separated features to exclude, like
+                            --exclude-feature
+    FC_INCLUDE_FEATURES     Comma-separated features to include, like
+                            --include-feature
+    FC_EXCLUDE_PACKAGES     Comma-separated packages to exclude, like
+                            --exclude-package
+
+Features included or excluded on the command line take precedence over the
+environment, which takes precedence over the package config.
+
+Feature sets can be configured in your Cargo.toml configuration.
+For example:
+
+```toml
+[package.metadata.cargo-feature-combinations]
+# Exclude groupings of features that are incompatible or do not make sense
+skip_feature_sets = [ ["foo", "bar"], ]
+
+# Add feature sets to the combinations, regardless of the other rules
+include_feature_sets = [ ["foo", "bar"], ]
+
+# Only check exactly these feature sets instead of all combinations,
+# e.g. when only a few combinations are supported
+allow_feature_sets = [ ["foo"], ["foo", "bar"], ]
+
+# Drop features of exclude_features from include_feature_sets, which
+# otherwise may include excluded features
+include_sets_respect_excludes = false
+
+# Keep features the package does not have in include_feature_sets, e.g.
+# features that only exist for some targets, instead of dropping them
+include_sets_allow_unknown = false
+
+# Exclude features from the feature combination matrix
+exclude_features = ["default", "full"]
+
+# Include features in every combination of the feature combination matrix
+include_features = ["std"]
+
+# Exclude workspace packages by name or glob pattern (in the root package only)
+exclude_packages = ["internal-*"]
+
+# Only include combinations of at most this many features
+max_combination_size = 3
+
+# Leave out the combination without any features
+no_empty_feature_set = false
+
+# Fail if a package has more feature combinations than this (default 100000)
+max_configurations = 100000
+
+# Groups of features of which at most one may be enabled at a time
+mutually_exclusive = [ ["tokio", "async-std"], ]
+
+# Only check a small set of combinations that covers every pair of features
+pairwise = false
+
+# Drop combinations that enable the same features as a smaller combination,
+# e.g. ["a", "b"] if b = ["a"]
+dedupe_implied = false
+
+# Order of the combinations in the matrix and list: "lexicographic", or
+# "size-then-lex" to list smaller combinations first. Runs always start with
+# the smallest combinations and are summarized in lexicographic order
+order = "lexicographic"
+
+# Add a display_name to each row of the matrix, e.g. for the names of CI jobs.
+# Supports {package}, {features}, {feature_count} and {index}, where empty
+# feature combinations render as empty_features_name
+name_template = "{package} [{features}]"
+
+# Name of empty feature combinations in the matrix, the list and display_name,
+# e.g. for consumers that do not accept an empty string. Their features are
+# empty and their display_name is "none" if unset. The name is never passed
+# to cargo nor written to the files of --emit-feature-files
+empty_features_name = "none"
+
+# Keep the default features enabled instead of passing --no-default-features.
+# Features enabled by default are then left out of the combinations, so
+# exclude_features cannot disable them.
+include_default_features = false
+
+# Skip the implicit features of optional dependencies
+skip_optional_dependencies = false
+
+# Arguments passed to clippy after --, but not to other subcommands
+clippy_args = ["-D", "warnings"]
+
+# Files gated by a feature for --changed-features, in addition to
+# src/<feature>.rs and src/<feature>/
+feature_paths = { tokio = ["src/runtime/tokio"] }
+
+# Environment variables to set for the combinations including a feature
+feature_env = { grpc = { PROTOC = "/usr/bin/protoc" } }
+
+# Combine the features of another workspace member instead of the features
+# of this package, e.g. when re-exporting all features of a core crate
+mirror_features_from = "core"
+```
+
+Workspace-wide defaults for members can be configured in the workspace root:
+
+```toml
+[workspace.metadata.cargo-feature-combinations]
+# Skip the implicit features of optional dependencies, unless a member
+# configures skip_optional_dependencies itself
+skip_optional_dependencies = true
+
+# Feature sets skipped or included in every member, in addition to their own.
+# Sets a member includes are not skipped
+skip_feature_sets = [ ["tokio", "async-std"], ]
+include_feature_sets = [ ["std"], ]
+
+# Matrix values for all members, which member matrix values take precedence over
+matrix = { os = ["ubuntu-latest", "macos-latest"] }
+```
+
+The config can also be given in a JSON or TOML file with
+`--fc-config config.json`, e.g. when generated in CI. Its fields are overlaid on the config of every
+package, and the fields in its `workspace` object on the workspace config.
+Lists (such as `skip_feature_sets` or `exclude_features`) are appended to,
+tables are merged, and all other values are replaced:
+
+```json
+{
+  "exclude_features": ["nightly"],
+  "max_combination_size": 2,
+  "workspace": { "skip_optional_dependencies": true }
+}
+```
+
+For more information, see 'https://github.com/romnn/cargo-feature-combinations'.
+
+See 'cargo help <command>' for more information on a specific command.
+    "#;
+    println!("{help}");
+}
+
+static VALID_BOOLS: [&str; 4] = ["yes", "true", "y", "t"];
+
+/// Parses the command line arguments of `cargo fc`.
+///
+/// Our own flags are drained from the arguments, which are returned
+/// alongside the parsed [`Options`] and forwarded to cargo.
+pub fn parse_arguments(args: impl IntoIterator<Item = String>) -> eyre::Result<(Options, Args)> {
+    parse_arguments_with_stdin(args, io::stdin().lock())
+}
+
+/// Parses the command line arguments of `cargo fc` like [`parse_arguments`],
+/// reading the packages of `--stdin-packages` from `stdin`.
+pub fn parse_arguments_with_stdin(
+    args: impl IntoIterator<Item = String>,
+    stdin: impl io::BufRead,
+) -> eyre::Result<(Options, Args)> {
+    parse_arguments_with_env(args, stdin, |key| std::env::var(key).ok())
+}
+
+/// Parses the command line arguments of `cargo fc` like [`parse_arguments_with_stdin`],
+/// looking up environment variables such as `VERBOSE` and `FC_EXCLUDE_FEATURES` with `env`.
+pub fn parse_arguments_with_env(
+    args: impl IntoIterator<Item = String>,
+    stdin: impl io::BufRead,
+    env: impl Fn(&str) -> Option<String>,
+) -> eyre::Result<(Options, Args)> {
+    let mut args: Args = Args(args.into_iter().collect());
+
+    let mut options = Options {
+        verbose: VALID_BOOLS
+            .contains(&env("VERBOSE").unwrap_or_default().to_lowercase().as_str())
+            .into(),
+        ..Options::default()
+    };
+
+    // extract path to manifest to operate on
+    for (span, manifest_path) in args.get_all("--manifest-path", true) {
+        // the manifest may not exist yet, which cargo metadata reports
+        let manifest_path = std::path::absolute(&manifest_path)
+            .wrap_err_with(|| format!("invalid manifest path {manifest_path:?}"))?;
+        options.manifest_path = Some(manifest_path);
+        args.drain(span);
+    }
+
+    // extract the cargo program to run
+    for (span, cargo_bin) in args.get_all("--cargo-bin", true) {
+        options.cargo_bin = Some(PathBuf::from(cargo_bin));
+        args.drain(span);
+    }
+
+    // extract packages to operate on
+    for flag in ["--package", "-p"] {
+        for (span, package) in args.get_all(flag, true) {
+            options.packages.insert(package);
+            args.drain(span);
+        }
+    }
+
+    // read packages to operate on from stdin, one per line
+    let mut stdin_packages = false;
+    for (span, _) in args.get_all("--stdin-packages", false) {
+        stdin_packages = true;
+        args.drain(span);
+    }
+    if stdin_packages {
+        for line in stdin.lines() {
+            let line = line.wrap_err("failed to read packages from stdin")?;
+            let package = line.trim();
+            if !package.is_empty() {
+                options.packages.insert(package.to_string());
+            }
+        }
+    }
+
+    // check for matrix command
+    for (span, _) in args.get_all("matrix", false) {
+        options.command = Some(Command::FeatureMatrix {
+            pretty: false,
+            format: MatrixFormat::default(),
+            with_command: false,
+        });
+        args.drain(span);
+    }
+    // check for pretty matrix option
+    for (span, _) in args.get_all("--pretty", false) {
+        if let Some(Command::FeatureMatrix { ref mut pretty, .. }) = options.command {
+            *pretty = true;
+        }
+        args.drain(span);
+    }
+    // check for matrix command option
+    for (span, _) in args.get_all("--with-command", false) {
+        if let Some(Command::FeatureMatrix {
+            ref mut with_command,
+            ..
+        }) = options.command
+        {
+            *with_command = true;
+        }
+        args.drain(span);
+    }
+    // extract the number of feature combinations per matrix row
+    for (span, batch) in args.get_all("--batch", true) {
+        let batch = batch
+            .parse::<usize>()
+            .ok()
+            .filter(|batch| *batch > 0)
+            .ok_or_else(|| eyre::eyre!("invalid --batch {batch:?}, expected a positive number"))?;
+        options.batch = Some(batch);
+        args.drain(span);
+    }
+    // check for collapse identical matrix option
+    for (span, _) in args.get_all("--collapse-identical", false) {
+        options.collapse_identical = true;
+        args.drain(span);
+    }
+    // extract a previous matrix to diff against
+    for (span, path) in args.get_all("--diff-against", true) {
+        options.diff_against = Some(PathBuf::from(path));
+        args.drain(span);
+    }
+    // extract the directory to write the feature files to
+    for (span, dir) in args.get_all("--emit-feature-files", true) {
+        options.emit_feature_files = Some(PathBuf::from(dir));
+        args.drain(span);
+    }
+    // extract the separator of the features in the matrix and cargo command
+    for (span, separator) in args.get_all("--features-separator", true) {
+        if separator.is_empty() {
+            eyre::bail!("invalid --features-separator, expected a non-empty separator");
+        }
+        options.features_separator = Some(separator);
+        args.drain(span);
+    }
+    // extract the feature combinations to run, e.g. of a batched matrix row
+    let separator = options
+        .features_separator
+        .clone()
+        .unwrap_or(",".to_string());
+    for (span, sets) in args.get_all("--feature-sets", true) {
+        let sets: Vec<String> = serde_json::from_str(&sets).wrap_err_with(|| {
+            format!("invalid --feature-sets {sets:?}, expected a JSON array of strings")
+        })?;
+        let sets = sets.iter().map(|set| {
+            set.split(separator.as_str())
+                .filter(|ft| !ft.is_empty())
+                .map(str::to_string)
+                .sorted()
+                .collect()
+        });
+        options
+            .feature_sets
+            .get_or_insert_with(Vec::new)
+            .extend(sets);
+        args.drain(span);
+    }
+    // extract the template of matrix display names
+    for (span, template) in args.get_all("--name-template", true) {
+        options.name_template = Some(template);
+        args.drain(span);
+    }
+    // check for matrix output format option
+    for (span, output_format) in args.get_all("--output-format", true) {
+        if let Some(Command::FeatureMatrix { ref mut format, .. }) = options.command {
+            *format = output_format.parse()?;
+        }
+        args.drain(span);
+    }
+
+    // check for per package count option, the count command is only known
+    // once all options are extracted
+    let mut per_package = false;
+    for (span, _) in args.get_all("--per-package", false) {
+        per_package = true;
+        args.drain(span);
+    }
+
+    // check for list command
+    for (span, _) in args.get_all("--list", false) {
+        options.command = Some(Command::List);
+        args.drain(span);
+    }
+
+    // check for print config command
+    for (span, _) in args.get_all("--print-config", false) {
+        options.command = Some(Command::PrintConfig);
+        args.drain(span);
+    }
+
+    // check for help command
+    for flag in ["--help", "-h"] {
+        for (span, _) in args.get_all(flag, false) {
+            options.command = Some(Command::Help);
+            args.drain(span);
+        }
+    }
+
+    // check for pedantic flag
+    for (span, _) in args.get_all("--pedantic", false) {
+        options.pedantic = true;
+        args.drain(span);
+    }
+
+    // check for warning threshold
+    for (span, max_warnings) in args.get_all("--max-warnings", true) {
+        let max_warnings = max_warnings.parse::<usize>().map_err(|_| {
+            eyre::eyre!("invalid --max-warnings {max_warnings:?}, expected a number")
+        })?;
+        options.max_warnings = Some(max_warnings);
+        args.drain(span);
+    }
+    if options.pedantic {
+        options.max_warnings = Some(0);
+    }
+
+    // check for errors only
+    for (span, _) in args.get_all("--errors-only", false) {
+        options.errors_only = true;
+        args.drain(span);
+    }
+
+    // packages only
+    for (span, _) in args.get_all("--packages-only", false) {
+        options.packages_only = true;
+        args.drain(span);
+    }
+
+    // only packages with lib or bin targets
+    for (span, _) in args.get_all("--only-packages-with-lib-target", false) {
+        options.only_packages_with_lib_target = true;
+        args.drain(span);
+    }
+    for (span, _) in args.get_all("--only-packages-with-bin-target", false) {
+        options.only_packages_with_bin_target = true;
+        args.drain(span);
+    }
+
+    // check for silent flag
+    for (span, _) in args.get_all("--silent", false) {
+        options.silent = true;
+        args.drain(span);
+    }
+
+    // check for fail fast flag
+    for (span, _) in args.get_all("--fail-fast", false) {
+        options.fail_fast = true;
+        args.drain(span);
+    }
+
+    // check for keep going flag
+    for flag in ["--keep-going", "--fail-at-end"] {
+        for (span, _) in args.get_all(flag, false) {
+            options.keep_going = true;
+            args.drain(span);
+        }
+    }
+    if options.fail_fast && options.keep_going {
+        eyre::bail!("--fail-fast cannot be used together with --keep-going");
+    }
+
+    // extract path to write the JSON summary to
+    for (span, path) in args.get_all("--summary-json", true) {
+        options.summary_json = Some(PathBuf::from(path));
+        args.drain(span);
+    }
+
+    // extract path to write the JUnit report to
+    for (span, path) in args.get_all("--junit", true) {
+        options.junit = Some(PathBuf::from(path));
+        args.drain(span);
+    }
+
+    // extract path to write the SARIF report to
+    for (span, path) in args.get_all("--sarif", true) {
+        options.sarif = Some(PathBuf::from(path));
+        args.drain(span);
+    }
+
+    // check for TeamCity service messages
+    for (span, _) in args.get_all("--teamcity", false) {
+        options.teamcity = true;
+        args.drain(span);
+    }
+
+    // extract the shard of feature combinations to run
+    for (span, shard) in args.get_all("--shard", true) {
+        options.shard = Some(shard.parse()?);
+        args.drain(span);
+    }
+    for (span, _) in args.get_all("--balance-shards", false) {
+        options.balance_shards = true;
+        args.drain(span);
+    }
+    if options.balance_shards && options.shard.is_none() {
+        eyre::bail!("--balance-shards requires --shard");
+    }
+
+    // check for json message format, other formats are forwarded to cargo
+    for (span, format) in args.get_all("--message-format", true) {
+        if format == "json" {
+            options.message_format_json = true;
+            args.drain(span);
+        }
+    }
+
+    if options.sarif.is_some() && !options.message_format_json {
+        eyre::bail!("--sarif requires --message-format json to collect the diagnostics");
+    }
+
+    // check for report sizes flag
+    for (span, _) in args.get_all("--report-sizes", false) {
+        options.report_sizes = true;
+        args.drain(span);
+    }
+    if options.report_sizes && !options.message_format_json {
+        eyre::bail!("--report-sizes requires --message-format json to collect the artifacts");
+    }
+
+    // extract timeout per cargo invocation
+    for (span, timeout) in args.get_all("--timeout", true) {
+        // timeouts too long to compute a deadline from are rejected, too
+        let duration = timeout
+            .parse::<f64>()
+            .ok()
+            .and_then(|secs| Duration::try_from_secs_f64(secs).ok())
+            .filter(|duration| {
+                !duration.is_zero() && Instant::now().checked_add(*duration).is_some()
+            })
+            .ok_or_else(|| eyre::eyre!("invalid timeout {timeout:?} (expected seconds)"))?;
+        options.timeout = Some(duration);
+        args.drain(span);
+    }
+
+    // check for skip failing supersets flag
+    for (span, _) in args.get_all("--skip-failing-supersets", false) {
+        options.skip_failing_supersets = true;
+        args.drain(span);
+    }
+
+    // check for default features flag
+    for (span, _) in args.get_all("--default-features", false) {
+        options.default_features = true;
+        args.drain(span);
+    }
+
+    // extract color mode, falling back to the one configured for cargo
+    let mut color = None;
+    for (span, value) in args.get_all("--color", true) {
+        // the last occurrence wins
+        color.get_or_insert(value);
+        args.drain(span);
+    }
+    if let Some(color) = color.or_else(|| env("CARGO_TERM_COLOR")) {
+        options.color = color.parse()?;
+    }
+
+    // extract config file, leaving cargo's own --config to cargo
+    for (span, path) in args.get_all("--fc-config", true) {
+        options.config_file = Some(PathBuf::from(path));
+        args.drain(span);
+    }
+
+    // extract regexes to filter the combined features by
+    for (span, filter) in args.get_all("--feature-filter", true) {
+        options.feature_filter = Some(
+            Regex::new(&filter).wrap_err_with(|| format!("invalid feature filter {filter:?}"))?,
+        );
+        args.drain(span);
+    }
+    for (span, reject) in args.get_all("--feature-reject", true) {
+        options.feature_reject = Some(
+            Regex::new(&reject).wrap_err_with(|| format!("invalid feature reject {reject:?}"))?,
+        );
+        args.drain(span);
+    }
+
+    // check for rerun failed flag
+    for (span, _) in args.get_all("--rerun-failed", false) {
+        options.rerun_failed = true;
+        args.drain(span);
+    }
+
+    // check for a planned matrix to run
+    for (span, path) in args.get_all("--features-json", true) {
+        options.features_json = Some(PathBuf::from(path));
+        args.drain(span);
+    }
+    if options.features_json.is_some() && options.rerun_failed {
+        eyre::bail!("--features-json cannot be combined with --rerun-failed");
+    }
+
+    // check for group by package flag
+    for (span, _) in args.get_all("--group-by-package", false) {
+        options.group_by_package = true;
+        args.drain(span);
+    }
+
+    // check for no feature args flag
+    for (span, _) in args.get_all("--no-feature-args", false) {
+        options.no_feature_args = true;
+        args.drain(span);
+    }
+
+    // extract arguments for clippy, such as lint levels
+    let mut clippy_args = Vec::new();
+    for (span, args_value) in args.get_all("--clippy-args", true) {
+        clippy_args.push(args_value);
+        args.drain(span);
+    }
+    // spans are visited last to first
+    clippy_args.reverse();
+    options.clippy_args = clippy_args
+        .iter()
+        .flat_map(|args| args.split_whitespace().map(str::to_string))
+        .collect();
+
+    // extract the order of feature combinations, of which the last one wins
+    let mut order = None;
+    for (span, value) in args.get_all("--order", true) {
+        order.get_or_insert(value);
+        args.drain(span);
+    }
+    options.order = order.map(|order| order.parse()).transpose()?;
+
+    // check for strict config flag
+    for (span, _) in args.get_all("--strict-config", false) {
+        options.strict_config = true;
+        args.drain(span);
+    }
+
+    // check for explain flag
+    for (span, _) in args.get_all("--explain", false) {
+        options.explain = true;
+        args.drain(span);
+    }
+
+    // check for cache flag
+    for (span, _) in args.get_all("--cache", false) {
+        options.cache = true;
+        args.drain(span);
+    }
+
+    // check for only changed flag
+    for (span, _) in args.get_all("--only-changed", false) {
+        options.only_changed = true;
+        args.drain(span);
+    }
+
+    // check for exit with count flag
+    for (span, _) in args.get_all("--exit-with-count", false) {
+        options.exit_with_count = true;
+        args.drain(span);
+    }
+
+    // check for no progress flag
+    for (span, _) in args.get_all("--no-progress", false) {
+        options.no_progress = true;
+        args.drain(span);
+    }
+
+    // check for confirm flag
+    for (span, _) in args.get_all("--confirm", false) {
+        options.confirm = true;
+        args.drain(span);
+    }
+
+    // check for pairwise flag
+    for (span, _) in args.get_all("--pairwise", false) {
+        options.pairwise = true;
+        args.drain(span);
+    }
+
+    // check for dedupe implied flag
+    for (span, _) in args.get_all("--dedupe-implied", false) {
+        options.dedupe_implied = true;
+        args.drain(span);
+    }
+
+    // check for the all features and no features flags
+    for (flag, mode) in [
+        ("--all-features", QuickMode::AllFeatures),
+        ("--no-features", QuickMode::NoFeatures),
+    ] {
+        for (span, _) in args.get_all(flag, false) {
+            if options.quick_mode.is_some_and(|other| other != mode) {
+                eyre::bail!("--all-features cannot be used together with --no-features");
+            }
+            options.quick_mode = Some(mode);
+            args.drain(span);
+        }
+    }
+    if options.quick_mode.is_some() && options.pairwise {
+        eyre::bail!("--all-features and --no-features cannot be used together with --pairwise");
+    }
+
+    // check for sort by time flag
+    for (span, _) in args.get_all("--sort-by-time", false) {
+        options.sort_by_time = true;
+        args.drain(span);
+    }
+
+    // check for dry run flag
+    for (span, _) in args.get_all("--dry-run", false) {
+        options.dry_run = true;
+        args.drain(span);
+    }
+
+    // check for number of parallel jobs, -j and --jobs are passed to cargo
+    for (span, jobs) in args.get_all("--fc-jobs", true) {
+        let jobs = jobs
+            .parse::<usize>()
+            .wrap_err_with(|| format!("invalid number of jobs {jobs:?}"))?;
+        options.jobs = Some(jobs);
+        args.drain(span);
+    }
+
+    // extract features to exclude from or include in every combination
+    for (span, features) in args.get_all("--exclude-feature", true) {
+        options.exclude_features.extend(
+            features
+                .split(',')
+                .filter(|ft| !ft.is_empty())
+                .map(str::to_string),
+        );
+        args.drain(span);
+    }
+    // extract feature sets to skip, in addition to the skip_feature_sets config
+    let mut exclude_feature_sets = Vec::new();
+    for (span, features) in args.get_all("--exclude-feature-set", true) {
+        exclude_feature_sets.push(
+            features
+                .split(',')
+                .filter(|ft| !ft.is_empty())
+                .map(str::to_string)
+                .collect(),
+        );
+        args.drain(span);
+    }
+    // spans are visited last to first
+    exclude_feature_sets.reverse();
+    options.exclude_feature_sets = exclude_feature_sets;
+    // extract the file of features to include and exclude, read in `run`
+    for (span, path) in args.get_all("--features-from-file", true) {
+        options.features_file = Some(PathBuf::from(path));
+        args.drain(span);
+    }
+
+    // extract features to include for a single package
+    for (span, value) in args.get_all("--package-features", true) {
+        let Some((package, features)) = value.split_once(':') else {
+            eyre::bail!("invalid package features {value:?}, expected PACKAGE:FEATURES");
+        };
+        options
+            .package_features
+            .entry(package.to_string())
+            .or_default()
+            .extend(
+                features
+                    .split(',')
+                    .filter(|feature| !feature.is_empty())
+                    .map(str::to_string),
+            );
+        args.drain(span);
+    }
+
+    // extract the git ref to select changed packages by
+    for (span, since) in args.get_all("--since", true) {
+        options.since = Some(since);
+        args.drain(span);
+    }
+    for (span, _) in args.get_all("--since-include-dependents", false) {
+        options.since_include_dependents = true;
+        args.drain(span);
+    }
+    for (span, _) in args.get_all("--changed-features", false) {
+        options.changed_features = true;
+        args.drain(span);
+    }
+    if options.changed_features && options.since.is_none() {
+        eyre::bail!("--changed-features requires --since");
+    }
+    if options.since_include_dependents && options.since.is_none() {
+        eyre::bail!("--since-include-dependents requires --since");
+    }
+
+    // extract packages to exclude, which may be glob patterns
+    for (span, packages) in args.get_all("--exclude-package", true) {
+        options
+            .exclude_packages
+            .extend(packages.split(',').map(str::to_string));
+        args.drain(span);
+    }
+
+    for (span, features) in args.get_all("--include-feature", true) {
+        options.include_features.extend(
+            features
+                .split(',')
+                .filter(|ft| !ft.is_empty())
+                .map(str::to_string),
+        );
+        args.drain(span);
+    }
+
+    // fold features passed to cargo into the include features, so that the
+    // command line contains a single --features flag
+    let mut cargo_features = Vec::new();
+    for flag in ["--features", "-F"] {
+        for (span, features) in args.get_all(flag, true) {
+            cargo_features.extend(
+                features
+                    .split([',', ' '])
+                    .filter(|feature| !feature.is_empty())
+                    .map(str::to_string),
+            );
+            args.drain(span);
+        }
+    }
+    // features of dependencies are only passed to cargo
+    options.include_features.extend(
+        cargo_features
+            .iter()
+            .filter(|feature| !feature.contains('/'))
+            .cloned(),
+    );
+    options.cargo_features = cargo_features.into_iter().sorted().dedup().collect();
+
+    // extract targets to run every feature combination for
+    let mut targets = Vec::new();
+    for (span, target) in args.get_all("--target", true) {
+        targets.push(target);
+        args.drain(span);
+    }
+    // spans are visited last to first
+    targets.reverse();
+    options.targets = targets.into_iter().unique().collect();
+
+    // check for cross-compilation with cross, which replaces cargo
+    for (span, _) in args.get_all("--use-cross", false) {
+        options.use_cross = true;
+        args.drain(span);
+    }
+    if options.use_cross {
+        if options.cargo_bin.is_some() {
+            eyre::bail!("--use-cross conflicts with --cargo-bin");
+        }
+        if options.targets.is_empty() {
+            eyre::bail!("--use-cross requires --target");
+        }
+        options.cargo_bin = Some(PathBuf::from("cross"));
+    }
+
+    // extract toolchains to run every feature combination with
+    let mut toolchains = Vec::new();
+    for (span, toolchain) in args.get_all("--toolchain", true) {
+        toolchains.push(toolchain.trim_start_matches('+').to_string());
+        args.drain(span);
+    }
+    toolchains.reverse();
+    options.toolchains = toolchains.into_iter().unique().collect();
+
+    // extract the cargo subcommands to allow besides the known ones
+    for (span, subcommand) in args.get_all("--allow-subcommand", true) {
+        options.allowed_subcommands.push(subcommand);
+        args.drain(span);
+    }
+    options.allowed_subcommands.reverse();
+
+    // check for count command, which is only the first positional argument,
+    // e.g. not the test name filter of `cargo fc test count`
+    if let Some(idx) = args
+        .subcommand_position()
+        .filter(|idx| args[*idx] == "count")
+    {
+        options.command = Some(Command::Count { per_package });
+        args.drain(idx..=idx);
+    }
+
+    // count verbose flags before the cargo subcommand, on top of the VERBOSE
+    // environment variable, and quiet flags, while those after it are passed to cargo
+    let subcommand = args
+        .subcommand_position()
+        .or_else(|| args.iter().position(|arg| arg == "--"))
+        .unwrap_or(args.len());
+    let (mut verbose, mut quiet) = (0u8, 0u8);
+    let mut idx = 0;
+    // the level of a flag repeating a short flag, e.g. 3 for -vvv
+    let repeated = |arg: &str, short: char| {
+        arg.strip_prefix('-')
+            .filter(|flag| !flag.is_empty() && flag.chars().all(|c| c == short))
+            .map_or(0, str::len)
+    };
+    args.retain(|arg| {
+        let (verbose_level, quiet_level) = match arg.as_str() {
+            "--verbose" => (1, 0),
+            "--quiet" => (0, 1),
+            arg => (repeated(arg, 'v'), repeated(arg, 'q')),
+        };
+        let before_subcommand = idx < subcommand;
+        idx += 1;
+        if before_subcommand && verbose_level + quiet_level > 0 {
+            verbose = verbose.saturating_add(u8::try_from(verbose_level).unwrap_or(u8::MAX));
+            quiet = quiet.saturating_add(u8::try_from(quiet_level).unwrap_or(u8::MAX));
+            return false;
+        }
+        true
+    });
+    options.verbose = options.verbose.max(verbose);
+    // there are no quieter levels than -qq
+    options.quiet = quiet.min(2);
+    if options.quiet > 0 {
+        options.silent = true;
+    }
+
+    merge_env_rules(&mut options, env);
+
+    Ok((options, args))
+}
+
+/// Merges the comma-separated `FC_EXCLUDE_FEATURES`, `FC_INCLUDE_FEATURES`
+/// and `FC_EXCLUDE_PACKAGES` environment variables, looked up with `var`,
+/// into the options parsed from the command line.
+///
+/// Features included on the command line are not excluded by the
+/// environment, and vice versa.
+fn merge_env_rules(options: &mut Options, var: impl Fn(&str) -> Option<String>) {
+    let list = |key: &str| -> Vec<String> {
+        var(key)
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|item| !item.is_empty())
+            .map(str::to_string)
+            .collect()
+    };
+    let exclude_features: Vec<String> = list("FC_EXCLUDE_FEATURES")
+        .into_iter()
+        .filter(|ft| !options.include_features.contains(ft))
+        .collect();
+    let include_features: Vec<String> = list("FC_INCLUDE_FEATURES")
+        .into_iter()
+        .filter(|ft| !options.exclude_features.contains(ft))
+        .collect();
+    options.exclude_features.extend(exclude_features);
+    options.include_features.extend(include_features);
+    options.exclude_packages.extend(list("FC_EXCLUDE_PACKAGES"));
+}
+
+/// Parses a features file of `--features-from-file` into the features to
+/// include in and exclude from all combinations.
+///
+/// Each line is a feature prefixed with `+` to include or `-` to exclude it.
+/// Blank lines and comments starting with `#` are ignored.
+pub fn parse_feature_policy(policy: &str) -> eyre::Result<(Vec<String>, Vec<String>)> {
+    let mut include_features = Vec::new();
+    let mut exclude_features = Vec::new();
+    for (idx, line) in policy.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or_default().trim();
+        if line.is_empty() {
+            continue;
+        }
+        let (features, feature) = if let Some(feature) = line.strip_prefix('+') {
+            (&mut include_features, feature.trim())
+        } else if let Some(feature) = line.strip_prefix('-') {
+            (&mut exclude_features, feature.trim())
+        } else {
+            eyre::bail!(
+                "line {}: expected a feature prefixed with + or -, found {line:?}",
+                idx + 1
+            );
+        };
+        if feature.is_empty() {
+            eyre::bail!("line {}: missing feature after {line:?}", idx + 1);
+        }
+        features.push(feature.to_string());
+    }
+    Ok((include_features, exclude_features))
+}
+
+/// Whether `name` matches a glob `pattern`.
+///
+/// A `*` matches any sequence of characters and a `?` matches a single
+/// character. A pattern without either only matches the exact name.
+#[must_use]
+pub fn glob_matches(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    let (mut p, mut n) = (0, 0);
+    // position of the last `*` in the pattern and of the name when it was reached
+    let mut backtrack = None;
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, n));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match backtrack {
+                // let the last `*` match one more character
+                Some((star, matched)) => {
+                    backtrack = Some((star, matched + 1));
+                    p = star + 1;
+                    n = matched + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// Whether a package is excluded by any of the (glob) `patterns`.
+#[must_use]
+pub fn is_package_excluded(package: &cargo_metadata::Package, patterns: &[String]) -> bool {
+    patterns
+        .iter()
+        .any(|pattern| glob_matches(pattern, &package.name))
+}
+
+/// Whether a package has the targets required by
+/// `--only-packages-with-lib-target` and `--only-packages-with-bin-target`.
+///
+/// Libraries of any crate type, including proc macros, count as lib targets.
+#[must_use]
+pub fn has_selected_targets(package: &cargo_metadata::Package, options: &Options) -> bool {
+    let has_lib = || {
+        package.targets.iter().any(|target| {
+            target.is_lib()
+                || target.is_rlib()
+                || target.is_dylib()
+                || target.is_cdylib()
+                || target.is_staticlib()
+                || target.is_proc_macro()
+        })
+    };
+    let has_bin = || package.targets.iter().any(cargo_metadata::Target::is_bin);
+    (!options.only_packages_with_lib_target || has_lib())
+        && (!options.only_packages_with_bin_target || has_bin())
+}
+
+/// Files changed since the git ref `since`, as absolute paths.
+///
+/// Only changes within `workspace_root` are considered.
+pub fn git_changed_files(
+    workspace_root: &std::path::Path,
+    since: &str,
+) -> eyre::Result<Vec<PathBuf>> {
+    let output = process::Command::new("git")
+        .args(["diff", "--name-only", "--relative", since, "--"])
+        .current_dir(workspace_root)
+        .output()
+        .wrap_err("failed to run git diff")?;
+    if !output.status.success() {
+        eyre::bail!(
+            "failed to list the files changed since {since}: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter(|line| !line.is_empty())
+        .map(|line| workspace_root.join(line))
+        .collect())
+}
+
+/// Features of a package with any of the `changed_files`, or `None` if files
+/// not gated by a feature changed, in which case all features are affected.
+///
+/// Files under a path of the `feature_paths` of a feature belong to it.
+/// Otherwise, `src/<feature>.rs` and the files in `src/<feature>/` belong to
+/// a feature, where dashes in the feature name are replaced by underscores.
+/// Changed files outside of the package are ignored. A package without any
+/// changed files, e.g. one depending on a changed package, has `None`.
+#[must_use]
+pub fn features_of_changed_files(
+    package: &cargo_metadata::Package,
+    config: &Config,
+    changed_files: &[PathBuf],
+) -> Option<HashSet<String>> {
+    let dir = package.manifest_path.parent()?.as_std_path();
+    let mut changed = HashSet::new();
+    for file in changed_files {
+        let Ok(file) = file.strip_prefix(dir) else {
+            continue;
+        };
+        let configured = config
+            .feature_paths
+            .iter()
+            .filter(|(_, paths)| paths.iter().any(|path| file.starts_with(path)))
+            .map(|(feature, _)| feature.clone())
+            .collect::<Vec<_>>();
+        if !configured.is_empty() {
+            changed.extend(configured);
+            continue;
+        }
+        let feature = package.features.keys().find(|feature| {
+            let module = std::path::Path::new("src").join(feature.replace('-', "_"));
+            file == module.with_extension("rs") || file.starts_with(&module)
+        });
+        match feature {
+            Some(feature) => {
+                changed.insert(feature.clone());
+            }
+            // e.g. a change of the lib.rs affects all features
+            None => return None,
+        }
+    }
+    // a change of a dependency may affect all features
+    (!changed.is_empty()).then_some(changed)
+}
+
+/// Names of the packages owning any of the `changed_files`.
+///
+/// A file is owned by the package with the innermost directory containing it.
+/// With `include_dependents`, packages depending on a changed package through
+/// a path dependency, directly or transitively, are included as well.
+#[must_use]
+pub fn changed_packages(
+    packages: &[&cargo_metadata::Package],
+    changed_files: &[PathBuf],
+    include_dependents: bool,
+) -> HashSet<String> {
+    let mut changed: HashSet<String> = changed_files
+        .iter()
+        .filter_map(|file| {
+            packages
+                .iter()
+                .filter_map(|package| {
+                    let dir = package.manifest_path.parent()?;
+                    file.starts_with(dir)
+                        .then_some((dir.as_str().len(), package))
+                })
+                .max_by_key(|(len, _)| *len)
+                .map(|(_, package)| package.name.clone())
+        })
+        .collect();
+    if !include_dependents {
+        return changed;
+    }
+    // dependents of dependents are added until nothing changes
+    loop {
+        let dependents: Vec<String> = packages
+            .iter()
+            .filter(|package| !changed.contains(&package.name))
+            .filter(|package| {
+                package
+                    .dependencies
+                    .iter()
+                    .any(|dep| dep.path.is_some() && changed.contains(&dep.name))
+            })
+            .map(|package| package.name.clone())
+            .collect();
+        if dependents.is_empty() {
+            break;
+        }
+        changed.extend(dependents);
+    }
+    changed
+}
+
+/// Whether a package is selected by a `--package` spec.
+///
+/// A spec is either a package name, `name@version` (where the version may be
+/// partial, e.g. `foo@1`), a package id, or the path to the package directory
+/// or its manifest. Names may be glob patterns such as `api-*`.
+#[must_use]
+pub fn package_matches_spec(package: &cargo_metadata::Package, spec: &str) -> bool {
+    if spec == package.id.repr {
+        return true;
+    }
+    if spec.contains(std::path::MAIN_SEPARATOR) || spec.starts_with('.') {
+        let Ok(path) = std::fs::canonicalize(spec) else {
+            return false;
+        };
+        return path == package.manifest_path.as_std_path()
+            || package
+                .manifest_path
+                .parent()
+                .is_some_and(|dir| path == dir.as_std_path());
+    }
+    match spec.split_once('@') {
+        Some((name, version)) => {
+            let package_version = package.version.to_string();
+            glob_matches(name, &package.name)
+                && package_version
+                    .strip_prefix(version)
+                    .is_some_and(|rest| rest.is_empty() || rest.starts_with(['.', '-', '+']))
+        }
+        None => glob_matches(spec, &package.name),
+    }
+}
+
+/// Replaces the features of workspace members configuring `mirror_features_from`
+/// with the features of the referenced workspace member.
+///
+/// The features declared by the referenced member are mirrored,
+/// even if that member mirrors the features of another member in turn.
+///
+/// # Errors
+///
+/// If a config can not be parsed or references a package that is not
+/// a member of the workspace, an Error is returned.
+pub fn mirror_features(
+    metadata: &mut cargo_metadata::Metadata,
+    workspace: &WorkspaceConfig,
+) -> eyre::Result<()> {
+    let members = metadata.workspace_packages();
+    let mut mirrored = Vec::new();
+    for package in &members {
+        let Some(source) = package.config(workspace)?.mirror_features_from else {
+            continue;
+        };
+        let Some(source) = members.iter().find(|member| member.name == source) else {
+            eyre::bail!(
+                "package {} mirrors the features of {source:?}, which is not a workspace member",
+                package.name
+            );
+        };
+        mirrored.push((package.id.clone(), source.features.clone()));
+    }
+    for (id, features) in mirrored {
+        if let Some(package) = metadata.packages.iter_mut().find(|p| p.id == id) {
+            package.features = features;
+        }
+    }
+    Ok(())
+}
+
+/// Resolves the config of a package, merging in overrides given on the command line.
+pub fn resolve_config(
+    package: &cargo_metadata::Package,
+    workspace: &WorkspaceConfig,
+    options: &Options,
+) -> eyre::Result<Config> {
+    let mut config = package.config(workspace)?;
+    // features included or excluded on the command line or in the
+    // environment take precedence over the package config
+    config
+        .exclude_features
+        .retain(|ft| !options.include_features.contains(ft));
+    config
+        .include_features
+        .retain(|ft| !options.exclude_features.contains(ft));
+    config
+        .exclude_features
+        .extend(options.exclude_features.iter().cloned());
+    config
+        .include_features
+        .extend(options.include_features.iter().cloned());
+    if let Some(features) = options.package_features.get(&package.name) {
+        config.include_features.extend(features.iter().cloned());
+    }
+    config
+        .skip_feature_sets
+        .extend(options.exclude_feature_sets.iter().cloned());
+    config.pairwise |= options.pairwise;
+    config.dedupe_implied |= options.dedupe_implied;
+    config.quick_mode = options.quick_mode;
+    config
+        .clippy_args
+        .extend(options.clippy_args.iter().cloned());
+    config
+        .features_separator
+        .clone_from(&options.features_separator);
+    config.include_default_features |= options.default_features;
+    config.feature_filter.clone_from(&options.feature_filter);
+    config.feature_reject.clone_from(&options.feature_reject);
+    if let Some(order) = options.order {
+        config.order = order;
+    }
+    if let Some(ref template) = options.name_template {
+        config.name_template = Some(template.clone());
+    }
+    // the single feature set of --all-features or --no-features is not
+    // dropped by the rules, so it must not break them either
+    if config.quick_mode.is_some() {
+        for set in combinations_iter(&package.features, &config) {
+            if let Some(reason) = skip_reason(&config, &set) {
+                eyre::bail!(
+                    "the features [{}] of package {} selected with --all-features or \
+                    --no-features would be {reason}, exclude some of them with --exclude-feature",
+                    set.iter().join(", "),
+                    package.name
+                );
+            }
+        }
+    }
+    Ok(config)
+}
+
+/// Runs `cargo metadata`, naming the manifest in the error if it fails,
+/// e.g. because the manifest does not exist.
+fn exec_metadata(
+    cmd: &cargo_metadata::MetadataCommand,
+    manifest_path: Option<&std::path::Path>,
+) -> eyre::Result<cargo_metadata::Metadata> {
+    cmd.exec().wrap_err_with(|| match manifest_path {
+        Some(path) => format!("failed to read the metadata of manifest {}", path.display()),
+        None => "failed to read the metadata of the current package".to_string(),
+    })
+}
+
+/// Runs `cargo fc` with the command line arguments of the current process.
+///
+/// Instead of exiting the process, the report of the run is returned,
+/// from which the caller can decide on the exit code.
+pub fn run(bin_name: impl AsRef<str>) -> eyre::Result<RunReport> {
+    color_eyre::install()?;
+
+    let args: Vec<String> = std::env::args()
+        // skip executable name
+        .skip(1)
+        // skip our own cargo-* command name
+        .skip_while(|arg| arg.as_str() == bin_name.as_ref())
+        .collect();
+
+    // use clap::FromArgMatches;
+    // let matches = clap::Command::new("command")
+    //     .arg(
+    //         clap::Arg::new("arg1")
+    //             .short('1')
+    //             .long("arg1")
+    //             .help("Argument one help message")
+    //             .default_value("one"), // Comment this line out to test other branches
+    //     )
+    //     .arg(
+    //         clap::Arg::new("arg2")
+    //             .short('2')
+    //             .long("arg2")
+    //             .help("Argument two help message"),
+    //     );
+    // let matches = matches.try_get_matches_from(&args)?;
+    // let values = NewOptions::try_parse_from(args.clone())?;
+    // let values = NewOptions::from_arg_matches(args.clone())?;
+    // let values = NewOptions::try_get_matches_from(args.clone())?;
+    // let values = ArgMatches::try_from(args.clone())?;
+    // dbg!(&matches);
+    //
+    // for test in matches {
+    //     dbg!(test);
+    // }
+
+    let (mut options, args) = parse_arguments(args)?;
+    if let Some(ref path) = options.features_file {
+        let policy = std::fs::read_to_string(path)
+            .wrap_err_with(|| format!("failed to read {}", path.display()))?;
+        let (include_features, exclude_features) = parse_feature_policy(&policy)
+            .wrap_err_with(|| format!("invalid features file {}", path.display()))?;
+        options.include_features.extend(include_features);
+        options.exclude_features.extend(exclude_features);
+    }
+
+    // get metadata for cargo package
+    let mut cmd = cargo_metadata::MetadataCommand::new();
+    if let Some(ref manifest_path) = options.manifest_path {
+        cmd.manifest_path(manifest_path);
+    }
+    let mut metadata = exec_metadata(&cmd, options.manifest_path.as_deref())?;
+    // cargo runs from the workspace root, where the target directory
+    // configured in `.cargo/config.toml` may differ from the current one
+    if std::env::current_dir()?.canonicalize()? != metadata.workspace_root.canonicalize()? {
+        cmd.current_dir(&metadata.workspace_root);
+        metadata = exec_metadata(&cmd, options.manifest_path.as_deref())?;
+    }
+    let mut workspace_config =
+        WorkspaceConfig::from_metadata(&metadata, options.config_file.as_deref())?;
+    workspace_config.strict = options.strict_config;
+    options
+        .allowed_subcommands
+        .extend(workspace_config.allowed_subcommands.iter().cloned());
+    mirror_features(&mut metadata, &workspace_config)?;
+    let mut packages = metadata.workspace_packages();
+    for message in unknown_workspace_feature_warnings(&packages, &workspace_config) {
+        warn_once(message);
+    }
+
+    for name in options.package_features.keys() {
+        if !packages.iter().any(|p| p.name == *name) {
+            eprintln!("warning: --package-features for unknown package {name:?} is ignored");
+        }
+    }
+
+    if let Some(root_package) = metadata.root_package() {
+        let config = root_package.config(&workspace_config)?;
+        // filter packages based on root package Cargo.toml configuration
+        packages.retain(|p| !is_package_excluded(p, &config.exclude_packages));
+    }
+    packages.retain(|p| !is_package_excluded(p, &options.exclude_packages));
+    packages.retain(|p| has_selected_targets(p, &options));
+
+    // filter packages based on CLI options
+    if !options.packages.is_empty() {
+        packages.retain(|p| {
+            options
+                .packages
+                .iter()
+                .any(|spec| package_matches_spec(p, spec))
+        });
+    }
+
+    let changed_files = options
+        .since
+        .as_ref()
+        .map(|since| git_changed_files(metadata.workspace_root.as_std_path(), since))
+        .transpose()?;
+    if let Some(ref changed_files) = changed_files {
+        let changed = changed_packages(
+            &metadata.workspace_packages(),
+            changed_files,
+            options.since_include_dependents,
+        );
+        packages.retain(|p| changed.contains(&p.name));
+    }
+
+    let packages = packages
+        .into_iter()
+        .map(|package| {
+            let mut config = resolve_config(package, &workspace_config, &options)?;
+            if let Some(changed_files) = changed_files.as_ref().filter(|_| options.changed_features)
+            {
+                config.changed_features =
+                    features_of_changed_files(package, &config, changed_files);
+            }
+            // the config is printed to debug too many configurations, too
+            if !matches!(options.command, Some(Command::PrintConfig)) {
+                check_num_configurations(package, &config)?;
+            }
+            Ok((package, config))
+        })
+        .collect::<eyre::Result<Vec<_>>>()?;
+
+    if options.verbose >= 2 {
+        print_feature_graph(packages.as_slice(), options.color)?;
+    }
+
+    if options.explain
+        && matches!(
+            options.command,
+            Some(Command::FeatureMatrix { .. } | Command::List)
+        )
+    {
+        print_explanation(packages.as_slice(), options.color)?;
+    }
+
+    match options.command {
+        Some(Command::Help) => {
+            print_help();
+            Ok(RunReport::default())
+        }
+        Some(Command::FeatureMatrix {
+            pretty,
+            format,
+            with_command,
+        }) => {
+            // the matrix is balanced by the durations recorded by earlier runs
+            let durations = if options.balance_shards {
+                read_durations(
+                    metadata
+                        .target_directory
+                        .join("fc/durations.json")
+                        .as_std_path(),
+                )?
+            } else {
+                BTreeMap::new()
+            };
+            print_feature_matrix(
+                packages.as_slice(),
+                pretty,
+                format,
+                with_command,
+                &durations,
+                &options,
+            )?;
+            Ok(RunReport::default())
+        }
+        Some(Command::List) => {
+            print_feature_list(packages.as_slice(), options.silent, options.color)?;
+            Ok(RunReport::default())
+        }
+        Some(Command::PrintConfig) => {
+            let config = resolved_config_json(packages.as_slice(), &workspace_config)?;
+            println!("{}", serde_json::to_string_pretty(&config)?);
+            Ok(RunReport::default())
+        }
+        Some(Command::Count { per_package }) => {
+            print_feature_count(packages.as_slice(), per_package);
+            Ok(RunReport::default())
+        }
+        None => {
+            check_subcommand(&args, &options)?;
+            let state_dir = metadata.target_directory.join("fc");
+            run_cargo_command(
+                packages.as_slice(),
+                args,
+                &options,
+                &metadata,
+                Some(state_dir.as_std_path()),
+            )
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        balance_shards, batch_rows, batches, cargo_program, cargo_subcommand, changed_packages,
+        check_feature_sets, check_num_configurations, check_run_binary, check_subcommand,
+        collapse_identical_rows, combination_label, combinations, config_file_digest, confirm_run,
+        cross_product, diff_matrices, edit_distance, error_counts, errors_only_rustflags,
+        exec_metadata, exit_code, explain_feature_combinations, failure_count_exit_code,
+        feature_env, feature_matrix_entries, feature_set_shards, feature_sets_to_run,
+        features_of_changed_files, first_bad_exit_code, format_size, has_selected_targets,
+        in_shard, injected_feature_args, is_package_excluded, is_proper_superset, junit_xml,
+        matrix_feature_sets, merge_failures, mirror_features, num_jobs, package_matches_spec,
+        parse_arguments_with_env, parse_feature_policy, print_matrix_diff, print_summary,
+        read_failures, render_json_diagnostics, render_json_messages, render_name_template,
+        resolve_config, resolved_config_json, size_column, source_paths, split_cargo_args,
+        unknown_feature_warnings, unknown_workspace_feature_warnings, warning_counts,
+        within_warning_threshold, write_failures, write_feature_files, Args, CacheInputs,
+        CargoSubcommand, Children, ColorMode, Command, Decision, Diagnostic, DiagnosticCounts,
+        FailedFeatureSet, Invocation, Job, MatrixChange, Options, Package, Progress, QuickMode,
+        Shard, Summary,
+    };
+    use crate::config::{Config, Order, WorkspaceConfig};
+    use color_eyre::eyre;
+    use itertools::Itertools;
+    use similar_asserts::assert_eq as sim_assert_eq;
+    use std::collections::{BTreeMap, HashMap, HashSet};
+    use std::io;
+
+    /// Parses `args` like [`super::parse_arguments`], but without the environment of the tests.
+    fn parse_arguments(args: impl IntoIterator<Item = String>) -> eyre::Result<(Options, Args)> {
+        parse_arguments_with_env(args, io::empty(), |_| None)
+    }
+
+    fn package_with_features(features: &[&str]) -> eyre::Result<cargo_metadata::Package> {
+        let features: BTreeMap<_, Vec<String>> = features
+            .iter()
+            .map(|feature| ((*feature).to_string(), vec![]))
+            .collect();
+        let package = serde_json::json!({
+            "name": "test",
+            "version": "0.1.0",
+            "id": "test 0.1.0 (path+file:///test)",
+            "dependencies": [],
+            "targets": [],
+            "features": features,
+            "manifest_path": "/test/Cargo.toml",
+        });
+        Ok(serde_json::from_value(package)?)
+    }
+
+    /// Package `name` with `features` in `/ws/{name}`,
+    /// which depends on the workspace members `path_dependencies` by path.
+    fn package(
+        name: &str,
+        features: &[&str],
+        path_dependencies: &[&str],
+    ) -> eyre::Result<cargo_metadata::Package> {
+        let mut package = serde_json::to_value(package_with_features(features)?)?;
+        package["name"] = name.into();
+        package["id"] = format!("{name} 0.1.0 (path+file:///ws/{name})").into();
+        package["manifest_path"] = format!("/ws/{name}/Cargo.toml").into();
+        package["dependencies"] = path_dependencies
+            .iter()
+            .map(|dependency| {
+                serde_json::json!({
+                    "name": dependency,
+                    "source": null,
+                    "req": "*",
+                    "kind": null,
+                    "optional": false,
+                    "uses_default_features": true,
+                    "features": [],
+                    "target": null,
+                    "rename": null,
+                    "registry": null,
+                    "path": format!("/ws/{dependency}"),
+                })
+            })
+            .collect();
+        Ok(serde_json::from_value(package)?)
+    }
+
+    /// Summary of the empty feature combination of package `name`,
+    /// which passed if `ok` and failed with an error otherwise.
+    fn summary(name: &str, ok: bool) -> Summary {
+        Summary {
+            package_name: name.to_string(),
+            features: vec![],
+            exit_code: Some(i32::from(!ok)),
+            pedantic_success: ok,
+            num_warnings: 0,
+            num_errors: usize::from(!ok),
+            timed_out: false,
+            toolchain: None,
+            target: None,
+            signal: None,
+            failing_subset: None,
+            cached: false,
+            cross: false,
+            unchanged: false,
+            duration: std::time::Duration::ZERO,
+            artifact_size: None,
+            diagnostics: vec![],
+        }
+    }
+
+    /// Parses `args` and splits the cargo arguments at the `--` separator.
+    fn split_arguments(args: &[&str]) -> eyre::Result<(Options, Args, Vec<String>)> {
+        let (options, cargo_args) = parse_arguments(args.iter().map(ToString::to_string))?;
+        let (cargo_args, extra_args) = split_cargo_args(cargo_args, &options);
+        Ok((options, cargo_args, extra_args))
+    }
+
+    /// Invocation of cargo from the workspace root `/`.
+    fn invocation<'a>(
+        cargo_args: &'a Args,
+        extra_args: &'a [String],
+        options: &'a Options,
+    ) -> Invocation<'a> {
+        Invocation {
+            cargo_args,
+            extra_args,
+            workspace_root: std::path::Path::new("/"),
+            missing_arguments: false,
+            options,
+            children: Children::default(),
+        }
+    }
+
+    /// Job of the combination of all features of `package` for `target`.
+    fn job<'a>(
+        package: &'a cargo_metadata::Package,
+        config: &'a Config,
+        target: Option<&'a String>,
+    ) -> Job<'a> {
+        Job {
+            idx: 0,
+            package,
+            config,
+            features: package.features.keys().collect(),
+            toolchain: None,
+            target,
+            progress: None,
+        }
+    }
+
+    fn member_with_optional_dependency(
+        name: &str,
+        config: serde_json::Value,
+    ) -> eyre::Result<cargo_metadata::Package> {
+        let mut package = package(name, &["foo"], &["serde"])?;
+        package
+            .features
+            .insert("serde".to_string(), vec!["dep:serde".to_string()]);
+        let serde = &mut package.dependencies[0];
+        serde.optional = true;
+        serde.path = None;
+        package.metadata = serde_json::json!({ "cargo-feature-combinations": config });
+        Ok(package)
+    }
+
+    #[test]
+    fn workspace_skip_optional_dependencies() -> eyre::Result<()> {
+        let a = member_with_optional_dependency("a", serde_json::json!({}))?;
+        let b = member_with_optional_dependency("b", serde_json::json!({}))?;
+        let c = member_with_optional_dependency(
+            "c",
+            serde_json::json!({ "skip_optional_dependencies": false }),
+        )?;
+
+        let workspace = WorkspaceConfig::default();
+        sim_assert_eq!(
+            a.feature_matrix(&a.config(&workspace)?),
+            vec!["", "foo", "foo,serde", "serde"]
+        );
+
+        let workspace = WorkspaceConfig {
+            skip_optional_dependencies: true,
+            ..Default::default()
+        };
+        for member in [&a, &b] {
+            sim_assert_eq!(
+                member.feature_matrix(&member.config(&workspace)?),
+                vec!["", "foo"]
+            );
+        }
+        // package config takes precedence
+        sim_assert_eq!(
+            c.feature_matrix(&c.config(&workspace)?),
+            vec!["", "foo", "foo,serde", "serde"]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn mirror_features_of_workspace_member() -> eyre::Result<()> {
+        let core = package("core", &["alloc", "std"], &[])?;
+        let mut facade = package("facade", &["std"], &[])?;
+        facade.metadata = serde_json::json!({
+            "cargo-feature-combinations": { "mirror_features_from": "core" }
+        });
+        let mut metadata: cargo_metadata::Metadata = serde_json::from_value(serde_json::json!({
+            "packages": [core, facade],
+            "workspace_members": [core.id, facade.id],
+            "resolve": null,
+            "workspace_root": "/",
+            "target_directory": "/target",
+            "version": 1,
+        }))?;
+
+        let workspace = WorkspaceConfig::default();
+        mirror_features(&mut metadata, &workspace)?;
+        let matrices = metadata
+            .workspace_packages()
+            .into_iter()
+            .map(|package| {
+                let config = package.config(&workspace)?;
+                Ok((package.name.as_str(), package.feature_matrix(&config)))
+            })
+            .collect::<eyre::Result<Vec<_>>>()?;
+        let matrix: Vec<String> = ["", "alloc", "alloc,std", "std"].map(String::from).to_vec();
+        sim_assert_eq!(matrices, vec![("core", matrix.clone()), ("facade", matrix)]);
+
+        metadata.packages[1].metadata = serde_json::json!({
+            "cargo-feature-combinations": { "mirror_features_from": "unknown" }
+        });
+        assert!(mirror_features(&mut metadata, &workspace).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn config_file_overlay() -> eyre::Result<()> {
+        let dir = std::env::temp_dir().join(format!("fc-config-{}", std::process::id()));
+        std::fs::create_dir_all(&dir)?;
+        let config_file = serde_json::json!({
+            "exclude_features": ["bar"],
+            "max_combination_size": 1,
+            "matrix": { "rust": "nightly" },
+            "workspace": { "skip_optional_dependencies": true },
+        });
+        let json_path = dir.join("config.json");
+        std::fs::write(&json_path, config_file.to_string())?;
+        let toml_path = dir.join("config.toml");
+        std::fs::write(
+            &toml_path,
+            r#"
+            exclude_features = ["bar"]
+            max_combination_size = 1
+            matrix = { rust = "nightly" }
+
+            [workspace]
+            skip_optional_dependencies = true
+            "#,
+        )?;
+
+        let workspace = WorkspaceConfig::from_value(None, Some(&json_path))?;
+        let toml_workspace = WorkspaceConfig::from_value(None, Some(&toml_path))?;
+        std::fs::remove_dir_all(dir)?;
+        assert!(workspace.skip_optional_dependencies);
+        sim_assert_eq!(toml_workspace.package_overlay, workspace.package_overlay);
+        assert!(toml_workspace.skip_optional_dependencies);
+
+        let mut package = member_with_optional_dependency(
+            "a",
+            serde_json::json!({
+                "exclude_features": ["foo"],
+                "max_combination_size": 3,
+                "matrix": { "os": "linux", "rust": "stable" },
+            }),
+        )?;
+        package.features.insert("bar".to_string(), vec![]);
+        package.features.insert("baz".to_string(), vec![]);
+        let config = package.config(&workspace)?;
+
+        // lists are appended to, scalars replaced, objects merged
+        sim_assert_eq!(
+            config.exclude_features.iter().sorted().collect::<Vec<_>>(),
+            vec!["bar", "foo"]
+        );
+        sim_assert_eq!(config.max_combination_size, Some(1));
+        sim_assert_eq!(
+            serde_json::json!(config.matrix),
+            serde_json::json!({ "os": "linux", "rust": "nightly" })
+        );
+        sim_assert_eq!(package.feature_matrix(&config), vec!["", "baz"]);
+        Ok(())
+    }
+
+    #[test]
+    fn injected_args_precede_separator() -> eyre::Result<()> {
+        let package = package_with_features(&["a", "b"])?;
+        let config = Config::default();
+        let (options, cargo_args, extra_args) =
+            split_arguments(&["--color", "always", "check", "--", "--nocapture"])?;
+        let invocation = invocation(&cargo_args, &extra_args, &options);
+        let job = job(&package, &config, None);
+        sim_assert_eq!(
+            invocation.args(&job),
+            vec![
+                "check",
+                "--color",
+                "always",
+                "--manifest-path",
+                "/test/Cargo.toml",
+                "--no-default-features",
+                "--features=a,b",
+                "--",
+                "--nocapture",
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn feature_env_of_combination() -> eyre::Result<()> {
+        let config: Config = serde_json::from_value(serde_json::json!({
+            "feature_env": {
+                "grpc": { "PROTOC": "/usr/bin/protoc", "PROTOC_INCLUDE": "/usr/include" },
+                "tls": { "OPENSSL_DIR": "/opt/openssl" },
+                "vendored": { "PROTOC": "/vendor/protoc" },
+            }
+        }))?;
+        let features = ["grpc", "tls", "vendored", "other"].map(str::to_string);
+        let [grpc, tls, vendored, other] = &features;
+
+        sim_assert_eq!(
+            feature_env(&config, &[grpc, other, tls])?
+                .into_iter()
+                .collect::<Vec<_>>(),
+            vec![
+                ("OPENSSL_DIR".to_string(), "/opt/openssl".to_string()),
+                ("PROTOC".to_string(), "/usr/bin/protoc".to_string()),
+                ("PROTOC_INCLUDE".to_string(), "/usr/include".to_string()),
+            ]
+        );
+        assert!(feature_env(&config, &[other])?.is_empty());
+        let err = feature_env(&config, &[grpc, vendored]).unwrap_err();
+        sim_assert_eq!(
+            err.to_string(),
+            r#"features grpc and vendored set PROTOC to different values ("/usr/bin/protoc" and "/vendor/protoc")"#
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn unknown_config_keys() -> eyre::Result<()> {
+        let config = serde_json::json!({
+            "exclude_feature": ["foo"],
+            "denylist": ["bar"],
+            "max_combination_size": 2,
+            "matrix": { "os": "linux" },
+        });
+        sim_assert_eq!(
+            crate::config::unknown_keys(&config),
+            vec!["exclude_feature"]
+        );
+        let config = serde_json::to_value(Config::default())?;
+        sim_assert_eq!(crate::config::unknown_keys(&config), Vec::<&str>::new());
+
+        let package = member_with_optional_dependency(
+            "a",
+            serde_json::json!({ "exclude_feature": ["foo"], "pairwize": true }),
+        )?;
+        // unknown keys only produce a warning by default
+        let config = package.config(&WorkspaceConfig::default())?;
+        assert!(config.exclude_features.is_empty());
+        let strict = WorkspaceConfig {
+            strict: true,
+            ..Default::default()
+        };
+        let err = package.config(&strict).unwrap_err();
+        sim_assert_eq!(
+            err.to_string(),
+            "unknown keys `exclude_feature`, `pairwize` in the config of package a"
+        );
+
+        let (options, _) = parse_arguments(["--strict-config", "check"].map(str::to_string))?;
+        assert!(options.strict_config);
+        Ok(())
+    }
+
+    #[test]
+    fn print_config_folds_denylist() -> eyre::Result<()> {
+        let package = member_with_optional_dependency(
+            "a",
+            serde_json::json!({ "denylist": ["foo"], "max_combination_size": 2 }),
+        )?;
+        let workspace = WorkspaceConfig {
+            skip_optional_dependencies: true,
+            ..Default::default()
+        };
+        let (options, _) =
+            parse_arguments(["--print-config", "--exclude-feature", "serde"].map(str::to_string))?;
+        assert!(matches!(options.command, Some(Command::PrintConfig)));
+        let config = resolve_config(&package, &workspace, &options)?;
+        let json = resolved_config_json(&[(&package, config)], &workspace)?;
+
+        let config = &json["packages"]["a"];
+        assert!(config.get("denylist").is_none());
+        let mut exclude_features: Vec<_> = config["exclude_features"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .filter_map(serde_json::Value::as_str)
+            .collect();
+        exclude_features.sort_unstable();
+        sim_assert_eq!(exclude_features, vec!["foo", "serde"]);
+        sim_assert_eq!(config["max_combination_size"], 2);
+        // falls back to the workspace config
+        sim_assert_eq!(config["skip_optional_dependencies"], true);
+        sim_assert_eq!(json["workspace"]["skip_optional_dependencies"], true);
+        Ok(())
+    }
+
+    #[test]
+    fn workspace_matrix_merged_under_package_matrix() -> eyre::Result<()> {
+        let package = member_with_optional_dependency(
+            "a",
+            serde_json::json!({
+                "matrix": {
+                    "os": ["windows"],
+                    "rust": "nightly",
+                    "env": { "RUSTFLAGS": "-Dwarnings" },
+                },
+            }),
+        )?;
+        let workspace: WorkspaceConfig = serde_json::from_value(serde_json::json!({
+            "matrix": {
+                "os": ["ubuntu", "macos"],
+                "rust": "stable",
+                "env": { "CI": "true", "RUSTFLAGS": "" },
+            },
+        }))?;
+        let config = package.config(&workspace)?;
+        sim_assert_eq!(
+            serde_json::json!(config.matrix),
+            serde_json::json!({
+                "os": ["windows"],
+                "rust": "nightly",
+                "env": { "CI": "true", "RUSTFLAGS": "-Dwarnings" },
+            })
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn parse_include_and_exclude_features() -> eyre::Result<()> {
+        let args = [
+            "--exclude-feature",
+            "a",
+            "check",
+            "--include-feature=b,",
+            "--exclude-feature=c,d",
+            "--include-feature",
+            ",",
+            "--all-targets",
+        ];
+        let (options, args) = parse_arguments(args.map(str::to_string))?;
+        sim_assert_eq!(
+            options.exclude_features,
+            HashSet::from(["a", "c", "d"].map(str::to_string))
+        );
+        sim_assert_eq!(options.include_features, HashSet::from(["b".to_string()]));
+        sim_assert_eq!(args.0, vec!["check", "--all-targets"]);
+        Ok(())
+    }
+
+    #[test]
+    fn parse_cargo_features_as_include_features() -> eyre::Result<()> {
+        let args = [
+            "check",
+            "--features",
+            "a,b",
+            "-F=c",
+            "--features=d e",
+            "--",
+            "--features",
+            "f",
+            "-F",
+            "g",
+        ];
+        let (options, args) = parse_arguments(args.map(str::to_string))?;
+        sim_assert_eq!(
+            options.include_features.iter().sorted().collect::<Vec<_>>(),
+            vec!["a", "b", "c", "d", "e"]
+        );
+        sim_assert_eq!(options.cargo_features, vec!["a", "b", "c", "d", "e"]);
+        sim_assert_eq!(args.0, vec!["check", "--", "--features", "f", "-F", "g"]);
+
+        // features of dependencies and unknown features are passed to cargo
+        let (options, _) =
+            parse_arguments(["check", "-F", "a,serde/std,unknown"].map(str::to_string))?;
+        assert!(!options.include_features.contains("serde/std"));
+        let package = package_with_features(&["a", "b"])?;
+        let config = resolve_config(&package, &WorkspaceConfig::default(), &options)?;
+        let features = package.feature_combinations(&config);
+        sim_assert_eq!(features.len(), 2);
+        sim_assert_eq!(
+            injected_feature_args(&["check".to_string()], &features[1], false, &options),
+            vec!["--no-default-features", "--features=a,b,serde/std,unknown"]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn cargo_subcommands() -> eyre::Result<()> {
+        let subcommand = |args: &[&str]| -> eyre::Result<CargoSubcommand> {
+            let (_, args) = parse_arguments(args.iter().map(ToString::to_string))?;
+            Ok(cargo_subcommand(&args))
+        };
+        sim_assert_eq!(
+            subcommand(&["check", "--all-targets"])?,
+            CargoSubcommand::Check
+        );
+        sim_assert_eq!(
+            subcommand(&["--pairwise", "build"])?,
+            CargoSubcommand::Build
+        );
+        sim_assert_eq!(subcommand(&["clippy"])?, CargoSubcommand::Clippy);
+        sim_assert_eq!(subcommand(&["udeps"])?, CargoSubcommand::Other);
+        sim_assert_eq!(subcommand(&["bench"])?, CargoSubcommand::Bench);
+        sim_assert_eq!(subcommand(&["nextest", "run"])?, CargoSubcommand::Nextest);
+        sim_assert_eq!(subcommand(&["nextest", "list"])?, CargoSubcommand::Nextest);
+        sim_assert_eq!(subcommand(&["nextest", "archive"])?, CargoSubcommand::Other);
+        sim_assert_eq!(subcommand(&["fix", "--allow-dirty"])?, CargoSubcommand::Fix);
+        assert!(CargoSubcommand::Fix.modifies_sources());
+        sim_assert_eq!(CargoSubcommand::Fix.label(), "Fixing");
+        sim_assert_eq!(subcommand(&["fmt"])?, CargoSubcommand::Fmt);
+        // only the first positional argument is the subcommand
+        sim_assert_eq!(
+            subcommand(&["udeps", "--package", "check"])?,
+            CargoSubcommand::Other
+        );
+        sim_assert_eq!(
+            subcommand(&["--color", "never", "+nightly", "test", "--", "build"])?,
+            CargoSubcommand::Test
+        );
+        sim_assert_eq!(
+            subcommand(&["test", "--test", "nextest", "run"])?,
+            CargoSubcommand::Test
+        );
+        sim_assert_eq!(
+            subcommand(&["nextest", "archive", "--", "run"])?,
+            CargoSubcommand::Other
+        );
+        sim_assert_eq!(
+            subcommand(&["nextest", "--no-capture", "run"])?,
+            CargoSubcommand::Nextest
+        );
+        assert!(!CargoSubcommand::Check.modifies_sources());
+        Ok(())
+    }
+
+    #[test]
+    fn parse_color() -> eyre::Result<()> {
+        let args = ["--color", "never", "test", "--", "--color", "always"];
+        let (options, args) = parse_arguments(args.map(str::to_string))?;
+        sim_assert_eq!(options.color, ColorMode::Never);
+        sim_assert_eq!(args.0, vec!["test", "--", "--color", "always"]);
+        sim_assert_eq!(
+            ColorMode::Never.color_choice(),
+            termcolor::ColorChoice::Never
+        );
+        sim_assert_eq!(ColorMode::Never.cargo_color(), "never");
+        sim_assert_eq!(ColorMode::Always.cargo_color(), "always");
+        assert!("sometimes".parse::<ColorMode>().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn parse_arguments_stops_at_separator() -> eyre::Result<()> {
+        let args = [
+            "--silent",
+            "test",
+            "--",
+            "--silent",
+            "--fail-fast",
+            "matrix",
+        ];
+        let (options, args) = parse_arguments(args.map(str::to_string))?;
+        assert!(options.silent);
+        assert!(!options.fail_fast);
+        assert!(options.command.is_none());
+        sim_assert_eq!(
+            args.0,
+            vec!["test", "--", "--silent", "--fail-fast", "matrix"]
+        );
+
+        let args = ["test", "--", "--silent", "--exclude-feature", "a"];
+        let (options, args) = parse_arguments(args.map(str::to_string))?;
+        assert!(!options.silent);
+        assert!(options.exclude_features.is_empty());
+        sim_assert_eq!(
+            args.0,
+            vec!["test", "--", "--silent", "--exclude-feature", "a"]
+        );
+        sim_assert_eq!(cargo_subcommand(&args), CargoSubcommand::Test);
+
+        let (_, args) = parse_arguments(["test", "--", "build"].map(str::to_string))?;
+        sim_assert_eq!(cargo_subcommand(&args), CargoSubcommand::Test);
+        Ok(())
+    }
+
+    #[test]
+    fn parse_quiet_levels() -> eyre::Result<()> {
+        let (options, args) = parse_arguments(["-q", "check"].map(str::to_string))?;
+        sim_assert_eq!((options.quiet, options.silent), (1, true));
+        sim_assert_eq!(args.0, vec!["check"]);
+
+        let args = ["--quiet", "-q", "test", "-q", "--", "-q"];
+        let (options, args) = parse_arguments(args.map(str::to_string))?;
+        sim_assert_eq!((options.quiet, options.silent), (2, true));
+        // quiet flags after the subcommand are passed to cargo
+        sim_assert_eq!(args.0, vec!["test", "-q", "--", "-q"]);
+        let (options, args) = parse_arguments(["test", "-q"].map(str::to_string))?;
+        sim_assert_eq!((options.quiet, options.silent), (0, false));
+        sim_assert_eq!(args.0, vec!["test", "-q"]);
+
+        let (options, _) = parse_arguments(["-qq", "check"].map(str::to_string))?;
+        sim_assert_eq!(options.quiet, 2);
+        let (options, args) = parse_arguments(["-qqq", "-q", "check"].map(str::to_string))?;
+        sim_assert_eq!(options.quiet, 2);
+        sim_assert_eq!(args.0, vec!["check"]);
+
+        let (options, _) = parse_arguments(["check"].map(str::to_string))?;
+        sim_assert_eq!((options.quiet, options.silent), (0, false));
+        Ok(())
+    }
+
+    #[test]
+    fn parse_config_file() -> eyre::Result<()> {
+        let args = [
+            "--fc-config",
+            "fc.json",
+            "check",
+            "--config",
+            "build.jobs=2",
+        ];
+        let (options, args) = parse_arguments(args.map(str::to_string))?;
+        sim_assert_eq!(
+            options.config_file,
+            Some(std::path::PathBuf::from("fc.json"))
+        );
+        sim_assert_eq!(args.0, vec!["check", "--config", "build.jobs=2"]);
+
+        // files given with --config are cargo config files, whatever their extension
+        let args = ["--fc-config", "fc.toml", "check", "--config", "cargo.toml"];
+        let (options, args) = parse_arguments(args.map(str::to_string))?;
+        sim_assert_eq!(
+            options.config_file,
+            Some(std::path::PathBuf::from("fc.toml"))
+        );
+        sim_assert_eq!(args.0, vec!["check", "--config", "cargo.toml"]);
+        Ok(())
+    }
+
+    #[test]
+    fn package_spec_disambiguates_versions() -> eyre::Result<()> {
+        let old = package_with_features(&[])?;
+        let mut new = package_with_features(&[])?;
+        new.version = "1.2.0".parse()?;
+        new.id.repr = "path+file:///nested/test#1.2.0".to_string();
+        new.manifest_path = "/nested/test/Cargo.toml".into();
+
+        let selected = |spec: &str| {
+            [&old, &new]
+                .into_iter()
+                .filter(|package| package_matches_spec(package, spec))
+                .map(|package| package.version.to_string())
+                .collect::<Vec<_>>()
+        };
+        sim_assert_eq!(selected("test"), vec!["0.1.0", "1.2.0"]);
+        sim_assert_eq!(selected("test@0.1.0"), vec!["0.1.0"]);
+        sim_assert_eq!(selected("test@1"), vec!["1.2.0"]);
+        sim_assert_eq!(selected("test@1.2"), vec!["1.2.0"]);
+        sim_assert_eq!(selected("test@1.20"), Vec::<String>::new());
+        sim_assert_eq!(selected("path+file:///nested/test#1.2.0"), vec!["1.2.0"]);
+        sim_assert_eq!(selected("other"), Vec::<String>::new());
+        Ok(())
+    }
+
+    #[test]
+    fn progress_is_padded_to_total() {
+        let progress = |current, total| Progress { current, total }.to_string();
+        sim_assert_eq!(progress(37, 200), "[  37/200 ]");
+        sim_assert_eq!(progress(200, 200), "[ 200/200 ]");
+        sim_assert_eq!(progress(1, 8), "[ 1/8 ]");
+    }
+
+    #[test]
+    fn exclude_packages_by_glob() -> eyre::Result<()> {
+        let packages = ["internal-a", "internal-b", "public-a"]
+            .into_iter()
+            .map(|name| package(name, &[], &[]))
+            .collect::<eyre::Result<Vec<_>>>()?;
+        let kept = |patterns: &[&str]| {
+            let patterns: Vec<String> = patterns.iter().map(ToString::to_string).collect();
+            packages
+                .iter()
+                .filter(|package| !is_package_excluded(package, &patterns))
+                .map(|package| package.name.as_str())
+                .collect::<Vec<_>>()
+        };
+        sim_assert_eq!(kept(&["internal-*"]), vec!["public-a"]);
+        sim_assert_eq!(kept(&["internal-a"]), vec!["internal-b", "public-a"]);
+        sim_assert_eq!(
+            kept(&["internal"]),
+            vec!["internal-a", "internal-b", "public-a"]
+        );
+        sim_assert_eq!(kept(&["*-a"]), vec!["internal-b"]);
+        sim_assert_eq!(kept(&["in*rn*?-b"]), vec!["internal-a", "public-a"]);
+        sim_assert_eq!(kept(&[]), vec!["internal-a", "internal-b", "public-a"]);
+        Ok(())
+    }
+
+    #[test]
+    fn only_packages_with_lib_or_bin_target() -> eyre::Result<()> {
+        let packages = [
+            ("lib-only", vec![vec!["lib"]]),
+            ("bin-only", vec![vec!["bin"]]),
+            ("lib-and-bin", vec![vec!["lib"], vec!["bin"]]),
+            ("proc-macro", vec![vec!["proc-macro"]]),
+            ("cdylib", vec![vec!["cdylib", "rlib"]]),
+        ]
+        .into_iter()
+        .map(|(name, kinds)| {
+            let mut package = package(name, &[], &[])?;
+            package.targets = kinds
+                .into_iter()
+                .map(|kind| {
+                    serde_json::from_value(serde_json::json!({
+                        "name": name,
+                        "kind": kind,
+                        "crate_types": kind,
+                        "src_path": format!("/ws/{name}/src/lib.rs"),
+                    }))
+                })
+                .collect::<Result<_, _>>()?;
+            Ok(package)
+        })
+        .collect::<eyre::Result<Vec<cargo_metadata::Package>>>()?;
+        let kept = |lib: bool, bin: bool| {
+            let options = Options {
+                only_packages_with_lib_target: lib,
+                only_packages_with_bin_target: bin,
+                ..Options::default()
+            };
+            packages
+                .iter()
+                .filter(|package| has_selected_targets(package, &options))
+                .map(|package| package.name.as_str())
+                .collect::<Vec<_>>()
+        };
+        sim_assert_eq!(
+            kept(false, false),
+            vec![
+                "lib-only",
+                "bin-only",
+                "lib-and-bin",
+                "proc-macro",
+                "cdylib"
+            ]
+        );
+        sim_assert_eq!(
+            kept(true, false),
+            vec!["lib-only", "lib-and-bin", "proc-macro", "cdylib"]
+        );
+        sim_assert_eq!(kept(false, true), vec!["bin-only", "lib-and-bin"]);
+        sim_assert_eq!(kept(true, true), vec!["lib-and-bin"]);
+        Ok(())
+    }
+
+    #[test]
+    fn changed_files_to_packages() -> eyre::Result<()> {
+        let in_dir = |name: &str, dir: &str| -> eyre::Result<_> {
+            let mut package = package(name, &[], &[])?;
+            package.manifest_path = format!("{dir}/Cargo.toml").into();
+            Ok(package)
+        };
+        let packages: Vec<cargo_metadata::Package> = vec![
+            in_dir("root", "/ws")?,
+            package("core", &[], &[])?,
+            in_dir("core-macros", "/ws/core/macros")?,
+            package("api", &[], &["core"])?,
+            package("cli", &[], &["api"])?,
+            package("other", &[], &[])?,
+        ];
+        let packages: Vec<_> = packages.iter().collect();
+        let changed = |files: &[&str], include_dependents: bool| {
+            let files: Vec<std::path::PathBuf> = files.iter().map(Into::into).collect();
+            changed_packages(&packages, &files, include_dependents)
+                .into_iter()
+                .sorted()
+                .collect::<Vec<_>>()
+        };
+        sim_assert_eq!(changed(&["/ws/core/src/lib.rs"], false), vec!["core"]);
+        // files belong to the innermost package
+        sim_assert_eq!(
+            changed(&["/ws/core/macros/src/lib.rs", "/ws/README.md"], false),
+            vec!["core-macros", "root"]
+        );
+        // a package directory is not a prefix of its sibling with a longer name
+        sim_assert_eq!(changed(&["/ws/core-macros/lib.rs"], false), vec!["root"]);
+        sim_assert_eq!(changed(&["/elsewhere/lib.rs"], false), Vec::<String>::new());
+        sim_assert_eq!(
+            changed(&["/ws/core/src/lib.rs"], true),
+            vec!["api", "cli", "core"]
+        );
+        sim_assert_eq!(changed(&["/ws/api/src/lib.rs"], true), vec!["api", "cli"]);
+        Ok(())
+    }
+
+    #[test]
+    fn package_spec_glob() -> eyre::Result<()> {
+        let packages = ["api-a", "api-b", "cli"]
+            .into_iter()
+            .map(|name| package(name, &[], &[]))
+            .collect::<eyre::Result<Vec<_>>>()?;
+        let selected = |spec: &str| {
+            packages
+                .iter()
+                .filter(|package| package_matches_spec(package, spec))
+                .map(|package| package.name.as_str())
+                .collect::<Vec<_>>()
+        };
+        sim_assert_eq!(selected("api-*"), vec!["api-a", "api-b"]);
+        sim_assert_eq!(selected("api-*@0.1"), vec!["api-a", "api-b"]);
+        sim_assert_eq!(selected("api"), Vec::<&str>::new());
+        sim_assert_eq!(selected("cli"), vec!["cli"]);
+        Ok(())
+    }
+
+    #[test]
+    fn cross_product_of_combinations_and_targets() {
+        let combinations = || [vec![], vec!["a"], vec!["a", "b"]].into_iter();
+        let expand = |targets: &[String]| {
+            cross_product(combinations(), targets)
+                .map(|(features, target)| (features.join(","), target.cloned()))
+                .collect::<Vec<_>>()
+        };
+        sim_assert_eq!(
+            expand(&[]),
+            vec![
+                (String::new(), None),
+                ("a".to_string(), None),
+                ("a,b".to_string(), None),
+            ]
+        );
+        let gnu = "x86_64-unknown-linux-gnu".to_string();
+        let musl = "x86_64-unknown-linux-musl".to_string();
+        sim_assert_eq!(
+            expand(&[gnu.clone(), musl.clone()]),
+            vec![
+                (String::new(), Some(gnu.clone())),
+                (String::new(), Some(musl.clone())),
+                ("a".to_string(), Some(gnu.clone())),
+                ("a".to_string(), Some(musl.clone())),
+                ("a,b".to_string(), Some(gnu)),
+                ("a,b".to_string(), Some(musl)),
+            ]
+        );
+    }
+
+    #[test]
+    fn cross_product_of_combinations_and_toolchains() {
+        let combinations = || [vec![], vec!["a"]].into_iter();
+        let toolchains = ["stable".to_string(), "nightly".to_string()];
+        let targets = ["wasm32-unknown-unknown".to_string()];
+        let expand = |targets: &[String]| {
+            cross_product(cross_product(combinations(), targets), &toolchains)
+                .map(|((features, target), toolchain)| {
+                    (features.join(","), toolchain.cloned(), target.cloned())
+                })
+                .collect::<Vec<_>>()
+        };
+        let some = |value: &str| Some(value.to_string());
+        sim_assert_eq!(
+            expand(&[]),
+            vec![
+                (String::new(), some("stable"), None),
+                (String::new(), some("nightly"), None),
+                ("a".to_string(), some("stable"), None),
+                ("a".to_string(), some("nightly"), None),
+            ]
+        );
+        let wasm = some("wasm32-unknown-unknown");
+        sim_assert_eq!(
+            expand(&targets),
+            vec![
+                (String::new(), some("stable"), wasm.clone()),
+                (String::new(), some("nightly"), wasm.clone()),
+                ("a".to_string(), some("stable"), wasm.clone()),
+                ("a".to_string(), some("nightly"), wasm),
+            ]
+        );
+    }
+
+    #[test]
+    fn errors_only_rustflags_allow_warnings_last() {
+        let compose = |rustflags: Option<&str>, encoded: Option<&str>| {
+            let (key, flags) =
+                errors_only_rustflags(rustflags.map(Into::into), encoded.map(Into::into));
+            (key, flags.to_string_lossy().into_owned())
+        };
+        sim_assert_eq!(compose(None, None), ("RUSTFLAGS", "-Awarnings".to_string()));
+        sim_assert_eq!(
+            compose(Some(""), None),
+            ("RUSTFLAGS", "-Awarnings".to_string())
+        );
+        sim_assert_eq!(
+            compose(Some("-Dwarnings -Ctarget-cpu=native"), None),
+            (
+                "RUSTFLAGS",
+                "-Dwarnings -Ctarget-cpu=native -Awarnings".to_string()
+            )
+        );
+        sim_assert_eq!(
+            compose(Some("-Dwarnings"), Some("-Wwarnings\x1f--cfg=foo")),
+            (
+                "CARGO_ENCODED_RUSTFLAGS",
+                "-Wwarnings\x1f--cfg=foo\x1f-Awarnings".to_string()
+            )
+        );
+    }
+
+    #[test]
+    fn injected_feature_args_do_not_conflict() -> eyre::Result<()> {
+        let inject = |cargo_args: &[&str], default_features, extra_args: &[&str]| {
+            let args = ["check"].iter().chain(extra_args).map(ToString::to_string);
+            let (options, _) = parse_arguments(args)?;
+            let cargo_args: Vec<String> = cargo_args.iter().map(ToString::to_string).collect();
+            Ok::<_, eyre::Report>(injected_feature_args(
+                &cargo_args,
+                &["a", "b"],
+                default_features,
+                &options,
+            ))
+        };
+        sim_assert_eq!(
+            inject(&["check"], false, &[])?,
+            vec!["--no-default-features", "--features=a,b"]
+        );
+        sim_assert_eq!(inject(&["check"], true, &[])?, vec!["--features=a,b"]);
+        sim_assert_eq!(
+            inject(&["check", "--no-default-features"], false, &[])?,
+            vec!["--features=a,b"]
+        );
+        // --all-features selects the feature combination and never reaches cargo
+        let (options, args) = parse_arguments(["check", "--all-features"].map(str::to_string))?;
+        sim_assert_eq!(options.quick_mode, Some(QuickMode::AllFeatures));
+        sim_assert_eq!(args.0, vec!["check"]);
+        sim_assert_eq!(
+            inject(&["check"], false, &["--no-feature-args"])?,
+            Vec::<String>::new()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn cache_keys_are_stable() -> eyre::Result<()> {
+        let (a, b) = ("a".to_string(), "b".to_string());
+        let args = ["check", "--no-default-features", "--features=a,b"].map(str::to_string);
+        let env = ["RUSTFLAGS=None".to_string()];
+        let base = crate::cache::Key {
+            package_id: "path+file:///test#0.1.0",
+            features: &[&a, &b],
+            args: &args,
+            env: &env,
+            rustc_version: "rustc 1.85.0 (4d91de4e4 2025-02-17)",
+            source_digest: 42,
+        };
+        let key = base.digest();
+        sim_assert_eq!(base.digest(), key);
+        sim_assert_eq!(key.len(), 16);
+        let features: &[&String] = &[&a];
+        assert_ne!(crate::cache::Key { features, ..base }.digest(), key);
+        assert_ne!(
+            crate::cache::Key {
+                args: &args[..2],
+                ..base
+            }
+            .digest(),
+            key
+        );
+        assert_ne!(crate::cache::Key { env: &[], ..base }.digest(), key);
+        assert_ne!(
+            crate::cache::Key {
+                rustc_version: "rustc 1.86.0",
+                ..base
+            }
+            .digest(),
+            key
+        );
+        assert_ne!(
+            crate::cache::Key {
+                source_digest: 43,
+                ..base
+            }
+            .digest(),
+            key
+        );
+        // values are delimited, so moving a feature into the arguments changes the key
+        let moved: Vec<String> = std::iter::once(b.clone()).chain(args.clone()).collect();
+        let features: &[&String] = &[&a];
+        assert_ne!(
+            crate::cache::Key {
+                features,
+                args: &moved,
+                ..base
+            }
+            .digest(),
+            key
+        );
+
+        let dir = std::env::temp_dir().join(format!("fc-cache-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("src"))?;
+        std::fs::create_dir_all(dir.join("target"))?;
+        std::fs::write(dir.join("src").join("lib.rs"), "")?;
+        let digest = || crate::cache::source_digest([dir.as_path()]);
+        let before = digest()?;
+        // build output does not invalidate the cache
+        std::fs::write(dir.join("target").join("out"), "")?;
+        sim_assert_eq!(digest()?, before);
+        std::fs::write(dir.join("src").join("lib.rs"), "fn changed() {}")?;
+        assert_ne!(digest()?, before);
+
+        // the config file is read when running, so its contents are part of the key
+        let options = Options {
+            config_file: Some(dir.join("fc.json")),
+            ..Options::default()
+        };
+        std::fs::write(dir.join("fc.json"), r#"{"exclude_features": ["a"]}"#)?;
+        let before = config_file_digest(&options)?;
+        sim_assert_eq!(config_file_digest(&options)?, before);
+        std::fs::write(dir.join("fc.json"), r#"{"exclude_features": ["b"]}"#)?;
+        assert_ne!(config_file_digest(&options)?, before);
+
+        let path = dir.join("fc").join("cache.json");
+        let mut cache = crate::cache::Cache::read(&path)?;
+        assert!(!cache.passed("a"));
+        cache.record("a".to_string(), true);
+        cache.record("b".to_string(), true);
+        cache.record("b".to_string(), false);
+        cache.write(&path)?;
+        let cache = crate::cache::Cache::read(&path)?;
+        assert!(cache.passed("a"));
+        assert!(!cache.passed("b"));
+        std::fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn include_feature_sets_and_excludes() -> eyre::Result<()> {
+        let package = package_with_features(&["a", "b", "c"])?;
+        let combinations = |respect_excludes: bool| -> eyre::Result<Vec<String>> {
+            let config: Config = serde_json::from_value(serde_json::json!({
+                "exclude_features": ["c"],
+                "max_combination_size": 1,
+                "include_feature_sets": [["a", "c"], ["a", "b", "missing"]],
+                "include_sets_respect_excludes": respect_excludes,
+            }))?;
+            Ok(package
+                .feature_combinations_iter(&config)
+                .map(|set| set.iter().join(","))
+                .collect())
+        };
+        // excluded features are included in included sets by default
+        sim_assert_eq!(combinations(false)?, vec!["", "a", "b", "a,c", "a,b"]);
+        // or dropped, which may make an included set an already generated one
+        sim_assert_eq!(combinations(true)?, vec!["", "b", "a", "a,b"]);
+        Ok(())
+    }
+
+    #[test]
+    fn explain_reports_rule_of_dropped_sets() -> eyre::Result<()> {
+        let package = package_with_features(&["a", "b", "c", "d"])?;
+        let config: Config = serde_json::from_value(serde_json::json!({
+            "exclude_features": ["d"],
+            "skip_feature_sets": [["a", "b"]],
+            "mutually_exclusive": [["b", "c"]],
+            "include_feature_sets": [["a", "b", "c"]],
+        }))?;
+        let explanations = explain_feature_combinations(&package, &config)?;
+        let decision = |features: &str| {
+            explanations
+                .iter()
+                .find(|e| e.features.iter().join(",") == features)
+                .map(|e| e.decision.clone())
+        };
+        sim_assert_eq!(decision("a"), Some(Decision::Kept));
+        sim_assert_eq!(decision("a,c"), Some(Decision::Kept));
+        sim_assert_eq!(
+            decision("a,b"),
+            Some(Decision::SkipFeatureSet(vec![
+                "a".to_string(),
+                "b".to_string()
+            ]))
+        );
+        sim_assert_eq!(
+            decision("b,c"),
+            Some(Decision::MutuallyExclusive(vec![
+                "b".to_string(),
+                "c".to_string()
+            ]))
+        );
+        sim_assert_eq!(
+            decision("a,d"),
+            Some(Decision::ExcludedFeature("d".to_string()))
+        );
+        sim_assert_eq!(decision("a,b,c"), Some(Decision::Included));
+        sim_assert_eq!(explanations.len(), 16);
+        let kept = explanations
+            .iter()
+            .filter(|e| matches!(e.decision, Decision::Kept | Decision::Included))
+            .count();
+        sim_assert_eq!(kept, package.feature_combinations(&config).len());
+        Ok(())
+    }
+
+    #[test]
+    fn feature_combination_order() -> eyre::Result<()> {
+        let package = package_with_features(&["a", "b", "c"])?;
+        let matrix = |order: &str| -> eyre::Result<Vec<String>> {
+            let config: Config = serde_json::from_value(serde_json::json!({ "order": order }))?;
+            Ok(package.feature_matrix(&config))
+        };
+        sim_assert_eq!(
+            matrix("lexicographic")?,
+            vec!["", "a", "a,b", "a,b,c", "a,c", "b", "b,c", "c"]
+        );
+        sim_assert_eq!(
+            matrix("size-then-lex")?,
+            vec!["", "a", "b", "c", "a,b", "a,c", "b,c", "a,b,c"]
+        );
+        let (options, _) = parse_arguments(["--order", "size-then-lex"].map(str::to_string))?;
+        sim_assert_eq!(options.order, Some(Order::SizeThenLex));
+        assert!(parse_arguments(["--order", "random"].map(str::to_string)).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn batch_feature_combinations() -> eyre::Result<()> {
+        let combinations = [
+            ("a", ""),
+            ("a", "x"),
+            ("a", "x,y"),
+            ("a", "y"),
+            ("a", "z"),
+            ("b", ""),
+            ("b", "x"),
+        ];
+        let batched = batches(combinations, 2, |a, b| a.0 == b.0);
+        sim_assert_eq!(
+            batched,
+            vec![
+                vec![("a", ""), ("a", "x")],
+                vec![("a", "x,y"), ("a", "y")],
+                vec![("a", "z")],
+                vec![("b", ""), ("b", "x")],
+            ]
+        );
+        sim_assert_eq!(batches(combinations, 10, |a, b| a.0 == b.0).len(), 2);
+        sim_assert_eq!(batches(combinations, 1, |_, _| true).len(), 7);
+
+        let rows = combinations.into_iter().map(|(name, features)| {
+            serde_json::json!({
+                "name": name,
+                "features": features,
+                "display_name": format!("{name} [{features}]"),
+            })
+        });
+        sim_assert_eq!(
+            batch_rows(rows, 3),
+            vec![
+                serde_json::json!({
+                    "name": "a",
+                    "features": ["", "x", "x,y"],
+                    "display_name": "a [], a [x], a [x,y]",
+                }),
+                serde_json::json!({
+                    "name": "a",
+                    "features": ["y", "z"],
+                    "display_name": "a [y], a [z]",
+                }),
+                serde_json::json!({
+                    "name": "b",
+                    "features": ["", "x"],
+                    "display_name": "b [], b [x]",
+                }),
+            ]
+        );
+
+        let args = ["matrix", "--batch", "3", "--feature-sets", r#"["y,x", ""]"#];
+        let (options, _) = parse_arguments(args.map(str::to_string))?;
+        sim_assert_eq!(options.batch, Some(3));
+        sim_assert_eq!(
+            options.feature_sets,
+            Some(vec![vec!["x".to_string(), "y".to_string()], vec![]])
+        );
+        assert!(parse_arguments(["matrix", "--batch", "0"].map(str::to_string)).is_err());
+        assert!(parse_arguments(["--feature-sets", "x,y"].map(str::to_string)).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn name_template_substitution() -> eyre::Result<()> {
+        sim_assert_eq!(
+            render_name_template("{package} [{features}]", "api", "serde,std", 3, "none"),
+            "api [serde,std]"
+        );
+        sim_assert_eq!(
+            render_name_template("{package} ({feature_count})", "api", "serde,std", 3, "none"),
+            "api (2)"
+        );
+        sim_assert_eq!(
+            render_name_template("#{index} {package} [{features}]", "api", "", 0, "none"),
+            "#0 api [none]"
+        );
+        sim_assert_eq!(
+            render_name_template("{features} ({feature_count})", "api", "", 0, "default"),
+            "default (0)"
+        );
+        sim_assert_eq!(
+            render_name_template("{unknown} {package}", "api", "std", 1, "none"),
+            "{unknown} api"
+        );
+
+        let (options, _) = parse_arguments(
+            ["matrix", "--name-template", "{package}: {features}"].map(str::to_string),
+        )?;
+        let package = package_with_features(&["std"])?;
+        let config = resolve_config(&package, &WorkspaceConfig::default(), &options)?;
+        sim_assert_eq!(
+            config.name_template.as_deref(),
+            Some("{package}: {features}")
+        );
+        sim_assert_eq!(config.empty_features_name, None);
+        Ok(())
+    }
+
+    #[test]
+    fn parse_feature_policy_prefixes() -> eyre::Result<()> {
+        let policy = "# generated feature policy
++std
+-nightly # unstable
+
+  + alloc
+-full
+";
+        let (include_features, exclude_features) = parse_feature_policy(policy)?;
+        sim_assert_eq!(include_features, vec!["std", "alloc"]);
+        sim_assert_eq!(exclude_features, vec!["nightly", "full"]);
+        assert!(parse_feature_policy("std").is_err());
+        assert!(parse_feature_policy("äöü").is_err());
+        assert!(parse_feature_policy("+").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn package_features_apply_to_their_package() -> eyre::Result<()> {
+        let args = [
+            "--package-features",
+            "a:std,serde",
+            "--package-features=b:alloc",
+            "--package-features",
+            "a:",
+            "check",
+        ];
+        let (options, args) = parse_arguments(args.map(str::to_string))?;
+        sim_assert_eq!(args.0, vec!["check"]);
+        sim_assert_eq!(
+            options.package_features.get("a"),
+            Some(&vec!["std".to_string(), "serde".to_string()])
+        );
+        sim_assert_eq!(
+            options.package_features.get("b"),
+            Some(&vec!["alloc".to_string()])
+        );
+        assert!(parse_arguments(["--package-features", "a"].map(str::to_string)).is_err());
+
+        let workspace = WorkspaceConfig::default();
+        let mut a = package_with_features(&["std", "serde", "alloc"])?;
+        a.name = "a".to_string();
+        let mut c = a.clone();
+        c.name = "c".to_string();
+        let included = |package| -> eyre::Result<Vec<String>> {
+            let config = resolve_config(package, &workspace, &options)?;
+            Ok(config.include_features.into_iter().sorted().collect())
+        };
+        sim_assert_eq!(included(&a)?, vec!["serde", "std"]);
+        sim_assert_eq!(included(&c)?, Vec::<String>::new());
+        Ok(())
+    }
+
+    #[test]
+    fn env_rules_precedence() -> eyre::Result<()> {
+        let env = HashMap::from([
+            ("FC_EXCLUDE_FEATURES", "a, b,"),
+            ("FC_INCLUDE_FEATURES", "c,d"),
+            ("FC_EXCLUDE_PACKAGES", "internal-*,tools"),
+        ]);
+        let args = ["--include-feature", "b", "--exclude-feature", "d", "check"];
+        let (options, _) =
+            parse_arguments_with_env(args.map(str::to_string), io::empty(), |key| {
+                env.get(key).map(ToString::to_string)
+            })?;
+        // the command line takes precedence over the environment
+        sim_assert_eq!(
+            options.exclude_features.iter().sorted().collect::<Vec<_>>(),
+            vec!["a", "d"]
+        );
+        sim_assert_eq!(
+            options.include_features.iter().sorted().collect::<Vec<_>>(),
+            vec!["b", "c"]
+        );
+        sim_assert_eq!(options.exclude_packages, vec!["internal-*", "tools"]);
+
+        // the environment takes precedence over the package config
+        let mut package = package_with_features(&["a", "b", "c", "d"])?;
+        package.metadata = serde_json::json!({
+            "cargo-feature-combinations": {
+                "exclude_features": ["c"],
+                "include_features": ["a"],
+            }
+        });
+        let config = resolve_config(&package, &WorkspaceConfig::default(), &options)?;
+        sim_assert_eq!(
+            config.exclude_features.iter().sorted().collect::<Vec<_>>(),
+            vec!["a", "d"]
+        );
+        sim_assert_eq!(
+            config.include_features.iter().sorted().collect::<Vec<_>>(),
+            vec!["b", "c"]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn parse_targets() -> eyre::Result<()> {
+        let args = [
+            "--target",
+            "b",
+            "check",
+            "--target=a",
+            "--target",
+            "b",
+            "--",
+            "--target",
+            "c",
+        ];
+        let (options, args) = parse_arguments(args.map(str::to_string))?;
+        sim_assert_eq!(options.targets, vec!["b", "a"]);
+        sim_assert_eq!(args.0, vec!["check", "--", "--target", "c"]);
+        Ok(())
+    }
+
+    #[test]
+    fn parse_shard() {
+        sim_assert_eq!(
+            "1/3".parse::<Shard>().ok(),
+            Some(Shard { index: 1, total: 3 })
+        );
+        assert!("3/3".parse::<Shard>().is_err());
+        assert!("0/0".parse::<Shard>().is_err());
+        assert!("1".parse::<Shard>().is_err());
+    }
+
+    #[test]
+    fn matrix_and_run_shards_select_the_same_combinations() -> eyre::Result<()> {
+        let package = package_with_features(&["a", "b", "c"])?;
+        // the matrix is printed in lexicographic order, while smaller feature
+        // combinations run first
+        let packages = [(&package, Config::default())];
+        let mut all = Vec::new();
+        for index in 0..3 {
+            let options = Options {
+                shard: Some(Shard { index, total: 3 }),
+                toolchains: vec!["stable".to_string(), "nightly".to_string()],
+                ..Options::default()
+            };
+            let matrix: Vec<_> =
+                feature_matrix_entries(&packages, false, &BTreeMap::new(), &options)
+                    .into_iter()
+                    .map(|entry| {
+                        let toolchain = entry.metadata["toolchain"].as_str().map(str::to_string);
+                        (entry.features.join(","), toolchain)
+                    })
+                    .sorted()
+                    .collect();
+            let shards = feature_set_shards(
+                feature_sets_to_run(&packages, None, &options),
+                &BTreeMap::new(),
+                &options,
+            );
+            let run: Vec<_> = feature_sets_to_run(&packages, None, &options)
+                .filter(|(idx, _)| in_shard(*idx, options.shard, shards.as_deref()))
+                .map(|(_, (_, _, features, toolchain, _))| {
+                    (features.iter().join(","), toolchain.cloned())
+                })
+                .sorted()
+                .collect();
+            sim_assert_eq!(matrix, run);
+            all.extend(run);
+        }
+        // the shards are disjoint and complete
+        sim_assert_eq!(all.len(), 16);
+        sim_assert_eq!(all.iter().unique().count(), 16);
+        Ok(())
+    }
+
+    #[test]
+    fn combinations_include_features() -> eyre::Result<()> {
+        let package = package_with_features(&["a", "b", "c"])?;
+        let config = Config {
+            include_features: HashSet::from(["a".to_string()]),
+            exclude_features: HashSet::from(["c".to_string()]),
+            ..Config::default()
+        };
+        sim_assert_eq!(package.feature_matrix(&config), vec!["a", "a,b"]);
+        Ok(())
+    }
+
+    #[test]
+    fn combinations_include_features_no_duplicates() -> eyre::Result<()> {
+        let package = package_with_features(&["std", "alloc", "foo"])?;
+        let config = Config {
+            include_features: HashSet::from_iter(["std".to_string(), "alloc".to_string()]),
+            ..Default::default()
+        };
+        let matrix = package.feature_matrix(&config);
+        sim_assert_eq!(matrix, vec!["alloc,foo,std", "alloc,std"]);
+        sim_assert_eq!(matrix.iter().unique().count(), matrix.len());
+        Ok(())
+    }
+
+    #[test]
+    fn combinations_include_default_features() -> eyre::Result<()> {
+        let mut package = package_with_features(&["std", "alloc", "foo", "bar"])?;
+        package
+            .features
+            .insert("default".into(), vec!["std".into()]);
+        package.features.insert(
+            "std".into(),
+            vec!["alloc".into(), "dep:serde".into(), "foo/std".into()],
+        );
+        let config = Config {
+            include_default_features: true,
+            ..Default::default()
+        };
+        sim_assert_eq!(
+            package.feature_matrix(&config),
+            vec!["", "bar", "bar,foo", "foo"]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn combinations_feature_reject() -> eyre::Result<()> {
+        let package = package_with_features(&["gen-a", "gen-b", "core"])?;
+        let config = Config {
+            feature_reject: Some(regex::Regex::new("^gen-")?),
+            ..Default::default()
+        };
+        sim_assert_eq!(package.feature_matrix(&config), vec!["", "core"]);
+        Ok(())
+    }
+
+    #[test]
+    fn combinations_feature_filter() -> eyre::Result<()> {
+        let package = package_with_features(&["gen-a", "gen-b", "core"])?;
+        let config = Config {
+            feature_filter: Some(regex::Regex::new("^gen-")?),
+            include_features: HashSet::from_iter(["core".to_string()]),
+            ..Default::default()
+        };
+        // included features must match the filter as well
+        sim_assert_eq!(package.feature_matrix(&config), Vec::<String>::new());
+        let config = Config {
+            feature_filter: Some(regex::Regex::new("^gen-")?),
+            ..Default::default()
+        };
+        sim_assert_eq!(
+            package.feature_matrix(&config),
+            vec!["", "gen-a", "gen-a,gen-b", "gen-b"]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn combinations_iter_in_order_of_size() -> eyre::Result<()> {
+        let package = package_with_features(&["a", "b", "c"])?;
+        let config = Config {
+            max_combination_size: Some(2),
+            ..Default::default()
+        };
+        let combinations: Vec<String> = package
+            .feature_combinations_iter(&config)
+            .map(|set| set.iter().join(","))
+            .collect();
+        sim_assert_eq!(combinations, vec!["", "a", "b", "c", "a,b", "a,c", "b,c"]);
+        sim_assert_eq!(
+            combinations.iter().sorted().collect::<Vec<_>>(),
+            package.feature_matrix(&config).iter().collect::<Vec<_>>()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn too_many_feature_configurations_errors_gracefully() -> eyre::Result<()> {
+        let features: Vec<String> = (0..20).map(|i| format!("f{i}")).collect();
+        let package = package_with_features(&features.iter().map(String::as_str).collect_vec())?;
+
+        let config = Config {
+            max_configurations: Some(1000),
+            ..Default::default()
+        };
+        let err = check_num_configurations(&package, &config).unwrap_err();
+        let message = err.to_string();
+        assert!(message.contains("20 features result in 1048576 combinations"));
+        assert!(message.contains("max_configurations = 1000"));
+        assert!(message.contains("max_combination_size"));
+
+        let config = Config {
+            max_configurations: Some(1000),
+            max_combination_size: Some(2),
+            ..Default::default()
+        };
+        check_num_configurations(&package, &config)?;
+        sim_assert_eq!(package.feature_matrix(&config).len(), 1 + 20 + 190);
+
+        let features: Vec<String> = (0..200).map(|i| format!("f{i}")).collect();
+        let package = package_with_features(&features.iter().map(String::as_str).collect_vec())?;
+        assert!(check_num_configurations(&package, &Config::default()).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn combinations_mutually_exclusive() -> eyre::Result<()> {
+        let package = package_with_features(&["tokio", "async-std", "serde"])?;
+        let config = Config {
+            mutually_exclusive: vec![HashSet::from(["tokio", "async-std"].map(str::to_string))],
+            ..Config::default()
+        };
+        sim_assert_eq!(
+            package.feature_matrix(&config),
+            vec![
+                "",
+                "async-std",
+                "async-std,serde",
+                "serde",
+                "serde,tokio",
+                "tokio"
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn combinations_pairwise() -> eyre::Result<()> {
+        let features = ["a", "b", "c", "d"];
+        let package = package_with_features(&features)?;
+        let config = Config {
+            pairwise: true,
+            ..Config::default()
+        };
+        let combinations = package.feature_combinations(&config);
+        assert!(combinations.len() < 2usize.pow(4));
+        for (a, b) in features.iter().tuple_combinations() {
+            for (on_a, on_b) in [(false, false), (false, true), (true, false), (true, true)] {
+                let covered = combinations.iter().any(|set| {
+                    set.iter().any(|ft| ft == a) == on_a && set.iter().any(|ft| ft == b) == on_b
+                });
+                assert!(covered, "{a}={on_a} and {b}={on_b} are not covered");
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn combinations_pairwise_mutually_exclusive() -> eyre::Result<()> {
+        let package = package_with_features(&["a", "b", "c"])?;
+        let config = Config {
+            pairwise: true,
+            mutually_exclusive: vec![HashSet::from(["a", "b"].map(str::to_string))],
+            ..Config::default()
+        };
+        let combinations = package.feature_combinations(&config);
+        assert!(combinations
+            .iter()
+            .all(|set| !(set.iter().any(|ft| *ft == "a") && set.iter().any(|ft| *ft == "b"))));
+        Ok(())
+    }
+
+    #[test]
+    fn combinations_max_combination_size() -> eyre::Result<()> {
+        let package = package_with_features(&["a", "b", "c"])?;
+        let config = Config {
+            max_combination_size: Some(2),
+            ..Config::default()
+        };
+        sim_assert_eq!(
+            package.feature_matrix(&config),
+            vec!["", "a", "a,b", "a,c", "b", "b,c", "c"]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn combinations_max_combination_size_zero() -> eyre::Result<()> {
+        let package = package_with_features(&["a", "b", "c"])?;
+        let config = Config {
+            max_combination_size: Some(0),
+            ..Config::default()
+        };
+        sim_assert_eq!(package.feature_matrix(&config), vec![""]);
+        Ok(())
+    }
+
+    #[test]
+    fn error_regex_single_mod_multiple_errors() {
+        let stderr = include_str!("../tests/single_mod_multiple_errors_stderr.txt");
+        let errors: Vec<_> = error_counts(stderr).collect();
+        sim_assert_eq!(&errors, &vec![2]);
+    }
+
+    #[test]
+    fn error_regex_target_kind_and_warnings_emitted() {
+        let stderr = include_str!("../tests/lib_errors_and_warnings_stderr.txt");
+        let errors: Vec<_> = error_counts(stderr).collect();
+        sim_assert_eq!(&errors, &vec![3]);
+        let warnings: Vec<_> = warning_counts(stderr).collect();
+        sim_assert_eq!(&warnings, &vec![2]);
+    }
+
+    #[test]
+    fn warning_regex_single_warning() {
+        let stderr = include_str!("../tests/lib_single_warning_stderr.txt");
+        let warnings: Vec<_> = warning_counts(stderr).collect();
+        sim_assert_eq!(&warnings, &vec![1]);
+    }
+
+    #[test]
+    fn json_messages_count_diagnostics() -> eyre::Result<()> {
+        let stdout = include_str!("../tests/lib_errors_and_warnings_stdout.json");
+        let mut rendered = Vec::new();
+        let counts = render_json_messages(stdout.as_bytes(), &mut rendered)?;
+        sim_assert_eq!(
+            counts,
+            DiagnosticCounts {
+                warnings: 2,
+                errors: 3
+            }
+        );
+        let rendered = strip_ansi_escapes::strip_str(String::from_utf8(rendered)?);
+        assert!(rendered.contains("error[E0425]: cannot find function `missing_fn` in this scope"));
+        Ok(())
+    }
+
+    #[test]
+    fn sarif_report_merges_feature_combinations() -> eyre::Result<()> {
+        let stdout = include_str!("../tests/lib_errors_and_warnings_stdout.json");
+        let diagnostics = render_json_diagnostics(stdout.as_bytes(), &mut std::io::sink())?;
+        sim_assert_eq!(
+            DiagnosticCounts::from(diagnostics.as_slice()),
+            DiagnosticCounts {
+                warnings: 2,
+                errors: 3
+            }
+        );
+        let failed = |features: &[&str], diagnostics: Vec<Diagnostic>| Summary {
+            features: features.iter().map(ToString::to_string).collect(),
+            exit_code: Some(101),
+            num_errors: 0,
+            diagnostics,
+            ..summary("foo", false)
+        };
+        let warnings: Vec<_> = diagnostics.iter().filter(|d| !d.error).cloned().collect();
+        let report = crate::sarif::report(&[
+            failed(&[], diagnostics.clone()),
+            failed(&["a", "b"], warnings),
+        ]);
+
+        let run = &report["runs"][0];
+        sim_assert_eq!(
+            run["tool"]["driver"]["rules"],
+            serde_json::json!([{ "id": "E0308" }, { "id": "E0425" }, { "id": "unused_imports" }])
+        );
+        let results = run["results"].as_array().unwrap();
+        sim_assert_eq!(results.len(), 5);
+        sim_assert_eq!(
+            results[0],
+            serde_json::json!({
+                "ruleId": "unused_imports",
+                "level": "warning",
+                "message": { "text": "unused import: `std::collections::HashMap`" },
+                "locations": [{
+                    "physicalLocation": {
+                        "artifactLocation": { "uri": "src/lib.rs" },
+                        "region": { "startLine": 1, "startColumn": 5 },
+                    },
+                }],
+                "properties": { "package": "foo", "featureCombinations": ["", "a,b"] },
+            })
+        );
+        sim_assert_eq!(results[4]["ruleId"], "E0425");
+        sim_assert_eq!(results[4]["level"], "error");
+        sim_assert_eq!(
+            results[4]["properties"]["featureCombinations"],
+            serde_json::json!([""])
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn timeout_reader_stops_at_deadline() -> eyre::Result<()> {
+        use std::io::Read;
+        use std::time::{Duration, Instant};
+
+        struct Stalled(bool);
+
+        impl Read for Stalled {
+            fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+                if std::mem::replace(&mut self.0, true) {
+                    std::thread::sleep(Duration::from_secs(60));
+                    return Ok(0);
+                }
+                buf[..5].copy_from_slice(b"hello");
+                Ok(5)
+            }
+        }
+
+        let started = Instant::now();
+        let deadline = started + Duration::from_millis(50);
+        let mut output = String::new();
+        let mut reader = crate::timeout::Reader::new(Stalled(false), deadline);
+        reader.read_to_string(&mut output)?;
+        sim_assert_eq!(output, "hello");
+        // the stalled thread is not waited for past the deadline
+        reader.join()?;
+        assert!(started.elapsed() < Duration::from_secs(10));
+
+        let deadline = Instant::now() + Duration::from_secs(60);
+        let mut reader = crate::timeout::Reader::new(&b"done"[..], deadline);
+        output.clear();
+        reader.read_to_string(&mut output)?;
+        sim_assert_eq!(output, "done");
+        reader.join()?;
+        Ok(())
+    }
+
+    #[test]
+    fn proper_supersets_of_failing_sets() {
+        let [a, b, c] = ["a", "b", "c"].map(String::from);
+        let failing = vec![a.clone(), b.clone()];
+        assert!(is_proper_superset(&[&a, &b, &c], &failing));
+        assert!(is_proper_superset(&[&c, &b, &a], &failing));
+        assert!(!is_proper_superset(&[&a, &b], &failing));
+        assert!(!is_proper_superset(&[&a, &c], &failing));
+        assert!(!is_proper_superset(&[&a], &failing));
+        assert!(is_proper_superset(&[&a], &[]));
+        assert!(!is_proper_superset(&[], &[]));
+    }
+
+    #[test]
+    fn first_bad_exit_code_of_killed_process() {
+        let exited = |exit_code, signal, ok| Summary {
+            exit_code,
+            signal,
+            ..summary("test", ok)
+        };
+        sim_assert_eq!(first_bad_exit_code(&[exited(Some(0), None, true)]), None);
+        sim_assert_eq!(
+            first_bad_exit_code(&[exited(Some(0), None, true), exited(None, Some(9), false)]),
+            Some(137)
+        );
+        sim_assert_eq!(first_bad_exit_code(&[exited(None, None, false)]), Some(1));
+        // cargo passed, but there were too many warnings
+        sim_assert_eq!(
+            first_bad_exit_code(&[exited(Some(0), None, false)]),
+            Some(1)
+        );
+        sim_assert_eq!(
+            first_bad_exit_code(&[exited(Some(101), None, false), exited(None, None, false)]),
+            Some(101)
+        );
+    }
+
+    #[test]
+    fn keep_going_exit_code() -> eyre::Result<()> {
+        for flag in ["--keep-going", "--fail-at-end"] {
+            let (options, args) = parse_arguments([flag, "check"].map(str::to_string))?;
+            assert!(options.keep_going);
+            assert!(!options.fail_fast);
+            sim_assert_eq!(args.0, vec!["check"]);
+        }
+        let args = ["--fail-fast", "--keep-going", "check"];
+        assert!(parse_arguments(args.map(str::to_string)).is_err());
+
+        let exited = |exit_code: Option<i32>| Summary {
+            exit_code,
+            ..summary("test", exit_code == Some(0))
+        };
+        let mixed = [
+            exited(Some(0)),
+            exited(Some(101)),
+            exited(Some(0)),
+            exited(Some(2)),
+            exited(Some(101)),
+        ];
+        let (options, _) = parse_arguments(["--keep-going", "check"].map(str::to_string))?;
+        sim_assert_eq!(exit_code(&mixed, &options), Some(101));
+        sim_assert_eq!(exit_code(&mixed[2..], &options), Some(2));
+        sim_assert_eq!(exit_code(&mixed[..1], &options), None);
+
+        let args = ["--keep-going", "--exit-with-count", "check"];
+        let (options, _) = parse_arguments(args.map(str::to_string))?;
+        sim_assert_eq!(exit_code(&mixed, &options), Some(3));
+        sim_assert_eq!(exit_code(&mixed[..1], &options), None);
+        Ok(())
+    }
+
+    #[test]
+    fn failure_count_exit_code_is_clamped() {
+        let failed = |ok, failing_subset| Summary {
+            exit_code: Some(101),
+            failing_subset,
+            ..summary("test", ok)
+        };
+        sim_assert_eq!(failure_count_exit_code(&[failed(true, None)]), None);
+        sim_assert_eq!(
+            failure_count_exit_code(&[
+                failed(false, None),
+                failed(true, None),
+                failed(false, None),
+                // skipped supersets of failures are not counted
+                failed(false, Some(vec![])),
+            ]),
+            Some(2)
+        );
+        let failures: Vec<_> = (0..300).map(|_| failed(false, None)).collect();
+        sim_assert_eq!(failure_count_exit_code(&failures), Some(255));
+    }
+
+    #[test]
+    fn failures_cache_round_trip() -> eyre::Result<()> {
+        let dir = std::env::temp_dir().join(format!("fc-failures-{}", std::process::id()));
+        let path = dir.join("fc").join("last-failures.json");
+        sim_assert_eq!(read_failures(&path)?, None);
+
+        let failures = vec![
+            FailedFeatureSet {
+                package: "a".to_string(),
+                features: vec!["bar".to_string(), "foo".to_string()],
+                toolchain: None,
+                target: None,
+            },
+            FailedFeatureSet {
+                package: "b".to_string(),
+                features: vec![],
+                toolchain: Some("nightly".to_string()),
+                target: Some("x86_64-unknown-linux-musl".to_string()),
+            },
+        ];
+        write_failures(&failures, &path)?;
+        sim_assert_eq!(read_failures(&path)?, Some(failures));
+        std::fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn junit_report_has_suite_per_package() {
+        let combination = |name: &str, features: &[&str], ok: bool| Summary {
+            features: features.iter().map(ToString::to_string).collect(),
+            num_warnings: 1,
+            duration: std::time::Duration::from_millis(1500),
+            ..summary(name, ok)
+        };
+        let xml = junit_xml(&[
+            combination("a", &[], true),
+            combination("a", &["foo", "bar"], false),
+            combination("b<c>", &["x"], true),
+        ]);
+        sim_assert_eq!(
+            xml,
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+<testsuites name="cargo-fc" tests="3" failures="1" skipped="0" time="4.500">
+  <testsuite name="a" tests="2" failures="1" skipped="0" time="3.000">
+    <testcase name="a []" classname="a" time="1.500" />
+    <testcase name="a [foo, bar]" classname="a" time="1.500">
+      <failure message="1 errors, 1 warnings" />
+    </testcase>
+  </testsuite>
+  <testsuite name="b&lt;c&gt;" tests="1" failures="0" skipped="0" time="1.500">
+    <testcase name="b&lt;c&gt; [x]" classname="b&lt;c&gt;" time="1.500" />
+  </testsuite>
+</testsuites>
+"#
+        );
+    }
+
+    #[test]
+    fn warning_regex_two_mod_multiple_warnings() {
+        let stderr = include_str!("../tests/two_mods_warnings_stderr.txt");
+        let warnings: Vec<_> = warning_counts(stderr).collect();
+        sim_assert_eq!(&warnings, &vec![6, 7]);
+    }
+
+    #[test]
+    fn feature_matrix_entries_of_two_features() -> eyre::Result<()> {
+        let (options, _) = parse_arguments(["matrix", "--with-command"].map(str::to_string))?;
+        let package = package_with_features(&["foo", "bar"])?;
+        let mut config = resolve_config(&package, &WorkspaceConfig::default(), &options)?;
+        config
+            .matrix
+            .insert("os".to_string(), serde_json::json!("ubuntu-latest"));
+        let packages = [(&package, config)];
+        let entries = feature_matrix_entries(&packages, true, &BTreeMap::new(), &options);
+        sim_assert_eq!(
+            entries
+                .iter()
+                .map(|entry| (entry.package.as_str(), entry.features.join(",")))
+                .collect::<Vec<_>>(),
+            vec![
+                ("test", String::new()),
+                ("test", "bar".to_string()),
+                ("test", "bar,foo".to_string()),
+                ("test", "foo".to_string()),
+            ]
+        );
+        sim_assert_eq!(
+            entries[2].metadata,
+            serde_json::json!({
+                "os": "ubuntu-latest",
+                "command": "--no-default-features --features=bar,foo",
+            })
+        );
+        sim_assert_eq!(
+            entries[2].to_json(","),
+            serde_json::json!({
+                "name": "test",
+                "features": "bar,foo",
+                "os": "ubuntu-latest",
+                "command": "--no-default-features --features=bar,foo",
+            })
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn combinations_all_features_and_no_features() -> eyre::Result<()> {
+        let package = package_with_features(&["a", "b", "c", "std"])?;
+        let matrix = |args: &[&str]| -> eyre::Result<Vec<String>> {
+            let args = ["check"].iter().chain(args).map(ToString::to_string);
+            let (options, _) = parse_arguments(args)?;
+            let config = resolve_config(&package, &WorkspaceConfig::default(), &options)?;
+            Ok(package.feature_matrix(&config))
+        };
+        sim_assert_eq!(matrix(&["--all-features"])?, vec!["a,b,c,std"]);
+        sim_assert_eq!(
+            matrix(&["--all-features", "--exclude-feature", "b"])?,
+            vec!["a,c,std"]
+        );
+        sim_assert_eq!(matrix(&["--no-features"])?, vec![""]);
+        sim_assert_eq!(
+            matrix(&["--no-features", "--include-feature", "std"])?,
+            vec!["std"]
+        );
+        assert!(
+            parse_arguments(["check", "--all-features", "--no-features"].map(str::to_string))
+                .is_err()
+        );
+        assert!(
+            parse_arguments(["check", "--no-features", "--pairwise"].map(str::to_string)).is_err()
+        );
+
+        let (options, _) = parse_arguments(["check", "--all-features"].map(str::to_string))?;
+        let workspace = WorkspaceConfig {
+            package_overlay: Some(serde_json::json!({ "mutually_exclusive": [["a", "b"]] })),
+            ..WorkspaceConfig::default()
+        };
+        let err = resolve_config(&package, &workspace, &options).unwrap_err();
+        assert!(
+            err.to_string().contains("mutually_exclusive [a, b]"),
+            "{err}"
+        );
+        let (options, _) = parse_arguments(
+            ["check", "--all-features", "--exclude-feature", "b"].map(str::to_string),
+        )?;
+        let config = resolve_config(&package, &workspace, &options)?;
+        sim_assert_eq!(package.feature_matrix(&config), vec!["a,c,std"]);
+        Ok(())
+    }
+
+    #[test]
+    fn size_column_formatting() -> eyre::Result<()> {
+        let sized = |artifact_size: Option<u64>| Summary {
+            artifact_size,
+            ..summary("test", true)
+        };
+        let columns: Vec<_> = [None, Some(512), Some(1536), Some(5 * 1024 * 1024 + 300_000)]
+            .into_iter()
+            .map(|size| size_column(&sized(size)))
+            .collect();
+        sim_assert_eq!(
+            columns,
+            vec![
+                None,
+                Some("size = 512 B,".to_string()),
+                Some("size = 1.5 KiB,".to_string()),
+                Some("size = 5.3 MiB,".to_string()),
+            ]
+        );
+        sim_assert_eq!(format_size(3 * 1024 * 1024 * 1024), "3.0 GiB");
+        sim_assert_eq!(
+            serde_json::to_value(sized(Some(512)))?["artifact_size"],
+            serde_json::json!(512)
+        );
+        assert!(serde_json::to_value(sized(None))?
+            .get("artifact_size")
+            .is_none());
+
+        let args = ["--report-sizes", "build"].map(str::to_string);
+        assert!(parse_arguments(args).is_err());
+        let args = ["--report-sizes", "--message-format", "json", "build"].map(str::to_string);
+        assert!(parse_arguments(args)?.0.report_sizes);
+        Ok(())
+    }
+
+    #[test]
+    fn features_separator_joins_features() -> eyre::Result<()> {
+        let args = ["matrix", "--with-command", "--features-separator", " "];
+        let (options, _) = parse_arguments(args.map(str::to_string))?;
+        let package = package_with_features(&["a", "b"])?;
+        let config = resolve_config(&package, &WorkspaceConfig::default(), &options)?;
+        sim_assert_eq!(package.feature_matrix(&config), vec!["", "a", "a b", "b"]);
+
+        let packages = [(&package, config)];
+        let entries = feature_matrix_entries(&packages, true, &BTreeMap::new(), &options);
+        sim_assert_eq!(
+            entries[2].to_json(" "),
+            serde_json::json!({
+                "name": "test",
+                "features": "a b",
+                "command": "--no-default-features --features=a,b",
+            })
+        );
+        sim_assert_eq!(
+            injected_feature_args(&["check".to_string()], &["a", "b"], false, &options),
+            vec!["--no-default-features", "--features=a,b"]
+        );
+
+        let args = [
+            "--features-separator",
+            " ",
+            "--feature-sets",
+            r#"["b a"]"#,
+            "check",
+        ];
+        let (options, _) = parse_arguments(args.map(str::to_string))?;
+        sim_assert_eq!(
+            options.feature_sets,
+            Some(vec![vec!["a".to_string(), "b".to_string()]])
+        );
+        assert!(
+            parse_arguments(["--features-separator", "", "check"].map(str::to_string)).is_err()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn collapse_identical_matrix_rows() -> eyre::Result<()> {
+        let (options, _) = parse_arguments(
+            [
+                "matrix",
+                "--collapse-identical",
+                "--name-template",
+                "{package}",
+            ]
+            .map(str::to_string),
+        )?;
+        assert!(options.collapse_identical);
+        let (a, b, c) = (
+            package("a", &["std"], &[])?,
+            package("b", &["std"], &[])?,
+            package("c", &["alloc"], &[])?,
+        );
+        let packages: Vec<_> = [&a, &b, &c]
+            .into_iter()
+            .map(|package| {
+                let config = resolve_config(package, &WorkspaceConfig::default(), &options)?;
+                Ok::<_, eyre::Report>((package, config))
+            })
+            .collect::<eyre::Result<_>>()?;
+        let rows = feature_matrix_entries(&packages, false, &BTreeMap::new(), &options)
+            .into_iter()
+            .map(|entry| entry.to_json(","));
+        sim_assert_eq!(
+            collapse_identical_rows(rows),
+            vec![
+                serde_json::json!({ "name": ["a", "b"], "features": "", "display_name": "a, b" }),
+                serde_json::json!({ "name": ["a", "b"], "features": "std", "display_name": "a, b" }),
+                serde_json::json!({ "name": "c", "features": "", "display_name": "c" }),
+                serde_json::json!({ "name": "c", "features": "alloc", "display_name": "c" }),
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn combinations_no_empty_feature_set() -> eyre::Result<()> {
+        let package = package_with_features(&["a", "b"])?;
+        let config: Config = serde_json::from_value(serde_json::json!({
+            "no_empty_feature_set": true,
+        }))?;
+        sim_assert_eq!(package.feature_matrix(&config), vec!["a", "a,b", "b"]);
+
+        let pairwise = Config {
+            no_empty_feature_set: true,
+            pairwise: true,
+            ..Config::default()
+        };
+        assert!(!package.feature_matrix(&pairwise).contains(&String::new()));
+        let no_features = Config {
+            no_empty_feature_set: true,
+            quick_mode: Some(QuickMode::NoFeatures),
+            ..Config::default()
+        };
+        sim_assert_eq!(package.feature_matrix(&no_features), Vec::<String>::new());
+
+        let explanations = explain_feature_combinations(&package, &config)?;
+        let empty = explanations.iter().find(|e| e.features.is_empty());
+        sim_assert_eq!(empty.map(|e| &e.decision), Some(&Decision::EmptyFeatureSet));
+        Ok(())
+    }
+
+    #[test]
+    fn combinations_allow_feature_sets() -> eyre::Result<()> {
+        let package = package_with_features(&["a", "b", "c"])?;
+        let mut config: Config = serde_json::from_value(serde_json::json!({
+            "allow_feature_sets": [["a"], ["b", "a"], ["c", "missing"], [], ["a", "b"]],
+            "exclude_features": ["c"],
+            "max_combination_size": 1,
+        }))?;
+        sim_assert_eq!(package.feature_matrix(&config), vec!["", "a", "a,b", "c"]);
+        check_num_configurations(&package, &config)?;
+
+        config.no_empty_feature_set = true;
+        sim_assert_eq!(package.feature_matrix(&config), vec!["a", "a,b", "c"]);
+
+        let explanations = explain_feature_combinations(&package, &config)?;
+        let decision = |features: &[&str]| {
+            explanations
+                .iter()
+                .find(|e| {
+                    e.features
+                        .iter()
+                        .map(|ft| ft.as_str())
+                        .eq(features.iter().copied())
+                })
+                .map(|e| e.decision.clone())
+        };
+        sim_assert_eq!(decision(&["a", "b"]), Some(Decision::Kept));
+        sim_assert_eq!(decision(&["c"]), Some(Decision::Kept));
+        sim_assert_eq!(decision(&["b"]), Some(Decision::NotAllowed));
+        Ok(())
+    }
+
+    #[test]
+    fn clippy_args_follow_separator_for_clippy_only() -> eyre::Result<()> {
+        let mut package = package_with_features(&["a"])?;
+        package.metadata = serde_json::json!({
+            "cargo-feature-combinations": { "clippy_args": ["-D", "warnings"] },
+        });
+        let args_of = |args: &[&str]| -> eyre::Result<Vec<String>> {
+            let (options, cargo_args, extra_args) = split_arguments(args)?;
+            let config = resolve_config(&package, &WorkspaceConfig::default(), &options)?;
+            let job = Job {
+                features: vec![],
+                ..job(&package, &config, None)
+            };
+            Ok(invocation(&cargo_args, &extra_args, &options).args(&job))
+        };
+        let common = [
+            "--color",
+            "never",
+            "--manifest-path",
+            "/test/Cargo.toml",
+            "--no-default-features",
+            "--features=",
+        ];
+        let expected = |subcommand: &str, extra_args: &[&str]| -> Vec<String> {
+            std::iter::once(&subcommand)
+                .chain(&common)
+                .chain(extra_args)
+                .map(ToString::to_string)
+                .collect()
+        };
+        sim_assert_eq!(
+            args_of(&["--color", "never", "clippy"])?,
+            expected("clippy", &["--", "-D", "warnings"])
+        );
+        sim_assert_eq!(
+            args_of(&[
+                "--color",
+                "never",
+                "--clippy-args",
+                "-A clippy::pedantic",
+                "clippy",
+                "--",
+                "-W",
+                "unused"
+            ])?,
+            expected(
+                "clippy",
+                &[
+                    "--",
+                    "-W",
+                    "unused",
+                    "-D",
+                    "warnings",
+                    "-A",
+                    "clippy::pedantic"
+                ]
+            )
+        );
+        sim_assert_eq!(
+            args_of(&["--color", "never", "build"])?,
+            expected("build", &[])
+        );
+        sim_assert_eq!(
+            args_of(&["--color", "never", "test", "--", "--nocapture"])?,
+            expected("test", &["--", "--nocapture"])
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn color_never_is_respected() -> eyre::Result<()> {
+        for color in [["--color=never"].as_slice(), &["--color", "never"]] {
+            let args = color.iter().chain(&["check"]).map(ToString::to_string);
+            let (options, cargo_args) = parse_arguments(args)?;
+            let (cargo_args, _) = split_cargo_args(cargo_args, &options);
+            sim_assert_eq!(cargo_args.0, vec!["check", "--color", "never"]);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn combinations_of_feature_map() -> eyre::Result<()> {
+        let features = |features: &[(&str, &[&str])]| -> BTreeMap<String, Vec<String>> {
+            features
+                .iter()
+                .map(|(name, implied)| {
+                    let implied = implied.iter().map(ToString::to_string).collect();
+                    ((*name).to_string(), implied)
+                })
+                .collect()
+        };
+        let sets = |sets: &[&[&str]]| -> Vec<Vec<String>> {
+            sets.iter()
+                .map(|set| set.iter().map(ToString::to_string).collect())
+                .collect()
+        };
+
+        let map = features(&[("a", &[]), ("b", &[])]);
+        sim_assert_eq!(
+            combinations(&map, &Config::default()),
+            sets(&[&[], &["a"], &["a", "b"], &["b"]])
+        );
+
+        let map = features(&[
+            ("default", &["std"]),
+            ("std", &[]),
+            ("serde", &["dep:serde"]),
+            ("extra", &["dep:extra-impl"]),
+        ]);
+        let config: Config = serde_json::from_value(serde_json::json!({
+            "include_default_features": true,
+            "skip_optional_dependencies": true,
+            "order": "size-then-lex",
+        }))?;
+        sim_assert_eq!(combinations(&map, &config), sets(&[&[], &["extra"]]));
+
+        let package = package_with_features(&["a", "b", "c"])?;
+        let config = Config {
+            max_combination_size: Some(2),
+            ..Config::default()
+        };
+        sim_assert_eq!(
+            combinations(&package.features, &config),
+            package
+                .feature_combinations(&config)
+                .into_iter()
+                .map(|set| set.into_iter().cloned().collect::<Vec<_>>())
+                .collect::<Vec<_>>()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn combinations_dedupe_implied() -> eyre::Result<()> {
+        let features: BTreeMap<String, Vec<String>> = [
+            ("a".to_string(), vec![]),
+            ("b".to_string(), vec!["a".to_string()]),
+        ]
+        .into_iter()
+        .collect();
+        let config = Config {
+            dedupe_implied: true,
+            ..Config::default()
+        };
+        sim_assert_eq!(
+            combinations(&features, &config),
+            vec![vec![], vec!["a".to_string()], vec!["b".to_string()]]
+        );
+        sim_assert_eq!(combinations(&features, &Config::default()).len(), 4);
+
+        // [a,b] enables the same features as [b], but is included explicitly
+        let config = Config {
+            include_feature_sets: vec![HashSet::from(["a", "b"].map(str::to_string))],
+            ..config
+        };
+        sim_assert_eq!(
+            combinations(&features, &config),
+            vec![
+                vec![],
+                vec!["a".to_string()],
+                vec!["a".to_string(), "b".to_string()],
+                vec!["b".to_string()],
+            ]
+        );
+
+        let (options, _) = parse_arguments(["--dedupe-implied", "check"].map(str::to_string))?;
+        assert!(options.dedupe_implied);
+        Ok(())
+    }
+
+    #[test]
+    fn changed_files_to_features() -> eyre::Result<()> {
+        let package = package_with_features(&["async-std", "grpc", "tokio"])?;
+        let config: Config = serde_json::from_value(serde_json::json!({
+            "feature_paths": { "grpc": ["proto", "src/transport/grpc.rs"] },
+        }))?;
+        let changed = |files: &[&str]| {
+            let files: Vec<std::path::PathBuf> = files.iter().map(Into::into).collect();
+            features_of_changed_files(&package, &config, &files)
+                .map(|features| features.into_iter().sorted().collect::<Vec<_>>())
+        };
+        sim_assert_eq!(
+            changed(&["/test/src/tokio.rs"]),
+            Some(vec!["tokio".to_string()])
+        );
+        sim_assert_eq!(
+            changed(&["/test/src/async_std/net.rs", "/test/proto/api.proto"]),
+            Some(vec!["async-std".to_string(), "grpc".to_string()])
+        );
+        sim_assert_eq!(
+            changed(&["/test/src/transport/grpc.rs", "/other/src/lib.rs"]),
+            Some(vec!["grpc".to_string()])
+        );
+        sim_assert_eq!(changed(&["/test/src/tokio.rs", "/test/src/lib.rs"]), None);
+        // e.g. a package included by --since-include-dependents
+        sim_assert_eq!(changed(&["/dependency/src/tokio.rs"]), None);
+
+        let config = Config {
+            changed_features: Some(HashSet::from(["tokio".to_string()])),
+            ..Config::default()
+        };
+        sim_assert_eq!(
+            package.feature_matrix(&config),
+            vec![
+                "async-std,grpc,tokio",
+                "async-std,tokio",
+                "grpc,tokio",
+                "tokio"
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn parse_verbose_levels() -> eyre::Result<()> {
+        let args = ["-v", "check", "--", "-v"];
+        let (options, args) = parse_arguments(args.map(str::to_string))?;
+        sim_assert_eq!(options.verbose, 1);
+        sim_assert_eq!(args.0, vec!["check", "--", "-v"]);
+
+        // verbose flags after the subcommand are passed to cargo
+        let args = ["--verbose", "check", "-v", "--message-format", "short"];
+        let (options, args) = parse_arguments(args.map(str::to_string))?;
+        sim_assert_eq!(options.verbose, 1);
+        sim_assert_eq!(args.0, vec!["check", "-v", "--message-format", "short"]);
+
+        let (options, _) = parse_arguments(["-vv", "check"].map(str::to_string))?;
+        sim_assert_eq!(options.verbose, 2);
+        let args = ["-vvv", "--pairwise", "-v", "check", "-vv"];
+        let (options, args) = parse_arguments(args.map(str::to_string))?;
+        sim_assert_eq!(options.verbose, 4);
+        sim_assert_eq!(args.0, vec!["check", "-vv"]);
+        Ok(())
+    }
+
+    #[test]
+    fn empty_features_name_for_featureless_package() -> eyre::Result<()> {
+        let (options, _) = parse_arguments(["matrix", "--with-command"].map(str::to_string))?;
+        let package = package_with_features(&[])?;
+        let mut config = resolve_config(&package, &WorkspaceConfig::default(), &options)?;
+        config.empty_features_name = Some("none".to_string());
+        sim_assert_eq!(package.feature_matrix(&config), vec![""]);
+
+        let packages = [(&package, config)];
+        let entries = feature_matrix_entries(&packages, true, &BTreeMap::new(), &options);
+        sim_assert_eq!(entries.len(), 1);
+        sim_assert_eq!(
+            entries[0].to_json(","),
+            serde_json::json!({
+                "name": "test",
+                "features": "none",
+                "command": "--no-default-features --features=",
+            })
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn parse_stdin_packages() -> eyre::Result<()> {
+        let args = [
+            "-p",
+            "cli",
+            "--stdin-packages",
+            "check",
+            "--exclude-package",
+            "core",
+        ];
+        let stdin = "api\n\n  core  \n".as_bytes();
+        let (options, args) = parse_arguments_with_env(args.map(str::to_string), stdin, |_| None)?;
+        sim_assert_eq!(
+            options.packages,
+            HashSet::from(["cli", "api", "core"].map(str::to_string))
+        );
+        sim_assert_eq!(options.exclude_packages, vec!["core"]);
+        sim_assert_eq!(args.0, vec!["check"]);
 
-    let missing_arguments = cargo_args.is_empty() && extra_args.is_empty();
+        // stdin is only read with --stdin-packages
+        let stdin = "api\n".as_bytes();
+        let (options, _) =
+            parse_arguments_with_env(["check"].map(str::to_string), stdin, |_| None)?;
+        assert!(options.packages.is_empty());
+        Ok(())
+    }
 
-    if !cargo_args.contains("--color") {
-        // force colored output
-        cargo_args.extend(["--color".to_string(), "always".to_string()]);
+    #[test]
+    fn print_summary_to_buffer() -> eyre::Result<()> {
+        let combination = |features: &[&str], num_warnings: usize, ok: bool| Summary {
+            features: features.iter().map(ToString::to_string).collect(),
+            num_warnings,
+            duration: std::time::Duration::from_millis(1500),
+            ..summary("test", ok)
+        };
+        let summaries = [
+            combination(&[], 0, true),
+            combination(&["foo"], 12, true),
+            combination(&["bar", "foo"], 0, false),
+        ];
+        let mut buffer = termcolor::Buffer::no_color();
+        print_summary(
+            &summaries,
+            &mut buffer,
+            std::time::Duration::from_secs(3),
+            false,
+        )?;
+        let output = String::from_utf8(buffer.into_inner())?;
+        sim_assert_eq!(
+            output.lines().collect::<Vec<_>>(),
+            vec![
+                "",
+                "    Finished 3 total feature combinations for 1 package in 3s",
+                "",
+                "        PASS test ( 0 errors, 0  warnings, 1.50s, features = [] )",
+                "        WARN test ( 0 errors, 12 warnings, 1.50s, features = [foo] )",
+                "        FAIL test ( 1 errors, 0  warnings, 1.50s, features = [bar, foo] )",
+                "",
+            ]
+        );
+        Ok(())
     }
 
-    let mut stdout = StandardStream::stdout(ColorChoice::Auto);
-    let mut summary: Vec<Summary> = Vec::new();
+    #[test]
+    fn print_summary_counts_each_target() -> eyre::Result<()> {
+        let on_target = |target: &str| Summary {
+            target: Some(target.to_string()),
+            ..summary("test", false)
+        };
+        let summaries = [
+            on_target("x86_64-unknown-linux-gnu"),
+            on_target("wasm32-unknown-unknown"),
+        ];
+        let mut buffer = termcolor::Buffer::no_color();
+        print_summary(
+            &summaries,
+            &mut buffer,
+            std::time::Duration::from_secs(3),
+            true,
+        )?;
+        let output = String::from_utf8(buffer.into_inner())?;
+        let lines: Vec<_> = output.lines().collect();
+        sim_assert_eq!(
+            lines[1],
+            "    Finished 2 total feature combinations for 1 package in 3s"
+        );
+        sim_assert_eq!(
+            lines.iter().rev().nth(1),
+            Some(&"       Total 1 package, 2 feature combinations, 2 failed")
+        );
+        Ok(())
+    }
 
-    for package in packages {
-        let config = package.config()?;
+    #[test]
+    fn print_summary_grouped_by_package_counts_unchanged() -> eyre::Result<()> {
+        let summaries = [
+            Summary {
+                unchanged: true,
+                ..summary("a", true)
+            },
+            summary("b", true),
+            Summary {
+                features: vec!["foo".to_string()],
+                ..summary("b", false)
+            },
+        ];
+        let mut buffer = termcolor::Buffer::no_color();
+        print_summary(
+            &summaries,
+            &mut buffer,
+            std::time::Duration::from_secs(3),
+            true,
+        )?;
+        let output = String::from_utf8(buffer.into_inner())?;
+        sim_assert_eq!(
+            output.lines().collect::<Vec<_>>(),
+            vec![
+                "",
+                "    Finished 3 total feature combinations for 2 packages in 3s",
+                "",
+                "     Package a",
+                "   UNCHANGED a ( 0 errors, 0 warnings, 0.00s, features = [] )",
+                "             a ( 1 UNCHANGED )",
+                "",
+                "     Package b",
+                "        PASS b ( 0 errors, 0 warnings, 0.00s, features = [] )",
+                "        FAIL b ( 1 errors, 0 warnings, 0.00s, features = [foo] )",
+                "             b ( 1 PASS, 1 FAIL )",
+                "",
+                "       Total 2 packages, 3 feature combinations, 1 failed",
+                "",
+            ]
+        );
+        Ok(())
+    }
 
-        for features in package.feature_combinations(&config) {
-            // We set the command working dir to the package manifest parent dir.
-            // This works well for now, but one could also consider `--manifest-path` or `-p`
-            let Some(working_dir) = package.manifest_path.parent() else {
-                eyre::bail!(
-                    "could not find parent dir of package {}",
-                    package.manifest_path.to_string()
-                )
-            };
+    #[test]
+    fn max_warnings_threshold() -> eyre::Result<()> {
+        // (errors, warnings) of a feature combination
+        let counts = [(0, 0), (0, 2), (0, 3), (1, 0)];
+        let passes = |max_warnings| {
+            counts
+                .iter()
+                .map(|&(errors, warnings)| within_warning_threshold(errors, warnings, max_warnings))
+                .collect::<Vec<_>>()
+        };
+        sim_assert_eq!(passes(None), vec![true, true, true, true]);
+        sim_assert_eq!(passes(Some(2)), vec![true, true, false, false]);
+        sim_assert_eq!(passes(Some(0)), vec![true, false, false, false]);
 
-            let cargo = std::env::var_os("CARGO").unwrap_or_else(|| "cargo".into());
-            let mut cmd = process::Command::new(&cargo);
+        let (options, _) = parse_arguments(["check", "--max-warnings", "2"].map(str::to_string))?;
+        sim_assert_eq!(options.max_warnings, Some(2));
+        let (options, _) = parse_arguments(["check", "--pedantic"].map(str::to_string))?;
+        sim_assert_eq!(options.max_warnings, Some(0));
+        let (options, _) = parse_arguments(["check"].map(str::to_string))?;
+        sim_assert_eq!(options.max_warnings, None);
+        assert!(parse_arguments(["check", "--max-warnings", "x"].map(str::to_string)).is_err());
+        Ok(())
+    }
 
-            if options.errors_only {
-                cmd.env(
-                    "RUSTFLAGS",
-                    format!(
-                        "-Awarnings {}", // allows all warnings
-                        std::env::var("RUSTFLAGS").unwrap_or_default()
-                    ),
-                );
-            }
+    #[test]
+    fn matrix_feature_sets_round_trip() -> eyre::Result<()> {
+        let (options, _) = parse_arguments(["matrix"].map(str::to_string))?;
+        let package = package_with_features(&["foo", "bar"])?;
+        let mut config = resolve_config(&package, &WorkspaceConfig::default(), &options)?;
+        config.empty_features_name = Some("none".to_string());
+        let packages = [(&package, config)];
+        let rows: Vec<_> = feature_matrix_entries(&packages, false, &BTreeMap::new(), &options)
+            .iter()
+            .map(|entry| entry.to_json(","))
+            .collect();
 
-            let mut args = cargo_args.clone();
-            if !missing_arguments {
-                args.push("--no-default-features".to_string());
-                args.push(format!("--features={}", &features.iter().join(",")));
-            }
-            args.extend(extra_args.clone());
-            print_package_cmd(
-                package,
-                &features,
-                &cargo_args,
-                args.as_slice(),
-                options,
-                &mut stdout,
+        let expected: Vec<Vec<String>> = packages[0]
+            .0
+            .feature_combinations(&packages[0].1)
+            .into_iter()
+            .map(|features| features.into_iter().cloned().collect())
+            .collect();
+        for matrix in [
+            serde_json::json!(rows),
+            serde_json::json!({ "include": rows }),
+        ] {
+            let feature_sets = matrix_feature_sets(&matrix, &packages)?;
+            assert!(feature_sets.iter().all(|set| set.package == "test"));
+            sim_assert_eq!(
+                feature_sets
+                    .into_iter()
+                    .map(|set| set.features)
+                    .collect::<Vec<_>>(),
+                expected
             );
+        }
 
-            cmd.args(args)
-                .current_dir(working_dir)
-                .stderr(process::Stdio::piped());
-            let mut process = cmd.spawn()?;
+        let batched = batch_rows(rows.into_iter(), 3);
+        sim_assert_eq!(
+            matrix_feature_sets(&serde_json::json!(batched), &packages)?.len(),
+            expected.len()
+        );
+        assert!(matrix_feature_sets(&serde_json::json!({ "rows": [] }), &packages).is_err());
 
-            // build an output writer buffer
-            let output_buffer = Vec::<u8>::new();
-            let mut colored_output = io::Cursor::new(output_buffer);
+        // rows run with a toolchain are planned for it
+        let row = serde_json::json!([{ "name": "test", "features": "foo", "toolchain": "beta" }]);
+        sim_assert_eq!(
+            matrix_feature_sets(&row, &packages)?,
+            vec![FailedFeatureSet {
+                package: "test".to_string(),
+                features: vec!["foo".to_string()],
+                toolchain: Some("beta".to_string()),
+                target: None,
+            }]
+        );
+        Ok(())
+    }
 
-            {
-                // tee write to buffer and stdout
-                if let Some(proc_stderr) = process.stderr.take() {
-                    let mut proc_reader = io::BufReader::new(proc_stderr);
-                    if options.silent {
-                        io::copy(&mut proc_reader, &mut colored_output)?;
-                    } else {
-                        let mut tee_reader =
-                            crate::tee::Reader::new(proc_reader, &mut stdout, true);
-                        io::copy(&mut tee_reader, &mut colored_output)?;
-                    }
-                } else {
-                    eprintln!("ERROR: failed to redirect stderr");
-                }
-            }
+    #[test]
+    fn check_feature_sets_rejects_unknown_features() -> eyre::Result<()> {
+        let package = package_with_features(&["foo", "bar"])?;
+        let packages = [(&package, Config::default())];
+        let feature_set = |package: &str, features: &[&str]| FailedFeatureSet {
+            package: package.to_string(),
+            features: features.iter().map(|ft| (*ft).to_string()).collect(),
+            toolchain: None,
+            target: None,
+        };
 
-            let exit_status = process.wait()?;
-            let output = strip_ansi_escapes::strip(colored_output.get_ref());
-            let output = String::from_utf8_lossy(&output);
+        check_feature_sets(&[feature_set("test", &["foo", "bar"])], &packages)?;
+        // packages that are not selected are left out
+        check_feature_sets(&[feature_set("other", &["baz"])], &packages)?;
 
-            let num_warnings = warning_counts(&output).sum::<usize>();
-            let num_errors = error_counts(&output).sum::<usize>();
-            let has_errors = num_errors > 0;
-            let has_warnings = num_warnings > 0;
+        let err = check_feature_sets(&[feature_set("test", &["foo", "baz"])], &packages)
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("\"baz\""), "{err}");
+        Ok(())
+    }
 
-            let fail = !exit_status.success();
+    #[test]
+    fn run_binary_of_package_with_two_binaries() -> eyre::Result<()> {
+        let mut package = package_with_features(&[])?;
+        package.targets = ["server", "client"]
+            .map(|name| {
+                serde_json::from_value(serde_json::json!({
+                    "name": name,
+                    "kind": ["bin"],
+                    "crate_types": ["bin"],
+                    "src_path": format!("/test/src/bin/{name}.rs"),
+                }))
+            })
+            .into_iter()
+            .collect::<Result<_, _>>()?;
+        let args = |args: &[&str]| Args(args.iter().map(ToString::to_string).collect());
 
-            let pedantic_fail = options.pedantic && (has_errors || has_warnings);
-            let pedantic_success = !(fail || pedantic_fail);
+        let err = check_run_binary(&package, &args(&["run"])).unwrap_err();
+        assert!(err.to_string().contains("server, client"), "{err}");
+        check_run_binary(&package, &args(&["run", "--bin", "client"]))?;
+        check_run_binary(&package, &args(&["check"]))?;
 
-            summary.push(Summary {
-                features: features.into_iter().cloned().collect(),
-                num_errors,
-                num_warnings,
-                package_name: package.name.clone(),
-                exit_code: exit_status.code(),
-                pedantic_success,
-            });
+        // cargo runs the default-run binary itself
+        package.default_run = Some("server".to_string());
+        check_run_binary(&package, &args(&["run"]))?;
+        let (options, cargo_args) = (Options::default(), args(&["run"]));
+        let config = Config::default();
+        let run_args = invocation(&cargo_args, &[], &options).args(&job(&package, &config, None));
+        assert!(!run_args.contains(&"--bin".to_string()), "{run_args:?}");
+        Ok(())
+    }
 
-            if options.fail_fast && !pedantic_success {
-                if options.silent {
-                    io::copy(
-                        &mut io::Cursor::new(colored_output.into_inner()),
-                        &mut stdout,
-                    )?;
-                    stdout.flush().ok();
-                }
-                print_summary(summary, stdout, start.elapsed());
-                std::process::exit(exit_status.code().unwrap_or(1));
-            }
-        }
+    #[test]
+    fn parse_exclude_feature_sets() -> eyre::Result<()> {
+        let args = [
+            "--exclude-feature-set",
+            "a,b",
+            "check",
+            "--exclude-feature",
+            "c",
+            "--exclude-feature-set=b,c,d",
+        ];
+        let (options, args) = parse_arguments(args.map(str::to_string))?;
+        let set = |features: &[&str]| -> HashSet<String> {
+            features.iter().map(ToString::to_string).collect()
+        };
+        sim_assert_eq!(
+            options.exclude_feature_sets,
+            vec![set(&["a", "b"]), set(&["b", "c", "d"])]
+        );
+        sim_assert_eq!(options.exclude_features, set(&["c"]));
+        sim_assert_eq!(args.0, vec!["check"]);
+
+        let package = package_with_features(&["a", "b", "d"])?;
+        let config = resolve_config(&package, &WorkspaceConfig::default(), &options)?;
+        sim_assert_eq!(config.skip_feature_sets, options.exclude_feature_sets);
+        sim_assert_eq!(
+            package.feature_matrix(&config),
+            vec!["", "a", "a,d", "b", "b,d", "d"]
+        );
+        Ok(())
     }
 
-    print_summary(summary, stdout, start.elapsed());
-    Ok(())
-}
+    #[test]
+    fn diff_matrices_classifies_changes() -> eyre::Result<()> {
+        let old = serde_json::json!([
+            { "name": "a", "features": "" },
+            { "name": "a", "features": "foo" },
+            { "name": "b", "features": "bar" },
+        ]);
+        let new = serde_json::json!({ "include": [
+            { "name": "a", "features": "" },
+            { "name": "a", "features": ["foo,baz", "baz"] },
+            { "name": "b", "features": "bar", "target": "wasm32-unknown-unknown" },
+        ]});
+        let changes = diff_matrices(&old, &new)?;
+        sim_assert_eq!(
+            changes,
+            vec![
+                MatrixChange::Added("a [baz]".to_string()),
+                MatrixChange::Added("a [foo,baz]".to_string()),
+                MatrixChange::Removed("a [foo]".to_string()),
+                MatrixChange::Removed("b [bar]".to_string()),
+                MatrixChange::Added("b [bar] (wasm32-unknown-unknown)".to_string()),
+            ]
+        );
+        sim_assert_eq!(diff_matrices(&old, &old)?, vec![]);
 
-fn print_help() {
-    let help = r#"Run cargo commands for all feature combinations
+        let mut buffer = termcolor::Buffer::no_color();
+        print_matrix_diff(&changes[..2], &mut buffer)?;
+        sim_assert_eq!(
+            String::from_utf8(buffer.into_inner())?,
+            "+ a [baz]\n+ a [foo,baz]\n2 added, 0 removed\n"
+        );
+        Ok(())
+    }
 
-USAGE:
-    cargo [+toolchain] [SUBCOMMAND] [SUBCOMMAND_OPTIONS]
-    cargo [+toolchain] [OPTIONS] [CARGO_OPTIONS] [CARGO_SUBCOMMAND]
+    #[test]
+    fn balance_shards_by_duration() -> eyre::Result<()> {
+        let durations = [60.0, 5.0, 5.0, 30.0, 25.0, 10.0, 1.0, 4.0];
+        let shards = balance_shards(&durations, 3);
+        sim_assert_eq!(shards, vec![0, 1, 1, 1, 2, 2, 2, 2]);
+        let mut totals = [0.0; 3];
+        for (shard, duration) in shards.iter().zip(durations) {
+            totals[*shard] += duration;
+        }
+        sim_assert_eq!(totals, [60.0, 40.0, 40.0]);
 
-SUBCOMMAND:
-    matrix                  Print JSON feature combination matrix to stdout
-        --pretty            Print pretty JSON
+        // without durations, the shards are still filled evenly
+        sim_assert_eq!(balance_shards(&[0.0; 4], 2), vec![0, 1, 0, 1]);
+        sim_assert_eq!(
+            combination_label("a", &["x", "y"], Some(&"wasm32".to_string()), None),
+            "a [x,y] (wasm32)"
+        );
 
-OPTIONS:
-    --help                  Print help information
-    --silent                Hide cargo output and only show summary
-    --fail-fast             Fail fast on the first bad feature combination
-    --errors-only           Allow all warnings, show errors only (-Awarnings)
-    --pedantic              Treat warnings like errors in summary and 
-                            when using --fail-fast
+        assert!(parse_arguments(["check", "--balance-shards"].map(str::to_string)).is_err());
+        let args = ["check", "--shard", "0/2", "--balance-shards"];
+        let (options, _) = parse_arguments(args.map(str::to_string))?;
+        assert!(options.balance_shards);
+        Ok(())
+    }
 
-Feature sets can be configured in your Cargo.toml configuration.
-For example:
+    #[test]
+    fn teamcity_service_messages() -> eyre::Result<()> {
+        let combination = |num_errors: usize, ok: bool| Summary {
+            features: vec!["a".to_string(), "b".to_string()],
+            num_warnings: 2,
+            num_errors,
+            duration: std::time::Duration::from_millis(1250),
+            ..summary("test", ok)
+        };
+        let label = combination_label("test", &["a", "b"], None, Some(&"nightly".to_string()));
+        sim_assert_eq!(
+            crate::teamcity::test_started(&label),
+            "##teamcity[testStarted name='test |[a,b|] +nightly']"
+        );
+        sim_assert_eq!(
+            crate::teamcity::test_finished(&label, &combination(0, true)),
+            vec!["##teamcity[testFinished name='test |[a,b|] +nightly' duration='1250']"]
+        );
+        sim_assert_eq!(
+            crate::teamcity::test_finished(&label, &combination(1, false)),
+            vec![
+                "##teamcity[testFailed name='test |[a,b|] +nightly' message='1 errors, 2 warnings']",
+                "##teamcity[testFinished name='test |[a,b|] +nightly' duration='1250']",
+            ]
+        );
+        sim_assert_eq!(
+            crate::teamcity::test_ignored(
+                &label,
+                &Summary {
+                    cached: true,
+                    ..combination(0, true)
+                }
+            ),
+            "##teamcity[testIgnored name='test |[a,b|] +nightly' message='cached']"
+        );
+        sim_assert_eq!(
+            crate::teamcity::test_ignored(
+                &label,
+                &Summary {
+                    failing_subset: Some(vec!["a".to_string()]),
+                    ..combination(0, false)
+                }
+            ),
+            "##teamcity[testIgnored name='test |[a,b|] +nightly' message='superset of failing features |[a|]']"
+        );
+        sim_assert_eq!(crate::teamcity::escape("it's\n|"), "it|'s|n||");
+        Ok(())
+    }
 
-```toml
-[package.metadata.cargo-feature-combinations]
-# Exclude groupings of features that are incompatible or do not make sense
-skip_feature_sets = [ ["foo", "bar"], ]
+    #[test]
+    fn include_sets_with_unknown_features() -> eyre::Result<()> {
+        let package = package_with_features(&["a", "b"])?;
+        let mut config = Config {
+            include_feature_sets: vec![HashSet::from(["a".to_string(), "wasm".to_string()])],
+            ..Config::default()
+        };
+        sim_assert_eq!(package.feature_matrix(&config), vec!["", "a", "a,b", "b"]);
 
-# Exclude features from the feature combination matrix
-denylist = ["default", "full"]
-```
+        config.include_sets_allow_unknown = true;
+        sim_assert_eq!(
+            package.feature_matrix(&config),
+            vec!["", "a", "a,b", "a,wasm", "b"]
+        );
+        Ok(())
+    }
 
-For more information, see 'https://github.com/romnn/cargo-feature-combinations'.
+    #[test]
+    fn missing_manifest_path_is_reported_by_cargo_metadata() -> eyre::Result<()> {
+        let args = ["--manifest-path", "generated/Cargo.toml", "matrix"];
+        let (options, _) = parse_arguments(args.map(str::to_string))?;
+        let manifest_path = options
+            .manifest_path
+            .ok_or_else(|| eyre::eyre!("missing manifest path"))?;
+        sim_assert_eq!(
+            manifest_path,
+            std::env::current_dir()?.join("generated/Cargo.toml")
+        );
 
-See 'cargo help <command>' for more information on a specific command.
-    "#;
-    println!("{help}");
-}
+        let mut cmd = cargo_metadata::MetadataCommand::new();
+        cmd.manifest_path(&manifest_path);
+        let err = exec_metadata(&cmd, Some(&manifest_path)).unwrap_err();
+        sim_assert_eq!(
+            err.to_string(),
+            format!(
+                "failed to read the metadata of manifest {}",
+                manifest_path.display()
+            )
+        );
+        Ok(())
+    }
 
-static VALID_BOOLS: [&str; 4] = ["yes", "true", "y", "t"];
+    #[test]
+    fn workspace_feature_sets_apply_to_members() -> eyre::Result<()> {
+        let workspace = WorkspaceConfig::from_value(
+            Some(&serde_json::json!({
+                "skip_feature_sets": [["tokio", "async-std"]],
+                "include_feature_sets": [["std"]],
+            })),
+            None,
+        )?;
+        let members = ["api", "cli"].map(|name| package(name, &["async-std", "tokio"], &[]));
+        for member in members {
+            let member = member?;
+            sim_assert_eq!(
+                member.feature_matrix(&member.config(&workspace)?),
+                vec!["", "async-std", "tokio"]
+            );
+        }
 
-pub fn run(bin_name: impl AsRef<str>) -> eyre::Result<()> {
-    color_eyre::install()?;
+        // sets included by a member are not skipped
+        let mut package = package_with_features(&["async-std", "tokio"])?;
+        package.metadata = serde_json::json!({
+            "cargo-feature-combinations": { "include_feature_sets": [["tokio", "async-std"]] }
+        });
+        sim_assert_eq!(
+            package.feature_matrix(&package.config(&workspace)?),
+            vec!["", "async-std", "async-std,tokio", "tokio"]
+        );
+        Ok(())
+    }
 
-    let args: Vec<String> = std::env::args()
-        // skip executable name
-        .skip(1)
-        // skip our own cargo-* command name
-        .skip_while(|arg| arg.as_str() == bin_name.as_ref())
-        .collect();
+    #[test]
+    fn total_of_feature_sets_to_run() -> eyre::Result<()> {
+        let api = package_with_features(&["a", "b"])?;
+        let cli = package("cli", &["x"], &[])?;
+        let packages = [(&api, Config::default()), (&cli, Config::default())];
 
-    // use clap::FromArgMatches;
-    // let matches = clap::Command::new("command")
-    //     .arg(
-    //         clap::Arg::new("arg1")
-    //             .short('1')
-    //             .long("arg1")
-    //             .help("Argument one help message")
-    //             .default_value("one"), // Comment this line out to test other branches
-    //     )
-    //     .arg(
-    //         clap::Arg::new("arg2")
-    //             .short('2')
-    //             .long("arg2")
-    //             .help("Argument two help message"),
-    //     );
-    // let matches = matches.try_get_matches_from(&args)?;
-    // let values = NewOptions::try_parse_from(args.clone())?;
-    // let values = NewOptions::from_arg_matches(args.clone())?;
-    // let values = NewOptions::try_get_matches_from(args.clone())?;
-    // let values = ArgMatches::try_from(args.clone())?;
-    // dbg!(&matches);
-    //
-    // for test in matches {
-    //     dbg!(test);
-    // }
+        let total = |options: &Options| feature_sets_to_run(&packages, None, options).count();
+        sim_assert_eq!(total(&Options::default()), 4 + 2);
 
-    let mut args: Args = Args(args);
+        // every feature combination runs for every target and toolchain
+        let options = Options {
+            targets: vec![
+                "wasm32-unknown-unknown".to_string(),
+                "x86_64-unknown-linux-gnu".to_string(),
+            ],
+            toolchains: ["stable", "beta", "nightly"].map(str::to_string).to_vec(),
+            ..Options::default()
+        };
+        sim_assert_eq!(total(&options), (4 + 2) * 2 * 3);
 
-    let mut options = Options {
-        verbose: VALID_BOOLS.contains(
-            &std::env::var("VERBOSE")
-                .unwrap_or_default()
-                .to_lowercase()
-                .as_str(),
-        ),
-        ..Options::default()
-    };
+        // a failure recorded for one target and toolchain runs only once
+        let failures = vec![FailedFeatureSet {
+            package: "cli".to_string(),
+            features: vec!["x".to_string()],
+            toolchain: Some("beta".to_string()),
+            target: None,
+        }];
+        sim_assert_eq!(
+            feature_sets_to_run(&packages, Some(&failures), &options).count(),
+            1
+        );
+        Ok(())
+    }
 
-    // extract path to manifest to operate on
-    for (span, manifest_path) in args.get_all("--manifest-path", true) {
-        let manifest_path = PathBuf::from(manifest_path);
-        let manifest_path = manifest_path
-            .canonicalize()
-            .wrap_err_with(|| format!("manifest {} does not exist", manifest_path.display()))?;
-        options.manifest_path = Some(manifest_path);
-        args.drain(span);
+    #[test]
+    fn confirm_run_answers() -> eyre::Result<()> {
+        assert!(confirm_run(3, "y\n".as_bytes(), true)?);
+        assert!(confirm_run(3, "Yes\n".as_bytes(), true)?);
+        assert!(!confirm_run(3, "n\n".as_bytes(), true)?);
+        assert!(!confirm_run(3, "\n".as_bytes(), true)?);
+        assert!(!confirm_run(3, "".as_bytes(), true)?);
+        // without a terminal, the run proceeds without reading an answer
+        assert!(confirm_run(3, "".as_bytes(), false)?);
+        Ok(())
     }
 
-    // extract packages to operate on
-    for flag in ["--package", "-p"] {
-        for (span, package) in args.get_all(flag, true) {
-            options.packages.insert(package);
-            args.drain(span);
-        }
+    #[test]
+    fn cargo_program_precedence() -> eyre::Result<()> {
+        let program = |cargo_bin: Option<&str>, env: &[(&str, &str)], toolchain: bool| {
+            let env: HashMap<String, std::ffi::OsString> = env
+                .iter()
+                .map(|(key, value)| ((*key).to_string(), (*value).into()))
+                .collect();
+            cargo_program(
+                cargo_bin.map(std::path::Path::new),
+                |key| env.get(key).cloned(),
+                toolchain,
+            )
+        };
+        let env = [("FC_CARGO", "fc-cargo"), ("CARGO", "/bin/cargo")];
+        sim_assert_eq!(program(Some("cross"), &env, false), "cross");
+        sim_assert_eq!(program(None, &env, false), "fc-cargo");
+        sim_assert_eq!(program(None, &env[1..], false), "/bin/cargo");
+        sim_assert_eq!(program(None, &[], false), "cargo");
+
+        // only the rustup proxy understands +toolchain, unlike $CARGO
+        sim_assert_eq!(program(None, &env[1..], true), "cargo");
+        sim_assert_eq!(program(None, &env, true), "fc-cargo");
+
+        let args = ["--cargo-bin", "cross", "check"];
+        let (options, args) = parse_arguments(args.map(str::to_string))?;
+        sim_assert_eq!(options.cargo_bin, Some(std::path::PathBuf::from("cross")));
+        sim_assert_eq!(args.0, vec!["check"]);
+        Ok(())
     }
 
-    // check for matrix command
-    for (span, _) in args.get_all("matrix", false) {
-        options.command = Some(Command::FeatureMatrix { pretty: false });
-        args.drain(span);
+    #[test]
+    fn use_cross_command() -> eyre::Result<()> {
+        let package = package_with_features(&["a"])?;
+        let config = Config::default();
+        let (options, cargo_args, extra_args) = split_arguments(&[
+            "--use-cross",
+            "--target",
+            "aarch64-unknown-linux-gnu",
+            "build",
+        ])?;
+        let invocation = Invocation {
+            workspace_root: std::path::Path::new("/workspace"),
+            ..invocation(&cargo_args, &extra_args, &options)
+        };
+        let job = job(&package, &config, options.targets.first());
+        let cmd = invocation.command(&job, &invocation.args(&job))?;
+        sim_assert_eq!(cmd.get_program(), "cross");
+        sim_assert_eq!(
+            cmd.get_current_dir(),
+            Some(std::path::Path::new("/workspace"))
+        );
+        let cmd_args: Vec<_> = cmd.get_args().collect();
+        assert!(
+            cmd_args
+                .windows(2)
+                .any(|arg| arg == ["--target", "aarch64-unknown-linux-gnu"]),
+            "{cmd_args:?}"
+        );
+        assert!(cmd_args.contains(&std::ffi::OsStr::new("--manifest-path")));
+
+        // cross can not guess the target
+        assert!(parse_arguments(["--use-cross", "build"].map(str::to_string)).is_err());
+        Ok(())
     }
-    // check for pretty matrix option
-    for (span, _) in args.get_all("--pretty", false) {
-        if let Some(Command::FeatureMatrix { ref mut pretty }) = options.command {
-            *pretty = true;
-        }
-        args.drain(span);
+
+    #[test]
+    fn feature_files_of_matrix() -> eyre::Result<()> {
+        let dir = std::env::temp_dir().join(format!("fc-feature-files-{}", std::process::id()));
+        let package = package_with_features(&["a", "b"])?;
+        let config = Config {
+            empty_features_name: Some("none".to_string()),
+            ..Config::default()
+        };
+        sim_assert_eq!(write_feature_files(&[(&package, &config)], &dir)?, 4);
+        let read =
+            |idx: usize| std::fs::read_to_string(dir.join("test").join(format!("{idx}.features")));
+        sim_assert_eq!(read(0)?, "\n");
+        sim_assert_eq!(read(1)?, "a\n");
+        sim_assert_eq!(read(2)?, "a,b\n");
+        sim_assert_eq!(read(3)?, "b\n");
+
+        // files of a larger matrix do not linger
+        let config = Config {
+            max_combination_size: Some(1),
+            ..Config::default()
+        };
+        sim_assert_eq!(write_feature_files(&[(&package, &config)], &dir)?, 3);
+        sim_assert_eq!(read(2)?, "b\n");
+        assert!(read(3).is_err());
+        std::fs::remove_dir_all(dir)?;
+        Ok(())
     }
 
-    // check for help command
-    for (span, _) in args.get_all("--pretty", false) {
-        options.command = Some(Command::Help);
-        args.drain(span);
+    #[test]
+    fn warn_on_misspelled_features() -> eyre::Result<()> {
+        let package = package_with_features(&["foo-bar", "tokio", "std"])?;
+        let config: Config = serde_json::from_value(serde_json::json!({
+            "include_feature_sets": [["foo_bar", "std"], ["tokoi"]],
+            "exclude_features": ["serde"],
+        }))?;
+        sim_assert_eq!(
+            unknown_feature_warnings(&package, &config),
+            vec![
+                "feature `foo_bar` in `include_feature_sets` of package test does not exist, \
+                did you mean `foo-bar`?",
+                "feature `tokoi` in `include_feature_sets` of package test does not exist, \
+                did you mean `tokio`?",
+                "feature `serde` in `exclude_features` of package test does not exist",
+            ]
+        );
+
+        // unknown features are kept on purpose
+        let config = Config {
+            include_sets_allow_unknown: true,
+            ..config
+        };
+        sim_assert_eq!(
+            unknown_feature_warnings(&package, &config),
+            vec!["feature `serde` in `exclude_features` of package test does not exist"]
+        );
+
+        let workspace = WorkspaceConfig {
+            skip_feature_sets: vec![HashSet::from(["std", "alloc"].map(str::to_string))],
+            include_feature_sets: vec![HashSet::from(["tokio".to_string()])],
+            ..WorkspaceConfig::default()
+        };
+        sim_assert_eq!(
+            unknown_workspace_feature_warnings(&[&package], &workspace),
+            vec![
+                "feature `alloc` in `skip_feature_sets` of the workspace does not exist in any \
+                package"
+            ]
+        );
+
+        sim_assert_eq!(edit_distance("tokio", "tokoi"), 2);
+        sim_assert_eq!(edit_distance("", "std"), 3);
+        Ok(())
     }
 
-    // check for pedantic flag
-    for (span, _) in args.get_all("--pedantic", false) {
-        options.pedantic = true;
-        args.drain(span);
+    #[test]
+    fn package_key_is_stable() -> eyre::Result<()> {
+        let dir = std::env::temp_dir().join(format!("fc-package-key-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("src"))?;
+        std::fs::create_dir_all(dir.join("tests"))?;
+        std::fs::write(dir.join("Cargo.toml"), "[package]\nname = \"test\"\n")?;
+        std::fs::write(dir.join("src/lib.rs"), "")?;
+        let mut package = package_with_features(&["a", "b"])?;
+        package.manifest_path = dir.join("Cargo.toml").try_into()?;
+        let options = Options::default();
+        let key = |config: Config| -> eyre::Result<String> {
+            let packages = [(&package, config)];
+            let inputs = CacheInputs::new(&packages, &[], &options)?;
+            let (package, config) = &packages[0];
+            Ok(inputs.package_key(package, config, &["test".to_string()], &options))
+        };
+
+        let before = key(Config::default())?;
+        sim_assert_eq!(key(Config::default())?, before);
+        // build output does not change the sources
+        std::fs::create_dir_all(dir.join("target/debug"))?;
+        std::fs::write(dir.join("target/debug/libtest.rlib"), "")?;
+        sim_assert_eq!(key(Config::default())?, before);
+
+        // other feature combinations may fail
+        let config = Config {
+            exclude_features: HashSet::from(["b".to_string()]),
+            ..Config::default()
+        };
+        assert_ne!(key(config)?, before);
+
+        std::fs::write(dir.join("tests/it.rs"), "#[test] fn it() {}")?;
+        assert_ne!(key(Config::default())?, before);
+        std::fs::remove_dir_all(dir)?;
+        Ok(())
     }
 
-    // check for errors only
-    for (span, _) in args.get_all("--errors-only", false) {
-        options.errors_only = true;
-        args.drain(span);
+    #[test]
+    fn fmt_args_select_no_features() -> eyre::Result<()> {
+        let package = package_with_features(&["a"])?;
+        let config = Config::default();
+        let (options, cargo_args, extra_args) =
+            split_arguments(&["--target", "wasm32-unknown-unknown", "fmt", "--", "--check"])?;
+        let invocation = invocation(&cargo_args, &extra_args, &options);
+        let job = job(&package, &config, options.targets.first());
+        // cargo fmt rejects --color, --features and --target
+        sim_assert_eq!(
+            invocation.args(&job),
+            vec![
+                "fmt",
+                "--manifest-path",
+                "/test/Cargo.toml",
+                "--",
+                "--check"
+            ]
+        );
+        Ok(())
     }
 
-    // packages only
-    for (span, _) in args.get_all("--packages-only", false) {
-        options.packages_only = true;
-        args.drain(span);
+    #[test]
+    fn source_paths_of_transitive_path_dependencies() -> eyre::Result<()> {
+        let all_packages = [
+            package("app", &[], &["mid"])?,
+            package("mid", &[], &["leaf", "app"])?,
+            package("leaf", &[], &[])?,
+        ];
+        sim_assert_eq!(
+            source_paths(&all_packages[0], &all_packages),
+            ["/ws/app", "/ws/mid", "/ws/leaf"].map(std::path::PathBuf::from)
+        );
+        // without the metadata of the dependency, only its directory is known
+        sim_assert_eq!(
+            source_paths(&all_packages[0], &[]),
+            ["/ws/app", "/ws/mid"].map(std::path::PathBuf::from)
+        );
+        Ok(())
     }
 
-    // check for silent flag
-    for (span, _) in args.get_all("--silent", false) {
-        options.silent = true;
-        args.drain(span);
+    #[test]
+    fn cache_key_covers_feature_env() -> eyre::Result<()> {
+        let package = package_with_features(&["grpc"])?;
+        let inputs = CacheInputs {
+            rustc_versions: HashMap::new(),
+            source_digests: HashMap::new(),
+            env: vec![],
+        };
+        let key = |protoc: &str| -> eyre::Result<String> {
+            let config: Config = serde_json::from_value(serde_json::json!({
+                "feature_env": { "grpc": { "PROTOC": protoc } },
+            }))?;
+            Ok(inputs.key(&job(&package, &config, None), &["check".to_string()]))
+        };
+        sim_assert_eq!(key("/usr/bin/protoc")?, key("/usr/bin/protoc")?);
+        assert_ne!(key("/usr/bin/protoc")?, key("/opt/bin/protoc")?);
+        Ok(())
     }
 
-    // check for fail fast flag
-    for (span, _) in args.get_all("--fail-fast", false) {
-        options.fail_fast = true;
-        args.drain(span);
+    #[test]
+    fn only_known_or_allowed_subcommands_run() -> eyre::Result<()> {
+        let check = |args: &[&str]| -> eyre::Result<Vec<String>> {
+            let (options, args) = parse_arguments(args.iter().map(ToString::to_string))?;
+            check_subcommand(&args, &options)?;
+            Ok(args.0)
+        };
+        check(&["check", "--all-targets"])?;
+        check(&["nextest", "run"])?;
+        let err = check(&["udeps", "--all-targets"]).unwrap_err().to_string();
+        assert!(err.contains("--allow-subcommand udeps"), "{err}");
+
+        sim_assert_eq!(
+            check(&["--allow-subcommand", "udeps", "udeps", "--all-targets"])?,
+            vec!["udeps", "--all-targets"]
+        );
+        assert!(check(&["--allow-subcommand=miri", "udeps"]).is_err());
+        Ok(())
     }
 
-    // get metadata for cargo package
-    let mut cmd = cargo_metadata::MetadataCommand::new();
-    if let Some(ref manifest_path) = options.manifest_path {
-        cmd.manifest_path(manifest_path);
+    #[test]
+    fn shards_order_packages_by_name() -> eyre::Result<()> {
+        let (cli, api) = (package("cli", &[], &[])?, package("api", &[], &[])?);
+        let packages = [(&cli, Config::default()), (&api, Config::default())];
+        let order = |options: &Options| -> Vec<String> {
+            feature_sets_to_run(&packages, None, options)
+                .map(|(_, (package, ..))| package.name.to_string())
+                .collect()
+        };
+        sim_assert_eq!(order(&Options::default()), vec!["cli", "api"]);
+        let options = Options {
+            shard: Some("0/2".parse()?),
+            ..Options::default()
+        };
+        // packages still run in their own order, but are sharded by name
+        sim_assert_eq!(order(&options), vec!["cli", "api"]);
+        let shards = feature_set_shards(
+            feature_sets_to_run(&packages, None, &options),
+            &BTreeMap::new(),
+            &options,
+        );
+        sim_assert_eq!(shards, Some(vec![1, 0]));
+        Ok(())
     }
-    let metadata = cmd.exec()?;
-    let mut packages = metadata.workspace_packages();
 
-    if let Some(root_package) = metadata.root_package() {
-        let config = root_package.config()?;
-        // filter packages based on root package Cargo.toml configuration
-        packages.retain(|p| !config.exclude_packages.contains(&p.name));
+    #[test]
+    fn feature_matrix_entries_of_balanced_shards() -> eyre::Result<()> {
+        let args = ["matrix", "--shard", "0/2", "--balance-shards"];
+        let (options, _) = parse_arguments(args.map(str::to_string))?;
+        let package = package_with_features(&["foo", "bar"])?;
+        let config = resolve_config(&package, &WorkspaceConfig::default(), &options)?;
+        let packages = [(&package, config)];
+        let features = |durations: &BTreeMap<String, f64>| {
+            feature_matrix_entries(&packages, false, durations, &options)
+                .into_iter()
+                .map(|entry| entry.features.join(","))
+                .collect::<Vec<_>>()
+        };
+        // without recorded durations, the rows are sharded by index
+        sim_assert_eq!(features(&BTreeMap::new()), vec!["", "bar,foo"]);
+        let durations = BTreeMap::from([
+            ("test []".to_string(), 1.0),
+            ("test [bar]".to_string(), 1.0),
+            ("test [bar,foo]".to_string(), 60.0),
+        ]);
+        sim_assert_eq!(features(&durations), vec!["bar,foo"]);
+        Ok(())
     }
 
-    // filter packages based on CLI options
-    if !options.packages.is_empty() {
-        packages.retain(|p| options.packages.contains(&p.name));
+    #[test]
+    fn parse_jobs_and_fail_fast() -> eyre::Result<()> {
+        let args = [
+            "--fc-jobs",
+            "3",
+            "--fail-fast",
+            "check",
+            "-j",
+            "2",
+            "--jobs",
+            "4",
+        ];
+        let (options, args) = parse_arguments(args.map(str::to_string))?;
+        sim_assert_eq!(options.jobs, Some(3));
+        assert!(options.fail_fast);
+        // the jobs of cargo itself are passed through
+        sim_assert_eq!(args.0, vec!["check", "-j", "2", "--jobs", "4"]);
+        sim_assert_eq!(num_jobs(&options), 3);
+
+        let (options, _) = parse_arguments(["check", "--fc-jobs", "0"].map(str::to_string))?;
+        assert!(!options.fail_fast);
+        // zero jobs use the available parallelism
+        assert!(num_jobs(&options) >= 1);
+        assert!(parse_arguments(["check", "--fc-jobs", "many"].map(str::to_string)).is_err());
+        Ok(())
     }
 
-    match options.command {
-        Some(Command::Help) => {
-            print_help();
-            Ok(())
-        }
-        Some(Command::FeatureMatrix { pretty }) => {
-            print_feature_matrix(packages.as_slice(), pretty, options.packages_only)
-        }
-        None => run_cargo_command(packages.as_slice(), args, &options),
+    #[test]
+    fn failures_of_other_shards_are_kept() {
+        let failure = |package: &str| FailedFeatureSet {
+            package: package.to_string(),
+            features: vec![],
+            toolchain: None,
+            target: None,
+        };
+        let recorded = vec![failure("a"), failure("b"), failure("c")];
+        // a passed and c failed again, while b did not run in this shard
+        let summary = [summary("a", true), summary("c", false), summary("d", false)];
+        sim_assert_eq!(
+            merge_failures(recorded, &summary),
+            vec![failure("b"), failure("c"), failure("d")]
+        );
     }
-}
 
-#[cfg(test)]
-mod test {
-    use super::{error_counts, warning_counts};
-    use similar_asserts::assert_eq as sim_assert_eq;
+    #[test]
+    fn parse_count_command() -> eyre::Result<()> {
+        let (options, args) = parse_arguments(["count", "--per-package"].map(str::to_string))?;
+        assert!(matches!(
+            options.command,
+            Some(Command::Count { per_package: true })
+        ));
+        assert!(args.is_empty());
+
+        let args = ["--exclude-feature", "foo", "count"];
+        let (options, _) = parse_arguments(args.map(str::to_string))?;
+        assert!(matches!(
+            options.command,
+            Some(Command::Count { per_package: false })
+        ));
+
+        // count is only the command as the first positional argument
+        for args in [&["test", "count"][..], &["run", "--bin", "count"]] {
+            let (options, cargo_args) = parse_arguments(args.iter().map(ToString::to_string))?;
+            assert!(options.command.is_none());
+            sim_assert_eq!(cargo_args.0, args);
+        }
+        Ok(())
+    }
 
     #[test]
-    fn error_regex_single_mod_multiple_errors() {
-        let stderr = include_str!("../tests/single_mod_multiple_errors_stderr.txt");
-        let errors: Vec<_> = error_counts(stderr).collect();
-        sim_assert_eq!(&errors, &vec![2]);
+    fn parse_timeout() -> eyre::Result<()> {
+        let timeout = |timeout: &str| {
+            let args = ["check", "--timeout", timeout].map(str::to_string);
+            parse_arguments(args).map(|(options, _)| options.timeout)
+        };
+        sim_assert_eq!(
+            timeout("1.5")?,
+            Some(std::time::Duration::from_millis(1500))
+        );
+        for invalid in ["0", "-1", "NaN", "inf", "1e20", "1e19", "soon"] {
+            assert!(timeout(invalid).is_err(), "{invalid}");
+        }
+        Ok(())
     }
 
     #[test]
-    fn warning_regex_two_mod_multiple_warnings() {
-        let stderr = include_str!("../tests/two_mods_warnings_stderr.txt");
-        let warnings: Vec<_> = warning_counts(stderr).collect();
-        sim_assert_eq!(&warnings, &vec![6, 7]);
+    fn parse_help_command() -> eyre::Result<()> {
+        for flag in ["--help", "-h"] {
+            let (options, args) = parse_arguments([flag].map(str::to_string))?;
+            assert!(matches!(options.command, Some(Command::Help)));
+            assert!(args.is_empty());
+        }
+        let (options, args) = parse_arguments(["test", "--", "--help"].map(str::to_string))?;
+        assert!(options.command.is_none());
+        sim_assert_eq!(args.0, vec!["test", "--", "--help"]);
+        let (options, _) = parse_arguments(["matrix", "--pretty"].map(str::to_string))?;
+        assert!(matches!(
+            options.command,
+            Some(Command::FeatureMatrix { pretty: true, .. })
+        ));
+        Ok(())
     }
 }
@@ -0,0 +1,86 @@
+use crate::{Diagnostic, Summary};
+use color_eyre::eyre::{self, WrapErr};
+use itertools::Itertools;
+
+/// Renders the compiler diagnostics of a run as a SARIF 2.1.0 log.
+///
+/// Identical diagnostics reported for several feature combinations of a
+/// package are merged into a single result, listing the feature combinations
+/// in its `featureCombinations` property.
+/// Diagnostics without a source location are left out.
+#[must_use]
+pub fn report(summary: &[Summary]) -> serde_json::Value {
+    let mut results: Vec<(&Summary, &Diagnostic, Vec<String>)> = Vec::new();
+    for s in summary {
+        let combination = s.features.join(",");
+        for diagnostic in s.diagnostics.iter().filter(|d| d.location.is_some()) {
+            let existing = results.iter_mut().find(|(other, other_diagnostic, _)| {
+                other.package_name == s.package_name && *other_diagnostic == diagnostic
+            });
+            match existing {
+                Some((_, _, combinations)) => {
+                    if !combinations.contains(&combination) {
+                        combinations.push(combination.clone());
+                    }
+                }
+                None => results.push((s, diagnostic, vec![combination.clone()])),
+            }
+        }
+    }
+
+    let rules: Vec<_> = results
+        .iter()
+        .map(|(_, diagnostic, _)| rule_id(diagnostic))
+        .unique()
+        .sorted()
+        .map(|id| serde_json::json!({ "id": id }))
+        .collect();
+    let results: Vec<_> = results
+        .into_iter()
+        .map(|(s, diagnostic, combinations)| {
+            let (file, line, column) = diagnostic.location.clone().unwrap_or_default();
+            serde_json::json!({
+                "ruleId": rule_id(diagnostic),
+                "level": if diagnostic.error { "error" } else { "warning" },
+                "message": { "text": diagnostic.message },
+                "locations": [{
+                    "physicalLocation": {
+                        "artifactLocation": { "uri": file },
+                        "region": { "startLine": line, "startColumn": column },
+                    },
+                }],
+                "properties": {
+                    "package": s.package_name,
+                    "featureCombinations": combinations,
+                },
+            })
+        })
+        .collect();
+
+    serde_json::json!({
+        "$schema": "https://json.schemastore.org/sarif-2.1.0.json",
+        "version": "2.1.0",
+        "runs": [{
+            "tool": {
+                "driver": {
+                    "name": "cargo-fc",
+                    "informationUri": "https://github.com/romnn/cargo-feature-combinations",
+                    "rules": rules,
+                },
+            },
+            "results": results,
+        }],
+    })
+}
+
+/// Diagnostics without a lint name or error code are reported as `rustc`.
+fn rule_id(diagnostic: &Diagnostic) -> &str {
+    diagnostic.code.as_deref().unwrap_or("rustc")
+}
+
+/// Writes the compiler diagnostics of a run as a SARIF log to `path`.
+pub fn write(summary: &[Summary], path: &std::path::Path) -> eyre::Result<()> {
+    let json = serde_json::to_string_pretty(&report(summary))?;
+    std::fs::write(path, json)
+        .wrap_err_with(|| format!("failed to write SARIF report to {}", path.display()))
+}
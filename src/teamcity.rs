@@ -0,0 +1,69 @@
+use crate::Summary;
+
+/// Escapes a value of a TeamCity service message attribute.
+#[must_use]
+pub fn escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '|' | '\'' | '[' | ']' => {
+                escaped.push('|');
+                escaped.push(c);
+            }
+            '\n' => escaped.push_str("|n"),
+            '\r' => escaped.push_str("|r"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// The service message announcing that the feature combination `name` starts.
+#[must_use]
+pub fn test_started(name: &str) -> String {
+    format!("##teamcity[testStarted name='{}']", escape(name))
+}
+
+/// The service messages reporting the outcome of the feature combination `name`.
+///
+/// Failed feature combinations are reported with their number of errors
+/// and warnings before they are finished.
+#[must_use]
+pub fn test_finished(name: &str, summary: &Summary) -> Vec<String> {
+    let name = escape(name);
+    let mut messages = Vec::new();
+    if !summary.pedantic_success {
+        let mut message = format!(
+            "{} errors, {} warnings",
+            summary.num_errors, summary.num_warnings
+        );
+        if summary.timed_out {
+            message.push_str(", timed out");
+        }
+        messages.push(format!(
+            "##teamcity[testFailed name='{name}' message='{}']",
+            escape(&message)
+        ));
+    }
+    messages.push(format!(
+        "##teamcity[testFinished name='{name}' duration='{}']",
+        summary.duration.as_millis()
+    ));
+    messages
+}
+
+/// The service message reporting that the feature combination `name` did not run,
+/// because it was cached, unchanged or a superset of a failing feature combination.
+#[must_use]
+pub fn test_ignored(name: &str, summary: &Summary) -> String {
+    let message = match &summary.failing_subset {
+        Some(failing) => format!("superset of failing features [{}]", failing.join(",")),
+        None if summary.unchanged => "unchanged".to_string(),
+        None => "cached".to_string(),
+    };
+    format!(
+        "##teamcity[testIgnored name='{}' message='{}']",
+        escape(name),
+        escape(&message)
+    )
+}
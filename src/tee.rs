@@ -36,3 +36,27 @@ impl<R: Read + Clone, W: Write + Clone> Clone for Reader<R, W> {
         }
     }
 }
+
+pub struct Writer<A, B> {
+    first: A,
+    second: B,
+}
+
+impl<A, B> Writer<A, B> {
+    pub fn new(first: A, second: B) -> Self {
+        Self { first, second }
+    }
+}
+
+impl<A: Write, B: Write> Write for Writer<A, B> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        self.first.write_all(buf)?;
+        self.second.write_all(buf)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.first.flush()?;
+        self.second.flush()
+    }
+}
@@ -0,0 +1,77 @@
+use std::io::{self, Read};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::time::Instant;
+
+/// Reader that stops reading at a deadline.
+///
+/// The inner reader is read on a separate thread, so that reading can be
+/// abandoned once the deadline has passed, even if the inner reader blocks
+/// (e.g. when a pipe is kept open by a process that never terminates).
+pub struct Reader {
+    chunks: mpsc::Receiver<io::Result<Vec<u8>>>,
+    chunk: io::Cursor<Vec<u8>>,
+    deadline: Instant,
+    thread: std::thread::JoinHandle<()>,
+}
+
+impl Reader {
+    pub fn new<R: Read + Send + 'static>(mut read: R, deadline: Instant) -> Self {
+        let (tx, chunks) = mpsc::channel();
+        let thread = std::thread::spawn(move || {
+            let mut buf = vec![0; 8 * 1024];
+            loop {
+                let chunk = match read.read(&mut buf) {
+                    Ok(0) => break,
+                    Ok(n) => Ok(buf[..n].to_vec()),
+                    Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(err) => Err(err),
+                };
+                let failed = chunk.is_err();
+                if tx.send(chunk).is_err() || failed {
+                    break;
+                }
+            }
+        });
+        Self {
+            chunks,
+            chunk: io::Cursor::new(Vec::new()),
+            deadline,
+            thread,
+        }
+    }
+
+    /// Waits for the thread reading the inner reader to finish.
+    ///
+    /// Past the deadline, the inner reader may block forever, so the thread
+    /// is only joined if it already finished.
+    ///
+    /// # Errors
+    ///
+    /// If the thread reading the inner reader panicked.
+    pub fn join(self) -> io::Result<()> {
+        // stops the thread at its next chunk
+        drop(self.chunks);
+        if Instant::now() >= self.deadline && !self.thread.is_finished() {
+            return Ok(());
+        }
+        self.thread
+            .join()
+            .map_err(|_| io::Error::other("failed to read output"))
+    }
+}
+
+impl Read for Reader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let n = self.chunk.read(buf)?;
+            if n > 0 || buf.is_empty() {
+                return Ok(n);
+            }
+            let remaining = self.deadline.saturating_duration_since(Instant::now());
+            match self.chunks.recv_timeout(remaining) {
+                Ok(chunk) => self.chunk = io::Cursor::new(chunk?),
+                Err(RecvTimeoutError::Timeout | RecvTimeoutError::Disconnected) => return Ok(0),
+            }
+        }
+    }
+}
@@ -0,0 +1,54 @@
+use color_eyre::eyre;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// A temporary workspace, which is removed on drop.
+pub struct Workspace {
+    root: PathBuf,
+}
+
+impl Workspace {
+    /// Creates an empty workspace in the temporary directory, unique to `name` and this process.
+    pub fn new(name: &str) -> eyre::Result<Self> {
+        let root = std::env::temp_dir().join(format!("cargo-fc-{name}-{}", std::process::id()));
+        if root.exists() {
+            std::fs::remove_dir_all(&root)?;
+        }
+        std::fs::create_dir_all(&root)?;
+        Ok(Self { root })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.root
+    }
+
+    /// Writes `contents` to `path` relative to the workspace, creating its directory.
+    pub fn write(&self, path: impl AsRef<Path>, contents: &str) -> eyre::Result<()> {
+        let path = self.root.join(path);
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        std::fs::write(path, contents)?;
+        Ok(())
+    }
+}
+
+impl Drop for Workspace {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.root);
+    }
+}
+
+/// Command running `cargo fc` with `args` in `dir`.
+///
+/// The target directory is not taken from the environment, so that it is
+/// resolved within the workspace.
+pub fn cargo_fc(dir: &Path, args: &[&str]) -> Command {
+    let mut cmd = Command::new(env!("CARGO_BIN_EXE_cargo-fc"));
+    cmd.arg("fc")
+        .args(args)
+        .current_dir(dir)
+        .env_remove("CARGO_TARGET_DIR")
+        .env_remove("CARGO_BUILD_TARGET_DIR");
+    cmd
+}
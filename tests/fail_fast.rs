@@ -0,0 +1,35 @@
+mod common;
+
+use color_eyre::eyre;
+use common::{cargo_fc, Workspace};
+
+#[test]
+fn fail_fast_stops_at_the_first_failure() -> eyre::Result<()> {
+    let workspace = Workspace::new("fail-fast")?;
+    workspace.write(
+        "Cargo.toml",
+        "[package]\nname = \"a\"\nversion = \"0.1.0\"\nedition = \"2021\"\n\n\
+        [features]\nbar = []\nfoo = []\n",
+    )?;
+    workspace.write(
+        "src/lib.rs",
+        "#[cfg(feature = \"foo\")]\ncompile_error!(\"foo\");\n",
+    )?;
+
+    // the -j of cargo is passed through, while --fc-jobs runs the combinations in parallel
+    let args = ["--silent", "--fc-jobs", "2", "check", "-j", "1"];
+    let output = cargo_fc(workspace.path(), &args).output()?;
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(!output.status.success(), "cargo fc succeeded:\n{stdout}");
+    assert_eq!(stdout.matches("PASS").count(), 2, "{stdout}");
+    assert_eq!(stdout.matches("FAIL").count(), 2, "{stdout}");
+
+    // [], [bar] and [foo] run in order, so [bar,foo] does not run after [foo] failed
+    let args = ["--silent", "--fc-jobs", "1", "--fail-fast", "check"];
+    let output = cargo_fc(workspace.path(), &args).output()?;
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(!output.status.success(), "cargo fc succeeded:\n{stdout}");
+    assert_eq!(stdout.matches("PASS").count(), 2, "{stdout}");
+    assert_eq!(stdout.matches("FAIL").count(), 1, "{stdout}");
+    Ok(())
+}
@@ -0,0 +1,55 @@
+#![cfg(unix)]
+
+mod common;
+
+use color_eyre::eyre;
+use common::{cargo_fc, Workspace};
+use std::os::unix::process::CommandExt;
+use std::process::{Command, Stdio};
+use std::time::{Duration, Instant};
+
+#[test]
+fn interrupted_runs_do_not_persist_state() -> eyre::Result<()> {
+    let workspace = Workspace::new("interrupt")?;
+    let started = workspace.path().join("started");
+    workspace.write(
+        "Cargo.toml",
+        "[package]\nname = \"a\"\nversion = \"0.1.0\"\nedition = \"2021\"\n\n\
+        [features]\nfoo = []\n",
+    )?;
+    workspace.write("src/lib.rs", "")?;
+    // blocks the build until the run is interrupted
+    workspace.write(
+        "build.rs",
+        &format!(
+            "fn main() {{\n    std::fs::write({started:?}, \"\").unwrap();\n    \
+            std::thread::sleep(std::time::Duration::from_secs(300));\n}}\n"
+        ),
+    )?;
+
+    // in a process group of its own, which receives Ctrl-C like from a terminal
+    let child = cargo_fc(workspace.path(), &["--silent", "check"])
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .process_group(0)
+        .spawn()?;
+    let deadline = Instant::now() + Duration::from_secs(120);
+    while !started.exists() {
+        assert!(Instant::now() < deadline, "the build did not start");
+        std::thread::sleep(Duration::from_millis(100));
+    }
+    let status = Command::new("kill")
+        .args(["-INT", "--", &format!("-{}", child.id())])
+        .status()?;
+    assert!(status.success());
+
+    let output = child.wait_with_output()?;
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(output.status.code(), Some(130), "{stderr}");
+    assert!(stderr.contains("interrupted"), "{stderr}");
+    let state_dir = workspace.path().join("target/fc");
+    for file in ["durations.json", "last-failures.json"] {
+        assert!(!state_dir.join(file).exists(), "unexpected {file}");
+    }
+    Ok(())
+}
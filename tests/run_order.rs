@@ -0,0 +1,43 @@
+mod common;
+
+use color_eyre::eyre;
+use common::{cargo_fc, Workspace};
+
+#[test]
+fn runs_ignore_the_configured_order() -> eyre::Result<()> {
+    let workspace = Workspace::new("run-order")?;
+    workspace.write(
+        "Cargo.toml",
+        "[package]\nname = \"a\"\nversion = \"0.1.0\"\nedition = \"2021\"\n\n\
+        [features]\nbar = []\nfoo = []\n\n\
+        [package.metadata.cargo-feature-combinations]\norder = \"lexicographic\"\n",
+    )?;
+    workspace.write(
+        "src/lib.rs",
+        "#[cfg(feature = \"foo\")]\ncompile_error!(\"foo\");\n",
+    )?;
+
+    // [foo] runs before [bar,foo] despite the lexicographic order
+    let args = ["--silent", "--fc-jobs", "1", "--fail-fast", "check"];
+    let output = cargo_fc(workspace.path(), &args).output()?;
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(!output.status.success(), "cargo fc succeeded:\n{stdout}");
+    let failed: Vec<_> = stdout
+        .lines()
+        .filter(|line| line.contains("FAIL"))
+        .collect();
+    assert_eq!(failed.len(), 1, "{stdout}");
+    assert!(failed[0].ends_with("features = [foo] )"), "{stdout}");
+
+    // the summary is in lexicographic order despite --order size-then-lex
+    let args = ["--silent", "--order", "size-then-lex", "check"];
+    let output = cargo_fc(workspace.path(), &args).output()?;
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let features: Vec<_> = stdout
+        .lines()
+        .filter(|line| line.contains(" warnings, "))
+        .filter_map(|line| line.split_once("features = ")?.1.strip_suffix(" )"))
+        .collect();
+    assert_eq!(features, ["[]", "[bar]", "[bar, foo]", "[foo]"], "{stdout}");
+    Ok(())
+}
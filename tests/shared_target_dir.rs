@@ -0,0 +1,43 @@
+mod common;
+
+use color_eyre::eyre;
+use common::{cargo_fc, Workspace};
+
+#[test]
+fn members_share_the_workspace_target_dir() -> eyre::Result<()> {
+    let workspace = Workspace::new("shared-target-dir")?;
+    workspace.write(
+        "Cargo.toml",
+        "[workspace]\nmembers = [\"a\", \"b\"]\nresolver = \"2\"\n",
+    )?;
+    for member in ["a", "b"] {
+        workspace.write(
+            format!("{member}/Cargo.toml"),
+            &format!(
+                "[package]\nname = \"{member}\"\nversion = \"0.1.0\"\nedition = \"2021\"\n\n\
+                [features]\nfoo = []\n"
+            ),
+        )?;
+        workspace.write(format!("{member}/src/lib.rs"), "")?;
+    }
+    // only picked up when cargo runs in the directory of the member
+    workspace.write(
+        "a/.cargo/config.toml",
+        "[build]\ntarget-dir = \"member-target\"\n",
+    )?;
+
+    let output = cargo_fc(&workspace.path().join("a"), &["--silent", "check"]).output()?;
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(output.status.success(), "cargo fc failed:\n{stdout}");
+    assert_eq!(stdout.matches("PASS").count(), 4, "{stdout}");
+
+    let target_dir = workspace.path().join("target/debug");
+    assert!(target_dir.is_dir(), "missing {}", target_dir.display());
+    for dir in ["a/target", "a/member-target", "b/target"] {
+        assert!(
+            !workspace.path().join(dir).exists(),
+            "unexpected target dir {dir}"
+        );
+    }
+    Ok(())
+}